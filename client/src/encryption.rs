@@ -0,0 +1,220 @@
+//! Client-side encryption of objects before they are packed into field
+//! elements and uploaded.
+//!
+//! `Domain::encode` is systematic, so the nodes holding the even chunks see the
+//! uploaded elements as they are. Objects can instead be sealed with
+//! XChaCha20-Poly1305 under a key derived from the user's `MasterKey` and a
+//! random per-object salt. The keys never leave the client: nodes only get
+//! the sealed bytes.
+//!
+//! A sealed object starts with a header naming its `Scheme`:
+//! - `Plain`: `0x00 || data`;
+//! - `XChaCha20Poly1305`: `0x01 || salt (32) || nonce (24) || ciphertext || tag (16)`,
+//!   with the scheme byte and the salt authenticated as associated data.
+//!
+//! ```
+//! use client::encryption::{open, pack, seal, unpack, MasterKey, Scheme};
+//!
+//! let key = MasterKey::generate();
+//! let sealed = seal(Some(&key), b"hello").unwrap();
+//! assert_eq!(Scheme::of(&sealed).unwrap(), Scheme::XChaCha20Poly1305);
+//! assert_eq!(open(Some(&key), &unpack(&pack(&sealed)).unwrap()).unwrap(), b"hello");
+//!
+//! // Another key or a modified object is rejected.
+//! assert!(open(Some(&MasterKey::generate()), &sealed).is_err());
+//! let mut tampered = sealed.clone();
+//! *tampered.last_mut().unwrap() ^= 1;
+//! assert!(open(Some(&key), &tampered).is_err());
+//! assert!(open(None, &sealed).is_err());
+//!
+//! // Objects can still be stored in plain.
+//! let plain = seal(None, b"hello").unwrap();
+//! assert_eq!(open(None, &plain).unwrap(), b"hello");
+//! ```
+
+use anyhow::Result;
+use ark_bn254::Fr;
+use ark_ff::{BigInteger, PrimeField, Zero};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+
+const SALT_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+/// Bytes packed into every field element, so that any value fits below the modulus.
+const BYTES_PER_ELEMENT: usize = 31;
+
+/// The user's secret, every object key is derived from it.
+#[derive(Clone)]
+pub struct MasterKey([u8; 32]);
+
+impl MasterKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn generate() -> Self {
+        let mut bytes = [0; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+
+    /// HKDF-SHA256 of the master key with the object salt.
+    fn object_key(&self, salt: &[u8]) -> XChaCha20Poly1305 {
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(Some(salt), &self.0)
+            .expand(b"sharded-storage object key", &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+        XChaCha20Poly1305::new(&key.into())
+    }
+}
+
+/// How an object has been sealed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Scheme {
+    Plain = 0,
+    XChaCha20Poly1305 = 1,
+}
+
+impl Scheme {
+    /// The scheme `sealed` has been sealed with.
+    pub fn of(sealed: &[u8]) -> Result<Self> {
+        match sealed.first() {
+            Some(0) => Ok(Scheme::Plain),
+            Some(1) => Ok(Scheme::XChaCha20Poly1305),
+            Some(scheme) => Err(anyhow::anyhow!("Unknown encryption scheme {}", scheme)),
+            None => Err(anyhow::anyhow!("Empty object")),
+        }
+    }
+}
+
+/// Encrypts `data` under a fresh key derived from `key`, or only adds the
+/// header if there is no key.
+pub fn seal(key: Option<&MasterKey>, data: &[u8]) -> Result<Vec<u8>> {
+    let Some(key) = key else {
+        let mut res = vec![Scheme::Plain as u8];
+        res.extend_from_slice(data);
+        return Ok(res);
+    };
+
+    let mut rng = rand::thread_rng();
+    let mut res = vec![Scheme::XChaCha20Poly1305 as u8];
+    let mut salt = [0; SALT_SIZE];
+    rng.fill_bytes(&mut salt);
+    res.extend_from_slice(&salt);
+    let mut nonce = [0; NONCE_SIZE];
+    rng.fill_bytes(&mut nonce);
+
+    let ciphertext = key
+        .object_key(&salt)
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: data,
+                aad: &res,
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+
+    res.extend_from_slice(&nonce);
+    res.extend_from_slice(&ciphertext);
+    Ok(res)
+}
+
+/// Checks and decrypts an object produced by `seal`.
+pub fn open(key: Option<&MasterKey>, sealed: &[u8]) -> Result<Vec<u8>> {
+    match Scheme::of(sealed)? {
+        Scheme::Plain => Ok(sealed[1..].to_vec()),
+        Scheme::XChaCha20Poly1305 => {
+            let key =
+                key.ok_or_else(|| anyhow::anyhow!("The object is encrypted, a key is required"))?;
+            if sealed.len() < 1 + SALT_SIZE + NONCE_SIZE {
+                return Err(anyhow::anyhow!("Truncated object"));
+            }
+
+            let (header, rest) = sealed.split_at(1 + SALT_SIZE);
+            let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+            key.object_key(&header[1..])
+                .decrypt(
+                    XNonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: header,
+                    },
+                )
+                .map_err(|_| anyhow::anyhow!("The object can't be decrypted with this key"))
+        }
+    }
+}
+
+/// Packs bytes into field elements: the length, then 31 bytes per element
+/// (little-endian).
+pub fn pack(bytes: &[u8]) -> Vec<Fr> {
+    std::iter::once(Fr::from(bytes.len() as u64))
+        .chain(
+            bytes
+                .chunks(BYTES_PER_ELEMENT)
+                .map(Fr::from_le_bytes_mod_order),
+        )
+        .collect()
+}
+
+/// Packs `bytes` like `pack` into exactly `len` elements, padding them with
+/// zeros, e.g. the `2^k` elements a node takes.
+///
+/// ```
+/// use client::encryption::{pack_padded, unpack};
+///
+/// let elements = pack_padded(b"hello", 4).unwrap();
+/// assert_eq!(elements.len(), 4);
+/// assert_eq!(unpack(&elements).unwrap(), b"hello");
+/// assert!(pack_padded(&[0; 100], 4).is_err());
+/// ```
+pub fn pack_padded(bytes: &[u8], len: usize) -> Result<Vec<Fr>> {
+    let mut elements = pack(bytes);
+    if elements.len() > len {
+        return Err(anyhow::anyhow!(
+            "{} bytes pack into {} elements, more than {}",
+            bytes.len(),
+            elements.len(),
+            len
+        ));
+    }
+    elements.resize(len, Fr::zero());
+
+    Ok(elements)
+}
+
+/// Reverses `pack` and `pack_padded`.
+pub fn unpack(elements: &[Fr]) -> Result<Vec<u8>> {
+    let (len, elements) = elements
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("No length element"))?;
+    let len = len.into_bigint();
+    if len.as_ref()[1..].iter().any(|limb| *limb != 0)
+        || len.as_ref()[0] > (elements.len() * BYTES_PER_ELEMENT) as u64
+    {
+        return Err(anyhow::anyhow!("Invalid length element"));
+    }
+
+    let mut res = Vec::with_capacity(elements.len() * BYTES_PER_ELEMENT);
+    for element in elements {
+        let bytes = element.into_bigint().to_bytes_le();
+        if bytes[BYTES_PER_ELEMENT..].iter().any(|b| *b != 0) {
+            return Err(anyhow::anyhow!(
+                "Element doesn't fit into {} bytes",
+                BYTES_PER_ELEMENT
+            ));
+        }
+        res.extend_from_slice(&bytes[..BYTES_PER_ELEMENT]);
+    }
+    res.truncate(len.as_ref()[0] as usize);
+
+    Ok(res)
+}
@@ -0,0 +1,411 @@
+//! Client of the storage node HTTP API.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use ark_bn254::{Fr, G1Affine};
+use ark_ff::Zero;
+use kzg::evm::g1_from_evm;
+use serde::Deserialize;
+use shamir_ss::Domain;
+
+use crate::encryption::MasterKey;
+use secp256k1::SecretKey;
+use web3::{
+    signing::{keccak256, Key, SecretKeyRef},
+    types::{Address, Bytes},
+};
+
+pub mod encryption;
+pub mod sampler;
+
+/// A state published to `StateRegistry`, as reported by `GET /state/{address}`.
+///
+/// A light client should check `commitment` against the registry rather than
+/// trust the node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StateInfo {
+    pub uploader: Address,
+    pub height: u64,
+    pub commitment: G1Affine,
+    /// Number of field elements in the original data.
+    pub size: u64,
+    /// Parameter of the `Domain` the data has been encoded with.
+    pub k: u32,
+    /// Number of encoded elements per chunk.
+    pub chunk_size: u32,
+}
+
+impl StateInfo {
+    /// Checks the parameters the way the node checks the records it reads from
+    /// `StateRegistry`: `k` is at most `max_k` and `chunk_size` is a power of
+    /// two of at most `2^(k+1)`, the size of the encoded data.
+    pub fn check(&self, max_k: u32) -> Result<()> {
+        let max_k = max_k.min(Domain::MAX_K as u32);
+        if self.k > max_k {
+            return Err(anyhow::anyhow!(
+                "State has k = {}, at most {} is supported",
+                self.k,
+                max_k
+            ));
+        }
+        if !self.chunk_size.is_power_of_two() || self.chunk_size > 2 << self.k {
+            return Err(anyhow::anyhow!(
+                "State has chunk size {}, expected a power of two up to {}",
+                self.chunk_size,
+                2u32 << self.k
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Number of chunks of a state that passes `check`.
+    pub fn num_chunks(&self) -> u32 {
+        (2u32 << self.k).div_ceil(self.chunk_size)
+    }
+}
+
+/// A chunk of a state with its proof against the state commitment.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub chunk: u32,
+    pub data: Vec<Fr>,
+    pub proof: G1Affine,
+}
+
+/// Identifies an uploaded object by the state it has been published as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub struct ObjectId {
+    pub uploader: Address,
+    pub height: u64,
+}
+
+/// Shape of the data a node accepts, as reported by `GET /data/params`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub struct UploadParams {
+    /// Parameter of the node's `Domain`, uploads have exactly `2^k` elements.
+    pub k: u32,
+    /// Number of encoded elements per chunk.
+    pub chunk_size: u32,
+}
+
+#[derive(Deserialize)]
+struct StateResponse {
+    uploader: Address,
+    height: u64,
+    commitment: Bytes,
+    size: u64,
+    k: u32,
+    chunk_size: u32,
+}
+
+#[derive(Deserialize)]
+struct ChunkResponse {
+    chunk: u32,
+    data: Vec<String>,
+    proof: Bytes,
+}
+
+/// How uploads authenticate with a node that has tenants.
+#[derive(Clone)]
+pub enum Auth {
+    /// `Authorization: Bearer <key>`.
+    ApiKey(String),
+    /// Signs the request body with the key of the tenant's address.
+    Key(SecretKey),
+}
+
+pub struct Client {
+    url: String,
+    http: reqwest::Client,
+    auth: Option<Auth>,
+}
+
+impl Client {
+    /// `url` is the base URL of a node, e.g. `http://localhost:3000`.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            http: reqwest::Client::new(),
+            auth: None,
+        }
+    }
+
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// The shape of the data `upload` must send to the node.
+    pub async fn upload_params(&self) -> Result<UploadParams> {
+        Ok(self
+            .http
+            .get(format!("{}/data/params", self.url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// The latest state of `uploader` known to the node, see `StateInfo::check`.
+    pub async fn latest_state(&self, uploader: Address) -> Result<StateInfo> {
+        let res = self
+            .http
+            .get(format!("{}/state/{:?}", self.url, uploader))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<StateResponse>()
+            .await?;
+
+        let state = StateInfo {
+            uploader: res.uploader,
+            height: res.height,
+            commitment: g1_from_evm(&res.commitment.0)?,
+            size: res.size,
+            k: res.k,
+            chunk_size: res.chunk_size,
+        };
+        state.check(Domain::MAX_K as u32)?;
+
+        Ok(state)
+    }
+
+    /// Requests the given chunks of `state`, the ones the node can't find are
+    /// missing from the result. The samples aren't verified.
+    pub async fn samples(&self, state: &StateInfo, chunks: &[u32]) -> Result<Vec<Sample>> {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| chunk.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let res = self
+            .http
+            .get(format!(
+                "{}/state/{:?}/{}/samples",
+                self.url, state.uploader, state.height
+            ))
+            .query(&[("chunks", chunks)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<ChunkResponse>>()
+            .await?;
+
+        res.into_iter()
+            .map(|chunk| {
+                Ok(Sample {
+                    chunk: chunk.chunk,
+                    data: chunk
+                        .data
+                        .iter()
+                        .map(|x| x.parse().map_err(|_| anyhow::anyhow!("Invalid element")))
+                        .collect::<Result<_>>()?,
+                    proof: g1_from_evm(&chunk.proof.0)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Uploads `data` as a new state of the node's account. It must have
+    /// exactly `2^k` elements, see `upload_params`.
+    pub async fn upload(&self, data: &[Fr]) -> Result<ObjectId> {
+        let body = serde_json::to_string(&data.iter().map(format_element).collect::<Vec<_>>())?;
+        let req = self
+            .http
+            .post(format!("{}/data", self.url))
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        let req = match &self.auth {
+            None => req,
+            Some(Auth::ApiKey(key)) => req.bearer_auth(key),
+            Some(Auth::Key(key)) => {
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                req.header("x-timestamp", timestamp)
+                    .header("x-signature", sign(key, timestamp, &body)?)
+            }
+        };
+
+        Ok(req
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// The data of a state, decoded by the node.
+    pub async fn download(&self, id: ObjectId) -> Result<Vec<Fr>> {
+        self.http
+            .get(format!(
+                "{}/state/{:?}/{}",
+                self.url, id.uploader, id.height
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<String>>()
+            .await?
+            .iter()
+            .map(|x| x.parse().map_err(|_| anyhow::anyhow!("Invalid element")))
+            .collect()
+    }
+
+    /// Seals `data` with `key` (see `encryption`), packs it into the `2^k`
+    /// field elements the node takes and uploads it. Objects that don't fit
+    /// are rejected before anything is sent.
+    pub async fn put_object(&self, key: Option<&MasterKey>, data: &[u8]) -> Result<ObjectId> {
+        let params = self.upload_params().await?;
+        let elements = encryption::pack_padded(&encryption::seal(key, data)?, 1 << params.k)
+            .map_err(|err| anyhow::anyhow!("The object doesn't fit into an upload: {}", err))?;
+
+        self.upload(&elements).await
+    }
+
+    /// Downloads an object uploaded with `put_object` and opens it with `key`.
+    pub async fn get_object(&self, key: Option<&MasterKey>, id: ObjectId) -> Result<Vec<u8>> {
+        encryption::open(key, &encryption::unpack(&self.download(id).await?)?)
+    }
+}
+
+/// The canonical decimal form the node takes elements in. `Fr` displays zero
+/// as an empty string.
+fn format_element(element: &Fr) -> String {
+    if element.is_zero() {
+        return "0".to_string();
+    }
+    element.to_string()
+}
+
+/// Signature of the node's signed messages (`identity::Signed` on the node):
+/// `keccak256("\x19Sharded Storage message:\n" || timestamp (8 bytes, big-endian) || payload)`,
+/// as 0x-prefixed `r || s || v` with `v` the recovery id.
+fn sign(key: &SecretKey, timestamp: u64, payload: &str) -> Result<String> {
+    let mut buf = b"\x19Sharded Storage message:\n".to_vec();
+    buf.extend_from_slice(&timestamp.to_be_bytes());
+    buf.extend_from_slice(payload.as_bytes());
+    let signature = SecretKeyRef::new(key).sign_message(&keccak256(&buf))?;
+
+    let mut bytes = signature.r.as_bytes().to_vec();
+    bytes.extend_from_slice(signature.s.as_bytes());
+    bytes.push(signature.v as u8);
+    Ok(format!(
+        "0x{}",
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        extract::{Path, State},
+        http::StatusCode,
+        routing::get,
+        Json, Router,
+    };
+
+    use super::*;
+
+    const K: u32 = 2;
+
+    /// A node that keeps the uploads in memory and, like the real one, only
+    /// accepts exactly `2^K` elements in their canonical form.
+    fn mock_node() -> String {
+        type Uploads = Arc<Mutex<Vec<Vec<String>>>>;
+
+        async fn upload(
+            State(uploads): State<Uploads>,
+            Json(data): Json<Vec<String>>,
+        ) -> Result<Json<serde_json::Value>, StatusCode> {
+            let canonical = |s: &String| {
+                s.parse::<Fr>().ok().map(|x| format_element(&x)).as_ref() == Some(s)
+            };
+            if data.len() != 1 << K || !data.iter().all(canonical) {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            let mut uploads = uploads.lock().unwrap();
+            uploads.push(data);
+            Ok(Json(serde_json::json!({
+                "uploader": Address::zero(),
+                "height": uploads.len() - 1,
+            })))
+        }
+
+        async fn download(
+            State(uploads): State<Uploads>,
+            Path((_, height)): Path<(Address, usize)>,
+        ) -> Result<Json<Vec<String>>, StatusCode> {
+            uploads
+                .lock()
+                .unwrap()
+                .get(height)
+                .cloned()
+                .map(Json)
+                .ok_or(StatusCode::NOT_FOUND)
+        }
+
+        let app = Router::new()
+            .route(
+                "/data/params",
+                get(|| async { Json(serde_json::json!({ "k": K, "chunk_size": 2 })) }),
+            )
+            .route("/data", axum::routing::post(upload))
+            .route("/state/:address/:height", get(download))
+            .with_state(Uploads::default());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        url
+    }
+
+    #[tokio::test]
+    async fn objects_round_trip_through_the_node() {
+        let client = Client::new(mock_node());
+        let key = MasterKey::generate();
+
+        for data in [&b""[..], b"hello", &[7; 92]] {
+            let id = client.put_object(None, data).await.unwrap();
+            assert_eq!(client.get_object(None, id).await.unwrap(), data);
+        }
+        for data in [&b""[..], b"hello", &[7; 20]] {
+            let id = client.put_object(Some(&key), data).await.unwrap();
+            assert_eq!(client.get_object(Some(&key), id).await.unwrap(), data);
+            assert!(client.get_object(None, id).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn objects_too_large_are_rejected_before_uploading() {
+        let client = Client::new(mock_node());
+
+        assert!(client.put_object(None, &[7; 93]).await.is_err());
+        assert!(client
+            .put_object(Some(&MasterKey::generate()), &[7; 21])
+            .await
+            .is_err());
+        // Nothing has been uploaded.
+        assert!(client
+            .download(ObjectId {
+                uploader: Address::zero(),
+                height: 0,
+            })
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use kzg::Crs;
+use shamir_ss::Domain;
+
+use crate::{Client, Sample, StateInfo};
+
+/// Outcome of sampling a state.
+#[derive(Clone, Debug)]
+pub struct Availability {
+    /// The chunks that have been requested.
+    pub sampled: Vec<u32>,
+    /// The requested chunks that have been received and match the commitment.
+    pub verified: Vec<u32>,
+    /// Probability that the data can be recovered, see `confidence`.
+    pub confidence: f64,
+}
+
+/// Requests `samples` random distinct chunks of `state` from the node,
+/// verifies them against the commitment and estimates the availability of
+/// the data. States `crs` is too small for are rejected.
+pub async fn sample(
+    client: &Client,
+    crs: &Crs,
+    state: &StateInfo,
+    samples: usize,
+) -> Result<Availability> {
+    state.check(crs.max_k())?;
+    let num_chunks = state.num_chunks() as usize;
+    let mut sampled =
+        rand::seq::index::sample(&mut rand::thread_rng(), num_chunks, samples.min(num_chunks))
+            .into_iter()
+            .map(|i| i as u32)
+            .collect::<Vec<_>>();
+    sampled.sort_unstable();
+
+    let domain = Domain::bit_reversed(state.k as usize);
+    let mut verified = BTreeSet::new();
+    for sample in client.samples(state, &sampled).await? {
+        if sampled.binary_search(&sample.chunk).is_ok() && verify(crs, &domain, state, &sample)? {
+            verified.insert(sample.chunk);
+        }
+    }
+
+    Ok(Availability {
+        confidence: confidence(num_chunks, sampled.len(), verified.len()),
+        sampled,
+        verified: verified.into_iter().collect(),
+    })
+}
+
+/// Checks the shape of `sample` and its proof against the commitment.
+pub fn verify(crs: &Crs, domain: &Domain, state: &StateInfo, sample: &Sample) -> Result<bool> {
+    let start = sample.chunk as usize * state.chunk_size as usize;
+    let expected = domain
+        .degrees
+        .len()
+        .saturating_sub(start)
+        .min(state.chunk_size as usize);
+    if expected == 0 || sample.data.len() != expected {
+        return Ok(false);
+    }
+
+    kzg::verify_multi(
+        crs,
+        &state.commitment,
+        &domain.degrees[start..start + expected],
+        &sample.data,
+        &sample.proof,
+    )
+}
+
+/// Lower bound on the probability that the data is recoverable given that
+/// `successes` of `samples` random distinct chunks out of `num_chunks` have
+/// been verified.
+///
+/// `Domain` is a rate 1/2 code, so the data can't be recovered only if more
+/// than half of the chunks are withheld. The bound is one minus the
+/// probability of seeing that many successes when just under half of the
+/// chunks are available (a hypergeometric tail):
+///
+/// ```
+/// use client::sampler::confidence;
+/// // Half of the chunks have been seen, the data is recoverable.
+/// assert_eq!(confidence(16, 8, 8), 1.0);
+/// // Withholding would have been noticed with probability over 1 - 2^-30.
+/// assert!(confidence(4096, 30, 30) > 1.0 - 1e-9);
+/// assert!(confidence(4096, 30, 0) < 1e-9);
+/// ```
+pub fn confidence(num_chunks: usize, samples: usize, successes: usize) -> f64 {
+    // The most chunks an adversary can publish while keeping the data unrecoverable.
+    let available = (num_chunks / 2).saturating_sub(1);
+
+    let total = ln_choose(num_chunks, samples);
+    let tail: f64 = (successes..=samples.min(available))
+        .map(|x| {
+            (ln_choose(available, x) + ln_choose(num_chunks - available, samples - x) - total).exp()
+        })
+        .sum();
+
+    (1.0 - tail).clamp(0.0, 1.0)
+}
+
+fn ln_choose(n: usize, k: usize) -> f64 {
+    if k > n {
+        return f64::NEG_INFINITY;
+    }
+
+    (0..k)
+        .map(|i| ((n - i) as f64).ln() - ((i + 1) as f64).ln())
+        .sum()
+}
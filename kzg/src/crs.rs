@@ -0,0 +1,559 @@
+use std::{fmt, io::Read, path::PathBuf, str::FromStr};
+
+use anyhow::Result;
+use ark_bn254::{Bn254, Fq, Fq2, Fr, G1Affine, G1Projective, G2Affine, G2Projective};
+use ark_ec::{pairing::Pairing, AffineRepr, VariableBaseMSM};
+use ark_ff::{BigInteger, BigInteger256, PrimeField, UniformRand};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+
+/// Magic bytes of the CRS files produced by `kzg10`.
+pub const MAGIC: &[u8; 4] = b"SCRS";
+/// Version of the CRS file format written by `to_bytes`, older versions
+/// (only `[1]_2` and `[τ]_2`, without Lagrange bases) are still read.
+pub const VERSION: u32 = 3;
+
+#[cfg(feature = "embedded-crs")]
+const EMBEDDED: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/../res/crs.bin"));
+
+/// KZG structured reference string: `[τ^i]_1` and `[τ^i]_2`.
+#[derive(CanonicalSerialize, CanonicalDeserialize)]
+pub struct Crs {
+    pub powers_of_g: Vec<G1Affine>,
+    /// At least `[1]_2` and `[τ]_2`, multi-point openings of `n` points need `n + 1` powers.
+    pub powers_of_h: Vec<G2Affine>,
+    /// Precomputed Lagrange bases, see `Crs::lagrange`.
+    pub lagrange: Vec<LagrangeBasis>,
+}
+
+/// `[L_j(τ)]_1` for the Lagrange polynomials of `points`.
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct LagrangeBasis {
+    pub points: Vec<Fr>,
+    pub basis: Vec<G1Affine>,
+}
+
+/// Where to load the CRS from.
+#[derive(Clone, Debug)]
+pub enum CrsSource {
+    /// The file the node has been built with (requires the `embedded-crs` feature).
+    Embedded,
+    /// A CRS file written by `kzg10`.
+    File(PathBuf),
+    /// A `.ptau` transcript of the Perpetual Powers of Tau ceremony (BN254).
+    Ptau(PathBuf),
+}
+
+impl FromStr for CrsSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            _ if s == "embedded" => Ok(CrsSource::Embedded),
+            Some(("file", path)) => Ok(CrsSource::File(path.into())),
+            Some(("ptau", path)) => Ok(CrsSource::Ptau(path.into())),
+            _ => Err(anyhow::anyhow!(
+                "Invalid CRS source {:?}, expected `embedded`, `file:<path>` or `ptau:<path>`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CrsSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrsSource::Embedded => write!(f, "embedded"),
+            CrsSource::File(path) => write!(f, "file:{}", path.display()),
+            CrsSource::Ptau(path) => write!(f, "ptau:{}", path.display()),
+        }
+    }
+}
+
+impl Crs {
+    /// Loads the CRS, checks that it supports polynomials of `min_size`
+    /// coefficients, multi-point openings of `min_g2_size - 1` points and that
+    /// its powers are consistent.
+    pub fn load(source: &CrsSource, min_size: usize, min_g2_size: usize) -> Result<Self> {
+        let crs = match source {
+            #[cfg(feature = "embedded-crs")]
+            CrsSource::Embedded => Self::from_bytes(EMBEDDED)?,
+            #[cfg(not(feature = "embedded-crs"))]
+            CrsSource::Embedded => {
+                return Err(anyhow::anyhow!(
+                    "The node has been built without the `embedded-crs` feature"
+                ))
+            }
+            CrsSource::File(path) => Self::from_bytes(&read(path)?)?,
+            CrsSource::Ptau(path) => Self::from_ptau(&read(path)?, min_size, min_g2_size)?,
+        };
+
+        if crs.powers_of_g.len() < min_size {
+            return Err(anyhow::anyhow!(
+                "CRS from {} supports polynomials of up to {} coefficients, {} are required",
+                source,
+                crs.powers_of_g.len(),
+                min_size
+            ));
+        }
+        if crs.powers_of_h.len() < min_g2_size {
+            return Err(anyhow::anyhow!(
+                "CRS from {} has {} G2 powers, {} are required",
+                source,
+                crs.powers_of_h.len(),
+                min_g2_size
+            ));
+        }
+
+        crs.verify()
+            .map_err(|err| anyhow::anyhow!("Invalid CRS from {}: {}", source, err))?;
+
+        Ok(crs)
+    }
+
+    /// Parses the versioned format written by `kzg10`: magic, version (u32 LE)
+    /// and the compressed `Crs`.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 8 || &buf[..4] != MAGIC {
+            return Err(anyhow::anyhow!(
+                "Not a CRS file (unversioned parameters from older `kzg10` builds must be regenerated)"
+            ));
+        }
+
+        let version = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+        let crs = match version {
+            1 => <(Vec<G1Affine>, G2Affine, G2Affine)>::deserialize_compressed(&buf[8..]).map(
+                |(powers_of_g, h, beta_h)| Self {
+                    powers_of_g,
+                    powers_of_h: vec![h, beta_h],
+                    lagrange: vec![],
+                },
+            ),
+            2 => <(Vec<G1Affine>, G2Affine, G2Affine, Vec<LagrangeBasis>)>::deserialize_compressed(
+                &buf[8..],
+            )
+            .map(|(powers_of_g, h, beta_h, lagrange)| Self {
+                powers_of_g,
+                powers_of_h: vec![h, beta_h],
+                lagrange,
+            }),
+            VERSION => Self::deserialize_compressed(&buf[8..]),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported CRS format version {}, expected {}",
+                    version,
+                    VERSION
+                ))
+            }
+        };
+
+        crs.map_err(|err| anyhow::anyhow!("Malformed CRS: {}", err))
+    }
+
+    /// The versioned format read by `from_bytes`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        self.serialize_compressed(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// Reads the first `size` G1 and `g2_size` G2 powers from a snarkjs `.ptau` file.
+    ///
+    /// Note that the Ethereum KZG ceremony is over BLS12-381 and can't be used
+    /// with BN254, the BN254 Perpetual Powers of Tau transcripts can.
+    pub fn from_ptau(buf: &[u8], size: usize, g2_size: usize) -> Result<Self> {
+        if buf.first() == Some(&b'{') {
+            return Err(anyhow::anyhow!(
+                "JSON transcripts (the Ethereum KZG ceremony) are BLS12-381, a BN254 .ptau file is required"
+            ));
+        }
+
+        let mut reader = buf;
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != b"ptau" {
+            return Err(anyhow::anyhow!("Not a .ptau file"));
+        }
+        let _version = read_u32(&mut reader)?;
+        let num_sections = read_u32(&mut reader)?;
+
+        let mut sections = std::collections::HashMap::new();
+        for _ in 0..num_sections {
+            let kind = read_u32(&mut reader)?;
+            let len = read_u64(&mut reader)? as usize;
+            if reader.len() < len {
+                return Err(anyhow::anyhow!("Truncated .ptau section {}", kind));
+            }
+            let (section, rest) = reader.split_at(len);
+            sections.insert(kind, section);
+            reader = rest;
+        }
+        let section = |kind: u32| {
+            sections
+                .get(&kind)
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("Missing .ptau section {}", kind))
+        };
+
+        let mut header = section(1)?;
+        let n8 = read_u32(&mut header)? as usize;
+        if n8 != 32 || header.len() < n8 + 4 || header[..n8] != Fq::MODULUS.to_bytes_le() {
+            return Err(anyhow::anyhow!("The .ptau file is not for BN254"));
+        }
+        header = &header[n8..];
+        let power = read_u32(&mut header)?;
+        let num_powers = 1usize
+            .checked_shl(power)
+            .ok_or_else(|| anyhow::anyhow!("Invalid .ptau power 2^{}", power))?;
+        let g2_size = g2_size.max(2);
+        if size.max(g2_size) > num_powers {
+            return Err(anyhow::anyhow!(
+                "The .ptau file has 2^{} powers, {} are required",
+                power,
+                size.max(g2_size)
+            ));
+        }
+
+        let g1 = section(2)?;
+        let g2 = section(3)?;
+        if g1.len() < size * 64 || g2.len() < g2_size * 128 {
+            return Err(anyhow::anyhow!("Truncated .ptau powers"));
+        }
+
+        let powers_of_g = g1
+            .chunks_exact(64)
+            .take(size)
+            .map(ptau_g1)
+            .collect::<Result<Vec<_>>>()?;
+
+        let powers_of_h = g2
+            .chunks_exact(128)
+            .take(g2_size)
+            .map(ptau_g2)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            powers_of_g,
+            powers_of_h,
+            lagrange: vec![],
+        })
+    }
+
+    /// Checks that `powers_of_g[i + 1] = τ * powers_of_g[i]` for the `τ` of
+    /// `[τ]_2` and that the G2 powers are powers of the same `τ`.
+    ///
+    /// All the powers are checked at once using a random linear combination.
+    /// Lagrange bases are checked by committing to a random polynomial in both
+    /// bases.
+    pub fn verify(&self) -> Result<()> {
+        if self.powers_of_g.first() != Some(&G1Affine::generator()) {
+            return Err(anyhow::anyhow!("The first power is not the G1 generator"));
+        }
+        if self.powers_of_h.len() < 2 || self.powers_of_h.iter().any(|p| p.is_zero()) {
+            return Err(anyhow::anyhow!("Degenerate G2 elements"));
+        }
+        if self.powers_of_h.len() >= self.powers_of_g.len() {
+            return Err(anyhow::anyhow!("Fewer G1 powers than G2 powers plus one"));
+        }
+
+        let n = self.powers_of_g.len() - 1;
+        let mut rng = rand::thread_rng();
+        let scalars = (0..=n).map(|_| Fr::rand(&mut rng)).collect::<Vec<_>>();
+        let lhs = G1Projective::msm_unchecked(&self.powers_of_g[1..], &scalars[..n]);
+        let rhs = G1Projective::msm_unchecked(&self.powers_of_g[..n], &scalars[..n]);
+
+        if Bn254::pairing(lhs, self.h()) != Bn254::pairing(rhs, self.beta_h()) {
+            return Err(anyhow::anyhow!("Powers of tau are inconsistent"));
+        }
+
+        let m = self.powers_of_h.len();
+        let lhs = G1Projective::msm_unchecked(&self.powers_of_g[..m], &scalars[..m]);
+        let rhs = G2Projective::msm_unchecked(&self.powers_of_h, &scalars[..m]);
+        if Bn254::pairing(lhs, self.h()) != Bn254::pairing(G1Affine::generator(), rhs) {
+            return Err(anyhow::anyhow!("G2 powers of tau are inconsistent"));
+        }
+
+        for lagrange in &self.lagrange {
+            let poly = (0..lagrange.points.len())
+                .map(|_| Fr::rand(&mut rng))
+                .collect::<Vec<_>>();
+            let evals = lagrange
+                .points
+                .iter()
+                .map(|x| crate::evaluate(&poly, *x))
+                .collect::<Vec<_>>();
+
+            if crate::commit_lagrange(&evals, &lagrange.basis)?
+                != crate::commit(&poly, &self.powers_of_g)?
+            {
+                return Err(anyhow::anyhow!(
+                    "Lagrange basis for {} points is inconsistent",
+                    lagrange.points.len()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The largest `k` such that polynomials of `2^k` coefficients can be
+    /// committed to.
+    pub fn max_k(&self) -> u32 {
+        self.powers_of_g.len().max(1).ilog2()
+    }
+
+    /// `[1]_2`.
+    pub fn h(&self) -> G2Affine {
+        self.powers_of_h[0]
+    }
+
+    /// `[τ]_2`.
+    pub fn beta_h(&self) -> G2Affine {
+        self.powers_of_h[1]
+    }
+
+    /// The precomputed Lagrange basis for exactly `points`, if any.
+    pub fn lagrange(&self, points: &[Fr]) -> Option<&[G1Affine]> {
+        self.lagrange
+            .iter()
+            .find(|lagrange| lagrange.points == points)
+            .map(|lagrange| &lagrange.basis[..])
+    }
+
+    /// Computes the Lagrange basis for `points` and stores it in the CRS.
+    pub fn add_lagrange(&mut self, points: &[Fr]) -> Result<()> {
+        if self.lagrange(points).is_none() {
+            let basis = self.lagrange_basis(points)?;
+            self.lagrange.push(LagrangeBasis {
+                points: points.to_vec(),
+                basis,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// `[L_j(τ)]_1` for the Lagrange polynomials of the distinct `points`, so
+    /// that committing to evaluations at `points` against them gives the
+    /// commitment of the interpolated polynomial.
+    pub fn lagrange_basis(&self, points: &[Fr]) -> Result<Vec<G1Affine>> {
+        if points.len() > self.powers_of_g.len() {
+            return Err(anyhow::anyhow!(
+                "CRS supports up to {} points, got {}",
+                self.powers_of_g.len(),
+                points.len()
+            ));
+        }
+
+        crate::lagrange_polynomials(points)?
+            .iter()
+            .map(|poly| crate::commit(poly, &self.powers_of_g))
+            .collect()
+    }
+}
+
+fn read(path: &PathBuf) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => anyhow::anyhow!(
+            "No CRS at {}, write one with `kzg10 export` or use a .ptau file",
+            path.display()
+        ),
+        _ => anyhow::anyhow!("Failed to read CRS from {}: {}", path.display(), err),
+    })
+}
+
+fn read_u32(reader: &mut &[u8]) -> Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut &[u8]) -> Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// `.ptau` field elements are little-endian in Montgomery form.
+fn ptau_fq(buf: &[u8]) -> Result<Fq> {
+    let repr = BigInteger256::deserialize_uncompressed(buf)?;
+    if repr >= Fq::MODULUS {
+        return Err(anyhow::anyhow!("Non-canonical .ptau field element"));
+    }
+
+    Ok(Fq::new_unchecked(repr))
+}
+
+fn ptau_g1(buf: &[u8]) -> Result<G1Affine> {
+    let point = G1Affine::new_unchecked(ptau_fq(&buf[..32])?, ptau_fq(&buf[32..])?);
+    if !point.is_on_curve() {
+        return Err(anyhow::anyhow!(".ptau G1 point is not on the curve"));
+    }
+
+    Ok(point)
+}
+
+fn ptau_g2(buf: &[u8]) -> Result<G2Affine> {
+    let x = Fq2::new(ptau_fq(&buf[..32])?, ptau_fq(&buf[32..64])?);
+    let y = Fq2::new(ptau_fq(&buf[64..96])?, ptau_fq(&buf[96..])?);
+    let point = G2Affine::new_unchecked(x, y);
+    if !point.is_on_curve() || !point.is_in_correct_subgroup_assuming_on_curve() {
+        return Err(anyhow::anyhow!(".ptau G2 point is not in G2"));
+    }
+
+    Ok(point)
+}
+
+#[cfg(test)]
+mod tests {
+    use ark_ec::CurveGroup;
+
+    use super::*;
+
+    fn crs(size: usize, g2_size: usize) -> Crs {
+        let tau = Fr::from(17);
+        let mut power = Fr::from(1);
+        let mut powers = vec![];
+        for _ in 0..size.max(g2_size) {
+            powers.push(power);
+            power *= tau;
+        }
+
+        Crs {
+            powers_of_g: powers[..size]
+                .iter()
+                .map(|p| (G1Affine::generator() * p).into_affine())
+                .collect(),
+            powers_of_h: powers[..g2_size]
+                .iter()
+                .map(|p| (G2Affine::generator() * p).into_affine())
+                .collect(),
+            lagrange: vec![],
+        }
+    }
+
+    #[test]
+    fn consistent_powers_are_accepted() {
+        crs(4, 3).verify().unwrap();
+    }
+
+    #[test]
+    fn as_many_g2_as_g1_powers_are_rejected() {
+        assert!(crs(3, 3).verify().is_err());
+        assert!(crs(2, 3).verify().is_err());
+    }
+
+    #[test]
+    fn inconsistent_powers_are_rejected() {
+        let mut crs = crs(4, 3);
+        crs.powers_of_g[2] = crs.powers_of_g[1];
+        assert!(crs.verify().is_err());
+    }
+
+    /// A BN254 `.ptau` file of 2^`power` powers with the given sections after the header.
+    fn ptau(power: u32, sections: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut header = 32u32.to_le_bytes().to_vec();
+        header.extend_from_slice(&Fq::MODULUS.to_bytes_le());
+        header.extend_from_slice(&power.to_le_bytes());
+
+        let mut buf = b"ptau".to_vec();
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.extend_from_slice(&(sections.len() as u32 + 1).to_le_bytes());
+        for (kind, section) in [(1, header)].iter().chain(sections) {
+            buf.extend_from_slice(&kind.to_le_bytes());
+            buf.extend_from_slice(&(section.len() as u64).to_le_bytes());
+            buf.extend_from_slice(section);
+        }
+        buf
+    }
+
+    /// Little-endian in Montgomery form, as `ptau_fq` reads them.
+    fn ptau_elements(elements: impl IntoIterator<Item = Fq>) -> Vec<u8> {
+        elements
+            .into_iter()
+            .flat_map(|x| x.0.to_bytes_le())
+            .collect()
+    }
+
+    #[test]
+    fn ptau_powers_are_read() {
+        let crs = crs(8, 4);
+        let g1 = ptau_elements(crs.powers_of_g.iter().flat_map(|p| [p.x, p.y]));
+        let g2 = ptau_elements(
+            crs.powers_of_h
+                .iter()
+                .flat_map(|p| [p.x.c0, p.x.c1, p.y.c0, p.y.c1]),
+        );
+        let buf = ptau(3, &[(2, g1), (3, g2)]);
+
+        let read = Crs::from_ptau(&buf, 6, 3).unwrap();
+        assert_eq!(read.powers_of_g, crs.powers_of_g[..6]);
+        assert_eq!(read.powers_of_h, crs.powers_of_h[..3]);
+        read.verify().unwrap();
+
+        let err = Crs::from_ptau(&buf, 9, 3).err().unwrap();
+        assert!(err.to_string().contains("has 2^3 powers"), "{}", err);
+        let err = Crs::from_ptau(&buf, 6, 5).err().unwrap();
+        assert!(err.to_string().contains("Truncated"), "{}", err);
+        assert!(Crs::from_ptau(&buf[..buf.len() - 1], 6, 3).is_err());
+    }
+
+    #[test]
+    fn ptau_with_an_out_of_range_power_is_rejected() {
+        let err = Crs::from_ptau(&ptau(64, &[]), 4, 2).err().unwrap();
+        assert!(err.to_string().contains("Invalid .ptau power"), "{}", err);
+    }
+
+    #[test]
+    fn all_format_versions_are_read() {
+        let mut crs = crs(4, 3);
+        crs.add_lagrange(&[1, 2, 3].map(Fr::from)).unwrap();
+        let same = |read: &Crs, g2_size: usize, lagrange: bool| {
+            assert_eq!(read.powers_of_g, crs.powers_of_g);
+            assert_eq!(read.powers_of_h, crs.powers_of_h[..g2_size]);
+            assert_eq!(read.lagrange.len(), lagrange as usize);
+            if lagrange {
+                assert_eq!(read.lagrange[0].points, crs.lagrange[0].points);
+                assert_eq!(read.lagrange[0].basis, crs.lagrange[0].basis);
+            }
+        };
+        let versioned = |version: u32, body: Vec<u8>| {
+            [MAGIC.to_vec(), version.to_le_bytes().to_vec(), body].concat()
+        };
+
+        same(&Crs::from_bytes(&crs.to_bytes().unwrap()).unwrap(), 3, true);
+
+        let (h, beta_h) = (crs.powers_of_h[0], crs.powers_of_h[1]);
+        let mut v1 = vec![];
+        (crs.powers_of_g.clone(), h, beta_h)
+            .serialize_compressed(&mut v1)
+            .unwrap();
+        same(&Crs::from_bytes(&versioned(1, v1)).unwrap(), 2, false);
+
+        let mut v2 = vec![];
+        (crs.powers_of_g.clone(), h, beta_h, crs.lagrange.clone())
+            .serialize_compressed(&mut v2)
+            .unwrap();
+        same(&Crs::from_bytes(&versioned(2, v2)).unwrap(), 2, true);
+    }
+
+    #[test]
+    fn other_format_versions_are_rejected() {
+        let buf = crs(4, 3).to_bytes().unwrap();
+        for version in [0, VERSION + 1] {
+            let mut other = buf.clone();
+            other[4..8].copy_from_slice(&version.to_le_bytes());
+            let err = Crs::from_bytes(&other).err().unwrap();
+            assert!(
+                err.to_string().contains("Unsupported CRS format version"),
+                "{}",
+                err
+            );
+        }
+
+        assert!(Crs::from_bytes(&buf[1..]).is_err());
+        let err = Crs::from_bytes(&buf[..buf.len() - 1]).err().unwrap();
+        assert!(err.to_string().contains("Malformed"), "{}", err);
+    }
+}
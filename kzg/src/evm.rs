@@ -0,0 +1,51 @@
+use anyhow::Result;
+use ark_bn254::{Fq, G1Affine, G2Affine};
+use ark_ec::AffineRepr;
+use ark_ff::{BigInteger, PrimeField};
+
+/// Uncompressed big-endian encoding used by the EVM precompiles (EIP-196).
+pub fn g1_to_evm(point: &G1Affine) -> [u8; 64] {
+    let mut buf = [0; 64];
+    if let Some((x, y)) = point.xy() {
+        buf[..32].copy_from_slice(&x.into_bigint().to_bytes_be());
+        buf[32..].copy_from_slice(&y.into_bigint().to_bytes_be());
+    }
+    buf
+}
+
+/// Encoding of the `ecPairing` precompile (EIP-197): the imaginary part of
+/// every coordinate goes first.
+pub fn g2_to_evm(point: &G2Affine) -> [u8; 128] {
+    let mut buf = [0; 128];
+    if let Some((x, y)) = point.xy() {
+        for (i, c) in [x.c1, x.c0, y.c1, y.c0].iter().enumerate() {
+            buf[i * 32..(i + 1) * 32].copy_from_slice(&c.into_bigint().to_bytes_be());
+        }
+    }
+    buf
+}
+
+pub fn g1_from_evm(buf: &[u8]) -> Result<G1Affine> {
+    if buf.len() != 64 {
+        return Err(anyhow::anyhow!("Invalid G1 point length: {}", buf.len()));
+    }
+
+    if buf.iter().all(|b| *b == 0) {
+        return Ok(G1Affine::identity());
+    }
+
+    let coordinate = |bytes: &[u8]| {
+        let value = Fq::from_be_bytes_mod_order(bytes);
+        if value.into_bigint().to_bytes_be() != bytes {
+            return Err(anyhow::anyhow!("Non-canonical G1 coordinate"));
+        }
+        Ok(value)
+    };
+
+    let point = G1Affine::new_unchecked(coordinate(&buf[..32])?, coordinate(&buf[32..])?);
+    if !point.is_on_curve() {
+        return Err(anyhow::anyhow!("G1 point is not on the curve"));
+    }
+
+    Ok(point)
+}
@@ -0,0 +1,193 @@
+use anyhow::Result;
+use ark_bn254::{Fr, G1Affine, G1Projective};
+use ark_ec::{AffineRepr, CurveGroup};
+use ark_ff::{Field, Zero};
+use ark_poly::{EvaluationDomain, Radix2EvaluationDomain};
+
+use crate::Crs;
+
+/// Multi-point proofs for all the cosets of a domain at once, computed in
+/// `O(n log n)` with the Feist–Khovratovich technique.
+///
+/// The domain consists of the `domain_size`-th roots of unity in bit-reversed
+/// order, `ω^bitrev(i)` at position `i`, so the points of every block of
+/// `coset_size` consecutive positions form a coset `{x : x^coset_size = a}`.
+/// The proof of a block is the commitment of the quotient by
+/// `X^coset_size - a`, the one `verify_multi` checks.
+///
+/// ```
+/// use ark_bn254::{Fr, G1Affine, G2Affine};
+/// use ark_ec::{AffineRepr, CurveGroup};
+/// use ark_ff::Field;
+/// use kzg::{fk20::Fk20, Crs};
+///
+/// let tau = Fr::from(17);
+/// let crs = Crs {
+///     powers_of_g: (0..8u64)
+///         .map(|i| (G1Affine::generator() * tau.pow([i])).into_affine())
+///         .collect(),
+///     powers_of_h: (0..3u64)
+///         .map(|i| (G2Affine::generator() * tau.pow([i])).into_affine())
+///         .collect(),
+///     lagrange: vec![],
+/// };
+///
+/// let poly = [1, 2, 3, 4].map(Fr::from);
+/// let commitment = kzg::commit(&poly, &crs.powers_of_g).unwrap();
+/// let fk20 = Fk20::new(&crs, 8, 2).unwrap();
+/// let proofs = fk20.prove(&poly).unwrap();
+/// assert_eq!(proofs.len(), 4);
+///
+/// for (j, proof) in proofs.iter().enumerate() {
+///     let points = fk20.coset(j);
+///     let values = points.iter().map(|x| kzg::evaluate(&poly, *x)).collect::<Vec<_>>();
+///     assert_eq!(*proof, kzg::open_multi(&crs, &poly, &points).unwrap());
+///     assert!(kzg::verify_multi(&crs, &commitment, &points, &values, proof).unwrap());
+/// }
+///
+/// let evals = (0..8).flat_map(|j| fk20.coset(j / 2).into_iter().skip(j % 2).take(1));
+/// let evals = evals.map(|x| kzg::evaluate(&poly, x)).collect::<Vec<_>>();
+/// assert_eq!(fk20.prove_evaluations(&evals).unwrap(), proofs);
+/// ```
+pub struct Fk20 {
+    coset_size: usize,
+    /// The whole domain.
+    domain: Radix2EvaluationDomain<Fr>,
+    /// The values of `a`, in the natural order.
+    cosets: Radix2EvaluationDomain<Fr>,
+    /// Domain of the circulant products.
+    toeplitz: Radix2EvaluationDomain<Fr>,
+    /// For every residue `r` modulo `coset_size`, the FFT of
+    /// `[τ^(u * coset_size + r)]_1` in reverse order of `u`, zero-padded.
+    points: Vec<Vec<G1Projective>>,
+    max_degree: usize,
+}
+
+impl Fk20 {
+    pub fn new(crs: &Crs, domain_size: usize, coset_size: usize) -> Result<Self> {
+        if !domain_size.is_power_of_two()
+            || !coset_size.is_power_of_two()
+            || coset_size > domain_size
+        {
+            return Err(anyhow::anyhow!(
+                "Can't split a domain of {} points into cosets of {}",
+                domain_size,
+                coset_size
+            ));
+        }
+
+        let num_cosets = domain_size / coset_size;
+        let domain = |size| {
+            Radix2EvaluationDomain::new(size)
+                .ok_or_else(|| anyhow::anyhow!("No evaluation domain of size {}", size))
+        };
+        let toeplitz = domain(2 * num_cosets)?;
+
+        let points = (0..coset_size)
+            .map(|r| {
+                let mut reversed = vec![G1Projective::zero(); 2 * num_cosets];
+                for u in 0..num_cosets {
+                    if let Some(point) = crs.powers_of_g.get(u * coset_size + r) {
+                        reversed[num_cosets - 1 - u] = point.into_group();
+                    }
+                }
+                toeplitz.fft_in_place(&mut reversed);
+                reversed
+            })
+            .collect();
+
+        Ok(Self {
+            coset_size,
+            domain: domain(domain_size)?,
+            cosets: domain(num_cosets)?,
+            toeplitz,
+            points,
+            max_degree: crs.powers_of_g.len().min(domain_size),
+        })
+    }
+
+    pub fn num_cosets(&self) -> usize {
+        self.cosets.size()
+    }
+
+    /// The points at positions `j * coset_size..(j + 1) * coset_size`.
+    pub fn coset(&self, j: usize) -> Vec<Fr> {
+        let bits = self.domain.log_size_of_group;
+        (j * self.coset_size..(j + 1) * self.coset_size)
+            .map(|i| self.domain.group_gen.pow([bit_reverse(i, bits) as u64]))
+            .collect()
+    }
+
+    /// Proofs of all the cosets, in the order of positions, for the polynomial
+    /// given by its coefficients.
+    pub fn prove(&self, poly: &[Fr]) -> Result<Vec<G1Affine>> {
+        let len = poly.iter().rposition(|c| !c.is_zero()).map_or(0, |i| i + 1);
+        if len > self.max_degree {
+            return Err(anyhow::anyhow!(
+                "Can't prove polynomials of more than {} coefficients, got {}",
+                self.max_degree,
+                len
+            ));
+        }
+
+        // The quotient by `X^l - a` is `Σ_t a^(t - 1) H_t`, with
+        // `H_t = Σ_j f_(j + t l) [τ^j]_1`: for every residue of `j` modulo `l`
+        // that's a Toeplitz matrix-vector product, done as a circulant one.
+        let m = self.num_cosets();
+        let mut acc = vec![G1Projective::zero(); 2 * m];
+        for (r, points) in self.points.iter().enumerate() {
+            let mut coeffs = (0..m)
+                .map(|v| {
+                    poly.get(v * self.coset_size + r)
+                        .copied()
+                        .unwrap_or_default()
+                })
+                .collect::<Vec<_>>();
+            self.toeplitz.fft_in_place(&mut coeffs);
+
+            for ((acc, point), c) in acc.iter_mut().zip(points).zip(coeffs) {
+                *acc += *point * c;
+            }
+        }
+        self.toeplitz.ifft_in_place(&mut acc);
+
+        // `H_1, ..., H_(m - 1)` are the coefficients of the proof as a
+        // polynomial in `a`, evaluated at all `a` at once.
+        let mut h = vec![G1Projective::zero(); m];
+        h[..m - 1].copy_from_slice(&acc[m..2 * m - 1]);
+        self.cosets.fft_in_place(&mut h);
+        let proofs = G1Projective::normalize_batch(&h);
+
+        // Coset `j` is the one of `a = ω_m^bitrev(j)`.
+        let bits = self.cosets.log_size_of_group;
+        Ok((0..m).map(|j| proofs[bit_reverse(j, bits)]).collect())
+    }
+
+    /// Same as `prove` for the polynomial given by its evaluations at all the
+    /// points, in the order of positions.
+    pub fn prove_evaluations(&self, evals: &[Fr]) -> Result<Vec<G1Affine>> {
+        if evals.len() != self.domain.size() {
+            return Err(anyhow::anyhow!(
+                "Expected {} evaluations, got {}",
+                self.domain.size(),
+                evals.len()
+            ));
+        }
+
+        let bits = self.domain.log_size_of_group;
+        let mut coeffs = (0..evals.len())
+            .map(|i| evals[bit_reverse(i, bits)])
+            .collect::<Vec<_>>();
+        self.domain.ifft_in_place(&mut coeffs);
+
+        self.prove(&coeffs)
+    }
+}
+
+fn bit_reverse(i: usize, bits: u32) -> usize {
+    if bits == 0 {
+        return 0;
+    }
+
+    i.reverse_bits() >> (usize::BITS - bits)
+}
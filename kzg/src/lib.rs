@@ -0,0 +1,315 @@
+//! KZG polynomial commitments over BN254.
+//!
+//! Polynomials are given by their coefficients (lowest degree first) and
+//! committed against the powers of the `Crs`, or by their evaluations and
+//! committed against a Lagrange basis for the evaluation points, which can
+//! be precomputed and shipped with the CRS (`Crs::add_lagrange`).
+//!
+//! ```
+//! use ark_bn254::{Fr, G1Affine, G2Affine};
+//! use ark_ec::{AffineRepr, CurveGroup};
+//! use kzg::Crs;
+//!
+//! // A setup with a known trapdoor, only good for tests.
+//! let tau = Fr::from(17);
+//! let crs = Crs {
+//!     powers_of_g: (0..4u64)
+//!         .map(|i| (G1Affine::generator() * tau.pow([i])).into_affine())
+//!         .collect(),
+//!     powers_of_h: vec![G2Affine::generator(), (G2Affine::generator() * tau).into_affine()],
+//!     lagrange: vec![],
+//! };
+//! # use ark_ff::Field;
+//!
+//! let poly = [1, 2, 3, 4].map(Fr::from);
+//! let commitment = kzg::commit(&poly, &crs.powers_of_g).unwrap();
+//! let opening = kzg::open(&crs, &poly, Fr::from(5)).unwrap();
+//! assert_eq!(opening.value, Fr::from(1 + 2 * 5 + 3 * 25 + 4 * 125));
+//! assert!(kzg::verify(&crs, &commitment, &opening));
+//!
+//! let openings = kzg::open_batch(&crs, &poly, &[Fr::from(6), Fr::from(7)]).unwrap();
+//! let batch = openings.iter().map(|o| (commitment, *o)).collect::<Vec<_>>();
+//! assert!(kzg::verify_batch(&crs, &batch));
+//!
+//! let points = [1, 2, 3, 4].map(Fr::from);
+//! let evals = points.map(|x| kzg::evaluate(&poly, x));
+//! let mut crs = crs;
+//! crs.add_lagrange(&points).unwrap();
+//! crs.verify().unwrap();
+//! let basis = crs.lagrange(&points).unwrap();
+//! assert_eq!(kzg::commit_lagrange(&evals, basis).unwrap(), commitment);
+//! ```
+
+use anyhow::Result;
+use ark_bn254::{Bn254, Fr, G1Affine, G1Projective, G2Projective};
+use ark_ec::{pairing::Pairing, AffineRepr, CurveGroup, VariableBaseMSM};
+use ark_ff::{Field, UniformRand};
+
+pub use crate::crs::{Crs, CrsSource, LagrangeBasis};
+
+mod crs;
+pub mod evm;
+pub mod fk20;
+
+/// Evaluation of a committed polynomial at `point` with the proof of it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Opening {
+    pub point: Fr,
+    pub value: Fr,
+    pub proof: G1Affine,
+}
+
+/// Commits to a polynomial given by its coefficients.
+pub fn commit(poly: &[Fr], points: &[G1Affine]) -> Result<G1Affine> {
+    if poly.len() > points.len() {
+        return Err(anyhow::anyhow!(
+            "CRS supports polynomials of up to {} coefficients, got {}",
+            points.len(),
+            poly.len()
+        ));
+    }
+
+    Ok(G1Projective::msm_unchecked(&points[..poly.len()], poly).into_affine())
+}
+
+/// Commits to a polynomial given by its evaluations at the points `basis`
+/// has been computed for.
+pub fn commit_lagrange(evals: &[Fr], basis: &[G1Affine]) -> Result<G1Affine> {
+    if evals.len() != basis.len() {
+        return Err(anyhow::anyhow!(
+            "Lagrange basis has {} points, got {} evaluations",
+            basis.len(),
+            evals.len()
+        ));
+    }
+
+    Ok(G1Projective::msm_unchecked(basis, evals).into_affine())
+}
+
+pub fn evaluate(poly: &[Fr], point: Fr) -> Fr {
+    poly.iter().rev().fold(Fr::ZERO, |acc, c| acc * point + c)
+}
+
+/// Opens the polynomial at `point`: the proof commits to
+/// `(p(X) - p(point)) / (X - point)`.
+pub fn open(crs: &Crs, poly: &[Fr], point: Fr) -> Result<Opening> {
+    let (quotient, value) = divide_by_linear(poly, point);
+
+    Ok(Opening {
+        point,
+        value,
+        proof: commit(&quotient, &crs.powers_of_g)?,
+    })
+}
+
+/// Opens the polynomial at every one of `points`.
+pub fn open_batch(crs: &Crs, poly: &[Fr], points: &[Fr]) -> Result<Vec<Opening>> {
+    points.iter().map(|point| open(crs, poly, *point)).collect()
+}
+
+/// Checks `e(C - [v]_1, [1]_2) = e(π, [τ]_2 - [z]_2)`.
+pub fn verify(crs: &Crs, commitment: &G1Affine, opening: &Opening) -> bool {
+    let lhs = commitment.into_group() - G1Affine::generator() * opening.value;
+    let rhs = crs.beta_h().into_group() - crs.h() * opening.point;
+
+    Bn254::pairing(lhs, crs.h()) == Bn254::pairing(opening.proof, rhs)
+}
+
+/// Checks many openings, possibly of different commitments, with two pairings.
+///
+/// Every check is rewritten as `e(C - [v]_1 + z * π, [1]_2) = e(π, [τ]_2)`
+/// and the checks are combined with random coefficients.
+pub fn verify_batch(crs: &Crs, openings: &[(G1Affine, Opening)]) -> bool {
+    let mut rng = rand::thread_rng();
+    let mut lhs = G1Projective::default();
+    let mut rhs = G1Projective::default();
+    for (commitment, opening) in openings {
+        let r = Fr::rand(&mut rng);
+        lhs += (commitment.into_group() - G1Affine::generator() * opening.value
+            + opening.proof * opening.point)
+            * r;
+        rhs += opening.proof * r;
+    }
+
+    Bn254::pairing(lhs, crs.h()) == Bn254::pairing(rhs, crs.beta_h())
+}
+
+/// Opens the polynomial at all of `points` with a single proof: the commitment
+/// of `(p(X) - I(X)) / Z(X)`, where `I` interpolates `p` on `points` and `Z`
+/// vanishes on them.
+pub fn open_multi(crs: &Crs, poly: &[Fr], points: &[Fr]) -> Result<G1Affine> {
+    let (quotient, _) = divide(poly, &vanishing(points));
+
+    commit(&quotient, &crs.powers_of_g)
+}
+
+/// Checks `e(C - [I(τ)]_1, [1]_2) = e(π, [Z(τ)]_2)` for the opening of
+/// `points` to `values`.
+pub fn verify_multi(
+    crs: &Crs,
+    commitment: &G1Affine,
+    points: &[Fr],
+    values: &[Fr],
+    proof: &G1Affine,
+) -> Result<bool> {
+    let z = vanishing(points);
+    if z.len() > crs.powers_of_h.len() {
+        return Err(anyhow::anyhow!(
+            "CRS supports openings of up to {} points, got {}",
+            crs.powers_of_h.len() - 1,
+            points.len()
+        ));
+    }
+
+    let lhs = commitment.into_group() - commit(&interpolate(points, values)?, &crs.powers_of_g)?;
+    let rhs = G2Projective::msm_unchecked(&crs.powers_of_h[..z.len()], &z);
+
+    Ok(Bn254::pairing(lhs, crs.h()) == Bn254::pairing(proof, rhs))
+}
+
+/// Commitments of the rows of a 2D encoding (see `shamir_ss::Domain2D`) at
+/// `points`, given the commitments of the rows at `data_points`.
+///
+/// Every column is a polynomial over the rows, so the row at `x` is
+/// `Σ_j L_j(x) row_j` and, commitments being linear, so is its commitment.
+/// This lets anyone check the commitments of the parity rows without the data:
+///
+/// ```
+/// use ark_bn254::{Fr, G1Affine};
+/// use ark_ec::{AffineRepr, CurveGroup};
+///
+/// let points = (0..4u64)
+///     .map(|i| (G1Affine::generator() * Fr::from(i + 2)).into_affine())
+///     .collect::<Vec<_>>();
+/// let rows = [[1, 2, 3, 4].map(Fr::from), [5, 6, 7, 8].map(Fr::from)];
+/// let data_points = [1, 2].map(Fr::from);
+/// let commitments = rows
+///     .iter()
+///     .map(|row| kzg::commit(row, &points).unwrap())
+///     .collect::<Vec<_>>();
+///
+/// // The row at 3, column by column.
+/// let row = (0..4)
+///     .map(|i| {
+///         let column = kzg::interpolate(&data_points, &[rows[0][i], rows[1][i]]).unwrap();
+///         kzg::evaluate(&column, Fr::from(3))
+///     })
+///     .collect::<Vec<_>>();
+///
+/// let extended = kzg::extend_commitments(&data_points, &[Fr::from(3)], &commitments).unwrap();
+/// assert_eq!(extended, vec![kzg::commit(&row, &points).unwrap()]);
+/// ```
+pub fn extend_commitments(
+    data_points: &[Fr],
+    points: &[Fr],
+    commitments: &[G1Affine],
+) -> Result<Vec<G1Affine>> {
+    if data_points.len() != commitments.len() {
+        return Err(anyhow::anyhow!(
+            "Got {} commitments for {} rows",
+            commitments.len(),
+            data_points.len()
+        ));
+    }
+
+    let lagrange = lagrange_polynomials(data_points)?;
+    let extended = points
+        .iter()
+        .map(|x| {
+            let coeffs = lagrange
+                .iter()
+                .map(|poly| evaluate(poly, *x))
+                .collect::<Vec<_>>();
+            G1Projective::msm_unchecked(commitments, &coeffs)
+        })
+        .collect::<Vec<_>>();
+
+    Ok(G1Projective::normalize_batch(&extended))
+}
+
+/// Coefficients of `Π (X - x)` over `points`.
+pub fn vanishing(points: &[Fr]) -> Vec<Fr> {
+    let mut res = vec![Fr::ONE];
+    for x in points {
+        res.push(Fr::ZERO);
+        for i in (1..res.len()).rev() {
+            res[i] = res[i - 1] - res[i] * x;
+        }
+        res[0] = -res[0] * x;
+    }
+    res
+}
+
+/// The polynomial of degree below `points.len()` taking `values` at `points`.
+pub fn interpolate(points: &[Fr], values: &[Fr]) -> Result<Vec<Fr>> {
+    if points.len() != values.len() {
+        return Err(anyhow::anyhow!(
+            "Got {} values for {} points",
+            values.len(),
+            points.len()
+        ));
+    }
+
+    let mut res = vec![Fr::ZERO; points.len()];
+    for (poly, value) in lagrange_polynomials(points)?.iter().zip(values) {
+        for (r, c) in res.iter_mut().zip(poly) {
+            *r += *c * value;
+        }
+    }
+    Ok(res)
+}
+
+/// Coefficients of the Lagrange polynomials of the distinct `points`.
+fn lagrange_polynomials(points: &[Fr]) -> Result<Vec<Vec<Fr>>> {
+    let full = vanishing(points);
+
+    points
+        .iter()
+        .map(|x_j| {
+            // Π_{m != j} (X - x_m) = full / (X - x_j)
+            let (mut quotient, _) = divide_by_linear(&full, *x_j);
+            let denominator = evaluate(&quotient, *x_j)
+                .inverse()
+                .ok_or_else(|| anyhow::anyhow!("Interpolation points must be distinct"))?;
+            quotient.iter_mut().for_each(|c| *c *= denominator);
+
+            Ok(quotient)
+        })
+        .collect()
+}
+
+/// Divides `poly` by the monic `divisor`, returns the quotient and the remainder.
+fn divide(poly: &[Fr], divisor: &[Fr]) -> (Vec<Fr>, Vec<Fr>) {
+    let d = divisor.len() - 1;
+    if poly.len() <= d {
+        return (vec![], poly.to_vec());
+    }
+
+    let mut remainder = poly.to_vec();
+    let mut quotient = vec![Fr::ZERO; poly.len() - d];
+    for i in (0..quotient.len()).rev() {
+        let c = remainder[i + d];
+        quotient[i] = c;
+        for (r, z) in remainder[i..=i + d].iter_mut().zip(divisor) {
+            *r -= c * z;
+        }
+    }
+    remainder.truncate(d);
+
+    (quotient, remainder)
+}
+
+/// Divides `poly` by `X - point`, returns the quotient and the remainder `poly(point)`.
+fn divide_by_linear(poly: &[Fr], point: Fr) -> (Vec<Fr>, Fr) {
+    let mut quotient = vec![Fr::ZERO; poly.len().saturating_sub(1)];
+    let mut acc = Fr::ZERO;
+    for i in (0..poly.len()).rev() {
+        let next = poly[i] + acc * point;
+        if i > 0 {
+            quotient[i - 1] = next;
+        }
+        acc = next;
+    }
+
+    (quotient, acc)
+}
@@ -0,0 +1,301 @@
+use std::io::Read;
+
+use anyhow::Result;
+use ark_bn254::{Bn254, Fr, G1Affine, G1Projective, G2Affine, G2Projective};
+use ark_ec::{pairing::Pairing, AffineRepr, CurveGroup};
+use ark_ff::{Field, PrimeField, UniformRand, Zero};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use kzg::Crs;
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+/// Magic bytes of ceremony transcripts.
+pub const MAGIC: &[u8; 4] = b"STAU";
+/// Version of the transcript format.
+pub const VERSION: u32 = 2;
+
+/// One participant's update of the setup: `τ' = x * τ`.
+#[derive(Clone, CanonicalSerialize, CanonicalDeserialize)]
+pub struct Contribution {
+    /// `[τ']_1` after this contribution.
+    pub tau_g1: G1Affine,
+    /// `[x]_1`.
+    pub pubkey_g1: G1Affine,
+    /// `[x]_2`.
+    pub pubkey_g2: G2Affine,
+    /// Schnorr proof of knowledge of `x`: commitment `[r]_1` and response `r + c * x`.
+    pub pok_r: G1Affine,
+    pub pok_s: Fr,
+}
+
+/// Current powers of the ceremony with the chain of contributions that led to them.
+#[derive(CanonicalSerialize, CanonicalDeserialize)]
+pub struct Transcript {
+    pub powers_of_g: Vec<G1Affine>,
+    pub powers_of_h: Vec<G2Affine>,
+    pub contributions: Vec<Contribution>,
+}
+
+impl Transcript {
+    /// Starts a ceremony for `size` G1 and `g2_size` G2 powers with `τ = 1`.
+    pub fn new(size: usize, g2_size: usize) -> Result<Self> {
+        if g2_size < 2 || g2_size >= size {
+            return Err(anyhow::anyhow!(
+                "At least 2 G2 powers and more G1 than G2 powers are required"
+            ));
+        }
+
+        Ok(Self {
+            powers_of_g: vec![G1Affine::generator(); size],
+            powers_of_h: vec![G2Affine::generator(); g2_size],
+            contributions: vec![],
+        })
+    }
+
+    /// Multiplies `τ` by a secret derived from the OS RNG and `entropy`.
+    ///
+    /// The secret is dropped when this returns, it is never written anywhere.
+    pub fn contribute(&mut self, entropy: &[u8]) {
+        let mut seed = [0; 64];
+        OsRng.fill_bytes(&mut seed);
+        let mut x = Fr::from_le_bytes_mod_order(
+            &Sha256::new()
+                .chain_update(seed)
+                .chain_update(entropy)
+                .finalize(),
+        );
+        if x.is_zero() {
+            x = Fr::ONE;
+        }
+
+        self.powers_of_g = scale::<G1Projective>(&self.powers_of_g, x);
+        self.powers_of_h = scale::<G2Projective>(&self.powers_of_h, x);
+
+        let g = G1Affine::generator();
+        let pubkey_g1 = (g * x).into_affine();
+        let r = Fr::rand(&mut OsRng);
+        let pok_r = (g * r).into_affine();
+        let prev = self.last_tau_g1();
+        let c = challenge(self.contributions.len(), &prev, &pubkey_g1, &pok_r);
+
+        self.contributions.push(Contribution {
+            tau_g1: self.powers_of_g[1],
+            pubkey_g1,
+            pubkey_g2: (G2Affine::generator() * x).into_affine(),
+            pok_r,
+            pok_s: r + c * x,
+        });
+    }
+
+    /// Checks every contribution of the chain and that the current powers are
+    /// the result of applying them.
+    pub fn verify(&self) -> Result<()> {
+        let g = G1Affine::generator();
+        let h = G2Affine::generator();
+
+        let mut prev = g;
+        for (i, c) in self.contributions.iter().enumerate() {
+            if c.pubkey_g1.is_zero() || c.pubkey_g2.is_zero() {
+                return Err(anyhow::anyhow!("Contribution #{} has a zero key", i));
+            }
+            if Bn254::pairing(c.pubkey_g1, h) != Bn254::pairing(g, c.pubkey_g2) {
+                return Err(anyhow::anyhow!("Contribution #{} has mismatched keys", i));
+            }
+
+            let ch = challenge(i, &prev, &c.pubkey_g1, &c.pok_r);
+            if g * c.pok_s != c.pok_r + c.pubkey_g1 * ch {
+                return Err(anyhow::anyhow!(
+                    "Contribution #{} has an invalid proof of knowledge",
+                    i
+                ));
+            }
+
+            if Bn254::pairing(c.tau_g1, h) != Bn254::pairing(prev, c.pubkey_g2) {
+                return Err(anyhow::anyhow!(
+                    "Contribution #{} doesn't build on the previous one",
+                    i
+                ));
+            }
+            prev = c.tau_g1;
+        }
+
+        if self.powers_of_g.len() < 2 || self.powers_of_g[0] != g {
+            return Err(anyhow::anyhow!("The first power is not the G1 generator"));
+        }
+        if self.powers_of_h.len() < 2 || self.powers_of_h[0] != h {
+            return Err(anyhow::anyhow!("The first power is not the G2 generator"));
+        }
+        if self.powers_of_g[1] != prev {
+            return Err(anyhow::anyhow!(
+                "The powers don't match the last contribution"
+            ));
+        }
+        if Bn254::pairing(self.powers_of_g[1], h) != Bn254::pairing(g, self.powers_of_h[1]) {
+            return Err(anyhow::anyhow!("[τ]_2 doesn't match [τ]_1"));
+        }
+
+        self.crs().verify()
+    }
+
+    fn crs(&self) -> Crs {
+        Crs {
+            powers_of_g: self.powers_of_g.clone(),
+            powers_of_h: self.powers_of_h.clone(),
+            lagrange: vec![],
+        }
+    }
+
+    /// The CRS the node loads, available once someone has contributed.
+    pub fn export(&self) -> Result<Crs> {
+        if self.contributions.is_empty() {
+            return Err(anyhow::anyhow!("The ceremony has no contributions yet"));
+        }
+
+        Ok(self.crs())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        self.serialize_compressed(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let mut reader = buf;
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(anyhow::anyhow!("Not a ceremony transcript"));
+        }
+
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported transcript version {}, expected {}",
+                version,
+                VERSION
+            ));
+        }
+
+        Self::deserialize_compressed(reader)
+            .map_err(|err| anyhow::anyhow!("Malformed transcript: {}", err))
+    }
+
+    fn last_tau_g1(&self) -> G1Affine {
+        self.contributions
+            .last()
+            .map(|c| c.tau_g1)
+            .unwrap_or_else(G1Affine::generator)
+    }
+}
+
+/// `x^i * points[i]`.
+fn scale<G: CurveGroup<ScalarField = Fr>>(points: &[G::Affine], x: Fr) -> Vec<G::Affine> {
+    let mut power = Fr::ONE;
+    let scaled = points
+        .iter()
+        .map(|point| {
+            let scaled = *point * power;
+            power *= x;
+            scaled
+        })
+        .collect::<Vec<_>>();
+
+    G::normalize_batch(&scaled)
+}
+
+/// Fiat-Shamir challenge of the proof of knowledge, bound to the position of
+/// the contribution and the setup it updates so it can't be replayed.
+fn challenge(index: usize, prev: &G1Affine, pubkey: &G1Affine, r: &G1Affine) -> Fr {
+    let mut buf = (index as u64).to_le_bytes().to_vec();
+    prev.serialize_compressed(&mut buf).unwrap();
+    pubkey.serialize_compressed(&mut buf).unwrap();
+    r.serialize_compressed(&mut buf).unwrap();
+
+    Fr::from_le_bytes_mod_order(&Sha256::digest(&buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A transcript with `n` contributions, written and read back.
+    fn transcript(n: usize) -> Transcript {
+        let mut transcript = Transcript::new(8, 3).unwrap();
+        for i in 0..n {
+            transcript.contribute(&[i as u8]);
+        }
+
+        Transcript::from_bytes(&transcript.to_bytes().unwrap()).unwrap()
+    }
+
+    fn error(transcript: &Transcript) -> String {
+        transcript.verify().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn contributions_are_verified() {
+        transcript(0).verify().unwrap();
+        let transcript = transcript(2);
+        transcript.verify().unwrap();
+
+        let crs = transcript.export().unwrap();
+        assert_ne!(crs.powers_of_g[1], G1Affine::generator());
+        crs.verify().unwrap();
+    }
+
+    #[test]
+    fn tampered_powers_are_rejected() {
+        let mut tampered = transcript(1);
+        tampered.powers_of_g[5] = (tampered.powers_of_g[5] + G1Affine::generator()).into_affine();
+        assert!(tampered.verify().is_err());
+
+        let mut tampered = transcript(1);
+        tampered.powers_of_h[2] = G2Affine::generator();
+        assert!(tampered.verify().is_err());
+
+        // Replacing `τ` without a contribution.
+        let mut tampered = transcript(1);
+        tampered.powers_of_g[1] = (G1Affine::generator() * Fr::from(5)).into_affine();
+        assert!(error(&tampered).contains("last contribution"));
+    }
+
+    #[test]
+    fn broken_chains_are_rejected() {
+        let mut broken = transcript(2);
+        broken.contributions[1].tau_g1 = broken.contributions[0].tau_g1;
+        assert!(error(&broken).contains("doesn't build on the previous one"));
+
+        // A contribution to another ceremony.
+        let mut broken = transcript(2);
+        broken.contributions[0] = transcript(1).contributions[0].clone();
+        assert!(broken.verify().is_err());
+
+        let mut dropped = transcript(2);
+        dropped.contributions.remove(0);
+        assert!(dropped.verify().is_err());
+    }
+
+    #[test]
+    fn forged_proofs_of_knowledge_are_rejected() {
+        let mut forged = transcript(1);
+        forged.contributions[0].pok_s += Fr::ONE;
+        assert!(error(&forged).contains("proof of knowledge"));
+
+        // A valid proof for another key.
+        let mut forged = transcript(1);
+        let other = transcript(1).contributions[0].clone();
+        forged.contributions[0].pok_r = other.pok_r;
+        forged.contributions[0].pok_s = other.pok_s;
+        assert!(error(&forged).contains("proof of knowledge"));
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut buf = transcript(1).to_bytes().unwrap();
+        buf[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(Transcript::from_bytes(&buf).is_err());
+    }
+}
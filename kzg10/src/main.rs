@@ -1,27 +1,160 @@
-use ark_bn254::Bn254;
-use ark_ec::{msm::VariableBaseMSM, PairingEngine, ProjectiveCurve};
-use ark_ff::PrimeField;
-use ark_poly::univariate::DensePolynomial;
-use ark_poly_commit::kzg10::KZG10;
-use ark_serialize::CanonicalSerialize;
-use rand::rngs::OsRng;
-
-pub const EVALUATION_DOMAIN_SIZE: usize = 1 << 10;
-pub const NUM_OF_SHARDS: usize = 1 << 4;
-
-// slice of points should be corresponding to values of polynomial we are going to commit
-pub fn simple_commit<E: PairingEngine>(poly: &[E::Fr], points: &[E::G1Affine]) -> E::G1Affine {
-    let scalars = poly.iter().map(|c| c.into_repr()).collect::<Vec<_>>();
-    VariableBaseMSM::multi_scalar_mul(&points, &scalars).into_affine()
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use kzg::evm::{g1_to_evm, g2_to_evm};
+use shamir_ss::Domain;
+
+use crate::ceremony::Transcript;
+
+mod ceremony;
+
+const EVALUATION_DOMAIN_SIZE: usize = 1 << 10;
+
+/// Powers-of-tau ceremony producing the node's KZG setup.
+///
+/// Every participant runs `contribute` on the transcript left by the previous
+/// one; the setup is secure as long as one of them discarded their secret.
+#[derive(Parser)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Start a new transcript (with a publicly known `τ = 1`).
+    Init {
+        #[arg(short, long, default_value_t = EVALUATION_DOMAIN_SIZE * 2)]
+        size: usize,
+        /// Number of G2 powers, multi-point openings of `n` points need `n + 1`.
+        #[arg(long, default_value_t = 65)]
+        g2_size: usize,
+        #[arg(short, long, default_value = "transcript.bin")]
+        out: PathBuf,
+    },
+    /// Verify the transcript and rerandomize it with a new secret.
+    Contribute {
+        #[arg(short, long, default_value = "transcript.bin")]
+        transcript: PathBuf,
+        /// Where to write the updated transcript.
+        #[arg(short, long, required_unless_present = "overwrite")]
+        out: Option<PathBuf>,
+        /// Replace the input transcript instead of writing to `--out`.
+        #[arg(long, conflicts_with = "out")]
+        overwrite: bool,
+        /// Additional entropy mixed into the OS randomness.
+        #[arg(short, long, default_value = "")]
+        entropy: String,
+    },
+    /// Verify the whole chain of contributions.
+    Verify {
+        #[arg(short, long, default_value = "transcript.bin")]
+        transcript: PathBuf,
+    },
+    /// Verify the transcript and write the CRS the node loads.
+    Export {
+        #[arg(short, long, default_value = "transcript.bin")]
+        transcript: PathBuf,
+        #[arg(short, long, default_value = "../res/crs.bin")]
+        out: PathBuf,
+        /// Include the Lagrange basis for the data points of `Domain::bit_reversed(k)`.
+        #[arg(short, long = "domain-k", default_values_t = [2])]
+        k: Vec<usize>,
+    },
+    /// Verify the transcript and write the powers `StateRegistry.initVerifier`
+    /// checks slashing evidence with, as JSON.
+    Verifier {
+        #[arg(short, long, default_value = "transcript.bin")]
+        transcript: PathBuf,
+        #[arg(short, long, default_value = "../res/verifier.json")]
+        out: PathBuf,
+        /// Largest chunk the contract has to check.
+        #[arg(short, long, default_value_t = 2)]
+        chunk_size: usize,
+    },
 }
 
-fn main() {
-    let crs =
-        KZG10::<Bn254, DensePolynomial<_>>::setup(EVALUATION_DOMAIN_SIZE * 2, true, &mut OsRng)
-            .unwrap();
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn read(path: &PathBuf) -> Result<Transcript> {
+    let transcript = Transcript::from_bytes(&std::fs::read(path)?)?;
+    transcript.verify()?;
+    println!(
+        "{}: {} powers, {} contributions, valid",
+        path.display(),
+        transcript.powers_of_g.len(),
+        transcript.contributions.len()
+    );
+
+    Ok(transcript)
+}
+
+fn main() -> Result<()> {
+    match Args::parse().command {
+        Command::Init { size, g2_size, out } => {
+            std::fs::write(out, Transcript::new(size, g2_size)?.to_bytes()?)?;
+        }
+        Command::Contribute {
+            transcript: path,
+            out,
+            overwrite: _,
+            entropy,
+        } => {
+            let mut transcript = read(&path)?;
+            transcript.contribute(entropy.as_bytes());
+            std::fs::write(out.unwrap_or(path), transcript.to_bytes()?)?;
+            println!("Contribution #{} added", transcript.contributions.len() - 1);
+        }
+        Command::Verify { transcript } => {
+            read(&transcript)?;
+        }
+        Command::Export { transcript, out, k } => {
+            let mut crs = read(&transcript)?.export()?;
+            for k in k {
+                crs.add_lagrange(&Domain::bit_reversed(k).data_points())?;
+            }
+            std::fs::write(out, crs.to_bytes()?)?;
+        }
+        Command::Verifier {
+            transcript,
+            out,
+            chunk_size,
+        } => {
+            let crs = read(&transcript)?.export()?;
+            if crs.powers_of_g.len() < chunk_size || crs.powers_of_h.len() <= chunk_size {
+                return Err(anyhow::anyhow!(
+                    "The setup is too small for chunks of {}",
+                    chunk_size
+                ));
+            }
+
+            let words = |buf: &[u8]| {
+                buf.chunks(32)
+                    .map(|word| format!("\"0x{}\"", hex(word)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            let g1 = crs.powers_of_g[..chunk_size]
+                .iter()
+                .map(|p| format!("[{}]", words(&g1_to_evm(p))))
+                .collect::<Vec<_>>();
+            let g2 = crs.powers_of_h[..=chunk_size]
+                .iter()
+                .map(|p| format!("[{}]", words(&g2_to_evm(p))))
+                .collect::<Vec<_>>();
+            std::fs::write(
+                out,
+                format!(
+                    "{{\n  \"g1Powers\": [\n    {}\n  ],\n  \"g2Powers\": [\n    {}\n  ]\n}}\n",
+                    g1.join(",\n    "),
+                    g2.join(",\n    ")
+                ),
+            )?;
+        }
+    }
 
-    // open ../res/crs.bin for writing
-    let mut f = std::fs::File::create("../res/crs.bin").unwrap();
-    // serialize crs
-    crs.serialize(&mut f).unwrap();
+    Ok(())
 }
@@ -31,16 +31,19 @@ function launchFunc() {
     echo "Enter contract address"
     read contract
 
+    echo "Enter private key of the account publishing states"
+    read -s privateKey
+
     #run master node
     currentDir=$(pwd)
-    mainNodeComand="cd $currentDir && cd ../node && cargo run --release -- -a 0.0.0.0:3000 --rpc-url http://localhost:8545 --contract '$contract'"
+    mainNodeComand="cd $currentDir && cd ../node && PRIVATE_KEY='$privateKey' cargo run --release -- -a 0.0.0.0:3000 --dir data/3000 --rpc-url http://localhost:8545 --contract '$contract'"
     osascript -e "tell app \"Terminal\"
         do script \"${mainNodeComand};\"
     end tell"
     sleep 2
     for ((i=1; i<$count; i++));
     do
-        nodeComand="cd $currentDir && cd ../node && cargo run --release -- -a 0.0.0.0:300$i --peer 127.0.0.1:3000 --rpc-url http://localhost:8545 --contract '$contract'"
+        nodeComand="cd $currentDir && cd ../node && PRIVATE_KEY='$privateKey' cargo run --release -- -a 0.0.0.0:300$i --dir data/300$i --peer 127.0.0.1:3000 --rpc-url http://localhost:8545 --contract '$contract'"
         osascript -e "tell app \"Terminal\"
             do script \"${nodeComand};\"
         end tell"
@@ -0,0 +1,399 @@
+//! Authentication of clients on the write endpoints, and the quotas of the
+//! tenants they belong to.
+//!
+//! Tenants are listed in the `--tenants` JSON file. A request authenticates
+//! as a tenant with `Authorization: Bearer <api_key>`, or by signing its body
+//! with the tenant's `address`: `X-Timestamp` and `X-Signature` carry the
+//! `timestamp` and `signature` of an `identity::Signed` message with the body
+//! as its payload. A signed body is accepted once: replays are rejected for as
+//! long as its timestamp is within `MAX_CLOCK_SKEW`.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use axum::http::{header, HeaderMap};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use web3::{
+    signing::keccak256,
+    types::{Address, Bytes},
+};
+
+use crate::{
+    error::{AppError, AppResult},
+    identity::{SeenMessages, Signed, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tenant {
+    pub name: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Requests signed with the key of this address are accepted.
+    #[serde(default)]
+    pub address: Option<Address>,
+    /// Total size of the uploaded data, 32 bytes per element.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Number of uploads.
+    #[serde(default)]
+    pub max_objects: Option<u64>,
+    /// Uploads per minute.
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+}
+
+/// What a tenant has uploaded so far.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub bytes: u64,
+    pub objects: u64,
+}
+
+/// Token bucket of `rate_limit` uploads, refilled over a minute.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct Tenants {
+    tenants: Vec<Tenant>,
+    /// Kept across restarts in `usage_path`.
+    usage: Mutex<HashMap<String, Usage>>,
+    usage_path: PathBuf,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// Accepted signed requests.
+    seen: SeenMessages,
+}
+
+impl Tenants {
+    /// Loads the tenants from `path` and their usage from `usage_path`, if it exists.
+    pub async fn load(path: &Path, usage_path: PathBuf) -> Result<Self> {
+        let tenants: Vec<Tenant> = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+        for (i, tenant) in tenants.iter().enumerate() {
+            if tenant.api_key.is_none() && tenant.address.is_none() {
+                return Err(anyhow::anyhow!(
+                    "Tenant {:?} has neither an API key nor an address",
+                    tenant.name
+                ));
+            }
+            if tenants[..i].iter().any(|other| other.name == tenant.name) {
+                return Err(anyhow::anyhow!("Duplicate tenant {:?}", tenant.name));
+            }
+        }
+
+        let usage = match tokio::fs::read(&usage_path).await {
+            Ok(buf) => serde_json::from_slice(&buf)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            tenants,
+            usage: Mutex::new(usage),
+            usage_path,
+            buckets: Mutex::new(HashMap::new()),
+            seen: SeenMessages::default(),
+        })
+    }
+
+    /// The tenant a request with `headers` and `body` comes from.
+    pub fn authenticate(&self, headers: &HeaderMap, body: &str) -> AppResult<&Tenant> {
+        if let Some(value) = headers.get(header::AUTHORIZATION) {
+            let key = value
+                .to_str()
+                .ok()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or_else(|| {
+                    AppError::Unauthorized(anyhow::anyhow!("Expected a Bearer API key"))
+                })?;
+            // Compares hashes so that the time taken doesn't depend on the key.
+            let hash = keccak256(key.as_bytes());
+            return self
+                .tenants
+                .iter()
+                .find(|tenant| {
+                    tenant
+                        .api_key
+                        .as_ref()
+                        .is_some_and(|api_key| keccak256(api_key.as_bytes()) == hash)
+                })
+                .ok_or_else(|| AppError::Unauthorized(anyhow::anyhow!("Unknown API key")));
+        }
+
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| {
+                    AppError::Unauthorized(anyhow::anyhow!(
+                        "Expected an API key or a signature ({} and {} headers)",
+                        TIMESTAMP_HEADER,
+                        SIGNATURE_HEADER
+                    ))
+                })
+        };
+        let signed = Signed {
+            payload: body.to_string(),
+            timestamp: header(TIMESTAMP_HEADER)?
+                .parse()
+                .map_err(|_| AppError::Unauthorized(anyhow::anyhow!("Invalid timestamp")))?,
+            // `Bytes` only deserializes from 0x-prefixed hex.
+            signature: serde_json::from_value::<Bytes>(header(SIGNATURE_HEADER)?.into())
+                .map_err(|_| AppError::Unauthorized(anyhow::anyhow!("Invalid signature")))?,
+        };
+        let (signer, _) = signed
+            .verify::<serde_json::Value>()
+            .map_err(AppError::Unauthorized)?;
+
+        let tenant = self
+            .tenants
+            .iter()
+            .find(|tenant| tenant.address == Some(signer))
+            .ok_or_else(|| {
+                AppError::Unauthorized(anyhow::anyhow!("Unknown signer {:?}", signer))
+            })?;
+        self.seen
+            .check(signed.timestamp, body.as_bytes())
+            .map_err(AppError::Unauthorized)?;
+
+        Ok(tenant)
+    }
+
+    /// Takes one upload from the tenant's rate limit.
+    pub async fn check_rate(&self, tenant: &Tenant) -> AppResult<()> {
+        let Some(rate_limit) = tenant.rate_limit else {
+            return Ok(());
+        };
+        let capacity = rate_limit as f64;
+        let per_second = capacity / 60.0;
+
+        let mut buckets = self.buckets.lock().await;
+        let now = Instant::now();
+        let bucket = buckets.entry(tenant.name.clone()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens =
+            (bucket.tokens + (now - bucket.updated).as_secs_f64() * per_second).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            let wait = if per_second > 0.0 {
+                Duration::from_secs_f64((1.0 - bucket.tokens) / per_second)
+            } else {
+                Duration::from_secs(60)
+            };
+            return Err(AppError::TooManyRequests {
+                inner: anyhow::anyhow!(
+                    "Tenant {:?} is limited to {} uploads per minute",
+                    tenant.name,
+                    rate_limit
+                ),
+                retry_after: wait,
+            });
+        }
+        bucket.tokens -= 1.0;
+
+        Ok(())
+    }
+
+    /// Checks that an upload of `bytes` fits into the tenant's quotas.
+    pub async fn check_quota(&self, tenant: &Tenant, bytes: u64) -> AppResult<()> {
+        let usage = self.usage(tenant).await;
+
+        if let Some(max_bytes) = tenant.max_bytes {
+            if usage.bytes + bytes > max_bytes {
+                return Err(AppError::Forbidden(anyhow::anyhow!(
+                    "Tenant {:?} has used {} of {} bytes, can't upload {} more",
+                    tenant.name,
+                    usage.bytes,
+                    max_bytes,
+                    bytes
+                )));
+            }
+        }
+        if let Some(max_objects) = tenant.max_objects {
+            if usage.objects >= max_objects {
+                return Err(AppError::Forbidden(anyhow::anyhow!(
+                    "Tenant {:?} has uploaded {} of {} objects",
+                    tenant.name,
+                    usage.objects,
+                    max_objects
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts an upload of `bytes` against the tenant's quotas.
+    pub async fn record(&self, tenant: &Tenant, bytes: u64) -> Result<()> {
+        let mut usage = self.usage.lock().await;
+        let entry = usage.entry(tenant.name.clone()).or_default();
+        entry.bytes += bytes;
+        entry.objects += 1;
+
+        tokio::fs::write(&self.usage_path, serde_json::to_vec(&*usage)?).await?;
+        Ok(())
+    }
+
+    pub async fn usage(&self, tenant: &Tenant) -> Usage {
+        self.usage
+            .lock()
+            .await
+            .get(&tenant.name)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use secp256k1::SecretKey;
+    use web3::signing::{Key, SecretKeyRef};
+
+    use super::*;
+    use crate::identity::sign_payload;
+
+    fn tenants(tenants: Vec<Tenant>) -> Tenants {
+        Tenants {
+            tenants,
+            usage: Mutex::new(HashMap::new()),
+            usage_path: PathBuf::new(),
+            buckets: Mutex::new(HashMap::new()),
+            seen: SeenMessages::default(),
+        }
+    }
+
+    fn tenant(name: &str, api_key: Option<&str>, address: Option<Address>) -> Tenant {
+        Tenant {
+            name: name.to_string(),
+            api_key: api_key.map(str::to_string),
+            address,
+            max_bytes: None,
+            max_objects: None,
+            rate_limit: None,
+        }
+    }
+
+    fn signed_headers(key: &SecretKey, body: &str) -> HeaderMap {
+        let (timestamp, signature) = sign_payload(key, body.as_bytes()).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+        let signature = serde_json::to_value(signature).unwrap();
+        headers.insert(
+            SIGNATURE_HEADER,
+            HeaderValue::from_str(signature.as_str().unwrap()).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn signed_request_is_accepted_once() {
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let address = SecretKeyRef::new(&key).address();
+        let tenants = tenants(vec![tenant("alice", None, Some(address))]);
+
+        let headers = signed_headers(&key, "[\"1\"]");
+        assert_eq!(
+            tenants.authenticate(&headers, "[\"1\"]").unwrap().name,
+            "alice"
+        );
+        assert!(matches!(
+            tenants.authenticate(&headers, "[\"1\"]"),
+            Err(AppError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn api_key_names_its_tenant() {
+        let tenants = tenants(vec![
+            tenant("alice", Some("secret-a"), None),
+            tenant("bob", Some("secret-b"), None),
+        ]);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret-b"),
+        );
+        assert_eq!(tenants.authenticate(&headers, "").unwrap().name, "bob");
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret-c"),
+        );
+        assert!(tenants.authenticate(&headers, "").is_err());
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("secret-b"));
+        assert!(tenants.authenticate(&headers, "").is_err());
+    }
+
+    #[test]
+    fn requests_without_credentials_are_rejected() {
+        let tenants = tenants(vec![tenant("alice", Some("secret"), None)]);
+        assert!(matches!(
+            tenants.authenticate(&HeaderMap::new(), ""),
+            Err(AppError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn signature_of_another_body_or_signer_is_rejected() {
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let other = SecretKey::from_slice(&[2; 32]).unwrap();
+        let tenants = tenants(vec![tenant(
+            "alice",
+            None,
+            Some(SecretKeyRef::new(&key).address()),
+        )]);
+
+        assert!(tenants
+            .authenticate(&signed_headers(&key, "[\"1\"]"), "[\"2\"]")
+            .is_err());
+        assert!(tenants
+            .authenticate(&signed_headers(&other, "[\"1\"]"), "[\"1\"]")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn quotas_count_recorded_uploads() {
+        let usage_path =
+            std::env::temp_dir().join(format!("auth-test-usage-{}.json", std::process::id()));
+        let mut alice = tenant("alice", Some("secret"), None);
+        alice.max_bytes = Some(100);
+        alice.max_objects = Some(2);
+        let mut tenants = tenants(vec![alice.clone()]);
+        tenants.usage_path = usage_path.clone();
+
+        tenants.check_quota(&alice, 60).await.unwrap();
+        tenants.record(&alice, 60).await.unwrap();
+        assert!(tenants.check_quota(&alice, 60).await.is_err());
+        tenants.check_quota(&alice, 40).await.unwrap();
+        tenants.record(&alice, 40).await.unwrap();
+        assert!(tenants.check_quota(&alice, 0).await.is_err());
+
+        let saved: HashMap<String, Usage> =
+            serde_json::from_slice(&tokio::fs::read(&usage_path).await.unwrap()).unwrap();
+        assert_eq!((saved["alice"].bytes, saved["alice"].objects), (100, 2));
+        tokio::fs::remove_file(usage_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rate_limit_is_enforced() {
+        let mut alice = tenant("alice", Some("secret"), None);
+        alice.rate_limit = Some(2);
+        let tenants = tenants(vec![alice.clone()]);
+
+        tenants.check_rate(&alice).await.unwrap();
+        tenants.check_rate(&alice).await.unwrap();
+        assert!(matches!(
+            tenants.check_rate(&alice).await,
+            Err(AppError::TooManyRequests { .. })
+        ));
+    }
+}
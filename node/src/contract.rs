@@ -1,64 +1,484 @@
-use std::str::FromStr;
-
 use anyhow::Result;
-use ark_bn254::Fr;
-use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_bn254::{Fr, G1Affine};
+use kzg::{
+    evm::{g1_from_evm, g1_to_evm},
+    Crs,
+};
 use secp256k1::SecretKey;
+use shamir_ss::Domain;
 use web3::{
     api::{Eth, Namespace},
-    contract::Contract,
+    contract::{tokens::Tokenize, Contract},
+    ethabi::{RawLog, Token},
     transports::Http,
-    types::{Address, H256, U256},
-    Web3,
+    types::{Address, BlockNumber, FilterBuilder, TransactionReceipt, H256, U256},
+};
+
+use crate::{
+    evidence::{Evidence, Registry},
+    signer::Signer,
+    storage::{Chunk, StateId},
 };
 
 const CONTRACT_ABI: &[u8] = include_bytes!("StateRegistry.json");
 
 pub struct RegistryContract {
-    web3: Web3<Http>,
+    eth: Eth<Http>,
     contract: Contract<Http>,
+    signer: Signer,
+    /// The largest `k` of the states read from the registry, see `StateRecord::decode`.
+    max_k: u32,
 }
 
 impl RegistryContract {
-    pub fn new(rpc_url: &str, address: &str) -> Result<Self> {
+    pub fn new(
+        rpc_url: &str,
+        address: &str,
+        key: SecretKey,
+        confirmations: u64,
+        max_k: u32,
+    ) -> Result<Self> {
         let transport = Http::new(rpc_url)?;
-        let web3 = Web3::new(transport.clone());
-        let contract = Contract::from_json(Eth::new(transport), address.parse()?, CONTRACT_ABI)?;
+        let eth = Eth::new(transport.clone());
+        let contract = Contract::from_json(eth.clone(), address.parse()?, CONTRACT_ABI)?;
+        let signer = Signer::new(transport, key, confirmations);
+
+        Ok(Self {
+            eth,
+            contract,
+            signer,
+            max_k,
+        })
+    }
+
+    /// The account that signs the pushed states, i.e. the `msg.sender` they are stored under.
+    pub fn account(&self) -> Address {
+        self.signer.address()
+    }
+
+    /// Appends a new state for the signing account and waits for it to be confirmed.
+    pub async fn push_state(&self, record: &StateRecord) -> Result<H256> {
+        let receipt = self
+            .send("pushState", (record.encode(),), U256::zero())
+            .await?;
+
+        if receipt.status != Some(1.into()) {
+            return Err(anyhow::anyhow!(
+                "pushState transaction {:?} reverted",
+                receipt.transaction_hash
+            ));
+        }
+
+        Ok(receipt.transaction_hash)
+    }
+
+    /// Registers the signing account as a storage node reachable at `endpoint`,
+    /// adding `stake` wei to its stake.
+    pub async fn register_node(&self, endpoint: &str, stake: U256) -> Result<H256> {
+        let receipt = self
+            .send("registerNode", (endpoint.to_string(),), stake)
+            .await?;
+
+        if receipt.status != Some(1.into()) {
+            return Err(anyhow::anyhow!(
+                "registerNode transaction {:?} reverted",
+                receipt.transaction_hash
+            ));
+        }
+
+        Ok(receipt.transaction_hash)
+    }
+
+    /// Commits to evidence before submitting it with `slash`, see `Evidence::commitment`.
+    pub async fn commit_evidence(&self, commitment: H256) -> Result<H256> {
+        let receipt = self
+            .send("commitEvidence", (commitment,), U256::zero())
+            .await?;
+
+        if receipt.status != Some(1.into()) {
+            return Err(anyhow::anyhow!(
+                "commitEvidence transaction {:?} reverted",
+                receipt.transaction_hash
+            ));
+        }
+
+        Ok(receipt.transaction_hash)
+    }
+
+    /// Submits evidence against a node, see `StateRegistry.slash`. It must
+    /// have been committed to in an earlier block.
+    pub async fn slash(&self, evidence: &Evidence) -> Result<H256> {
+        let receipt = self
+            .send("slash", (evidence.to_token(),), U256::zero())
+            .await?;
+
+        if receipt.status != Some(1.into()) {
+            return Err(anyhow::anyhow!(
+                "slash transaction {:?} reverted",
+                receipt.transaction_hash
+            ));
+        }
+
+        Ok(receipt.transaction_hash)
+    }
+
+    async fn send(
+        &self,
+        func: &str,
+        params: impl Tokenize,
+        value: U256,
+    ) -> Result<TransactionReceipt> {
+        let data = self
+            .contract
+            .abi()
+            .function(func)?
+            .encode_input(&params.into_tokens())?;
 
-        Ok(Self { web3, contract })
+        self.signer.send(self.contract.address(), data, value).await
     }
 
-    pub async fn push_state(&self, address: Address, commit: Fr) -> Result<H256> {
-        let mut buf = vec![];
-        commit.serialize_compressed(&mut buf).unwrap();
+    /// The deployment served chunks are signed for, see `evidence::digest`.
+    pub async fn registry(&self) -> Result<Registry> {
+        Ok(Registry {
+            chain_id: self.eth.chain_id().await?,
+            address: self.contract.address(),
+        })
+    }
 
-        let accounts = self.web3.eth().accounts().await?;
-        let hash = self
+    /// `StateRegistry.verifierHash` and the number of G1 and G2 powers it covers,
+    /// zero if the verifier isn't set.
+    pub async fn verifier(&self) -> Result<(H256, u64, u64)> {
+        let (hash, g1_len, g2_len): (H256, U256, U256) = self
             .contract
-            .call("pushState", (address, buf), accounts[0], Default::default())
+            .query("getVerifier", (), None, Default::default(), None)
             .await?;
 
-        Ok(hash)
+        Ok((hash, g1_len.as_u64(), g2_len.as_u64()))
     }
 
-    pub async fn get_state(&self, address: Address) -> Result<Fr> {
-        let state: Vec<u8> = self
+    pub async fn is_active_node(&self, address: Address) -> Result<bool> {
+        Ok(self
+            .contract
+            .query("isActiveNode", address, None, Default::default(), None)
+            .await?)
+    }
+
+    /// Registered nodes with enough stake and their endpoints.
+    pub async fn active_nodes(&self) -> Result<Vec<(Address, String)>> {
+        let (addresses, endpoints): (Vec<Address>, Vec<String>) = self
             .contract
-            .query("state", (address), None, Default::default(), None)
+            .query("getActiveNodes", (), None, Default::default(), None)
             .await?;
 
-        let commit = Fr::deserialize_compressed(&mut &state[..])
-            .map_err(|_| anyhow::anyhow!("Deserialization error"))?;
+        Ok(addresses.into_iter().zip(endpoints).collect())
+    }
+
+    pub async fn get_state(&self, address: Address, height: u64) -> Result<StateRecord> {
+        let state: Vec<u8> = self
+            .contract
+            .query(
+                "state",
+                (address, U256::from(height)),
+                None,
+                Default::default(),
+                None,
+            )
+            .await?;
 
-        Ok(commit)
+        StateRecord::decode(&state, self.max_k)
     }
 
     pub async fn get_state_height(&self, address: Address) -> Result<u64> {
         let state_height: U256 = self
             .contract
-            .query("getStateHeight", (address), None, Default::default(), None)
+            .query("getStateHeight", address, None, Default::default(), None)
             .await?;
 
         Ok(state_height.as_u64())
     }
+
+    pub async fn block_number(&self) -> Result<u64> {
+        Ok(self.eth.block_number().await?.as_u64())
+    }
+
+    /// States pushed within the `from..=to` block range, in the order they were pushed.
+    ///
+    /// Anyone can push a state, so records that can't be decoded are skipped.
+    pub async fn pushed_states(&self, from: u64, to: u64) -> Result<Vec<(StateId, StateRecord)>> {
+        let event = self.contract.abi().event("StatePushed")?;
+        let filter = FilterBuilder::default()
+            .address(vec![self.contract.address()])
+            .topics(Some(vec![event.signature()]), None, None, None)
+            .from_block(BlockNumber::Number(from.into()))
+            .to_block(BlockNumber::Number(to.into()))
+            .build();
+
+        let logs = self.eth.logs(filter).await?;
+        let mut states = Vec::with_capacity(logs.len());
+        for log in logs {
+            let log = event.parse_log(RawLog {
+                topics: log.topics,
+                data: log.data.0,
+            })?;
+
+            match &log.params[..] {
+                [uploader, height, state] => match (&uploader.value, &height.value, &state.value) {
+                    (Token::Address(uploader), Token::Uint(height), Token::Bytes(state)) => {
+                        let id = StateId {
+                            uploader: *uploader,
+                            height: height.as_u64(),
+                        };
+                        match StateRecord::decode(state, self.max_k) {
+                            Ok(record) => states.push((id, record)),
+                            Err(err) => tracing::warn!(
+                                "Skipping state #{} of {:?}: {}",
+                                id.height,
+                                id.uploader,
+                                err
+                            ),
+                        }
+                    }
+                    _ => return Err(anyhow::anyhow!("Unexpected StatePushed params: {:?}", log)),
+                },
+                _ => return Err(anyhow::anyhow!("Unexpected StatePushed params: {:?}", log)),
+            }
+        }
+
+        Ok(states)
+    }
+}
+
+/// A single entry of `StateRegistry.state`: the KZG commitment of an object and
+/// the parameters it has been encoded with.
+///
+/// Encoded as 80 bytes: the commitment in the form accepted by the `ecAdd`,
+/// `ecMul` and `ecPairing` precompiles (big-endian `x` and `y`, 32 bytes each,
+/// with the point at infinity as `(0, 0)`), followed by big-endian `size` (8 bytes),
+/// `k` (4 bytes) and `chunk_size` (4 bytes).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StateRecord {
+    pub commitment: G1Affine,
+    /// Number of field elements in the original data.
+    pub size: u64,
+    /// Parameter of the `Domain` the data has been encoded with.
+    pub k: u32,
+    /// Number of encoded elements per chunk.
+    pub chunk_size: u32,
+}
+
+/// Checks that `chunk` has the shape of a chunk of data encoded with
+/// `Domain::bit_reversed(k)` and split into chunks of `chunk_size`, for chunks
+/// of states whose record isn't known yet.
+pub fn check_chunk_shape(k: u32, chunk_size: u32, chunk: &Chunk) -> Result<()> {
+    let encoded_len = 2u64 << k;
+    let start = chunk.chunk as u64 * chunk_size as u64;
+    if start >= encoded_len {
+        return Err(anyhow::anyhow!(
+            "Chunk {} is out of range, state has {} chunks",
+            chunk.chunk,
+            encoded_len.div_ceil(chunk_size as u64)
+        ));
+    }
+
+    let expected = (encoded_len - start).min(chunk_size as u64);
+    if chunk.data.len() as u64 != expected {
+        return Err(anyhow::anyhow!(
+            "Chunk {} has {} elements, expected {}",
+            chunk.chunk,
+            chunk.data.len(),
+            expected
+        ));
+    }
+
+    Ok(())
+}
+
+impl StateRecord {
+    pub const ENCODED_SIZE: usize = 80;
+
+    /// Number of chunks the encoded data is split into.
+    pub fn num_chunks(&self) -> u64 {
+        (2u64 << self.k).div_ceil(self.chunk_size as u64)
+    }
+
+    /// Checks that `chunk` has the shape this state prescribes.
+    pub fn check_chunk(&self, chunk: &Chunk) -> Result<()> {
+        check_chunk_shape(self.k, self.chunk_size, chunk)
+    }
+
+    /// The first `len` points of chunk `chunk`.
+    pub fn chunk_points(&self, chunk: u32, len: usize) -> Vec<Fr> {
+        let start = chunk as usize * self.chunk_size as usize;
+        Domain::bit_reversed(self.k as usize).degrees[start..][..len].to_vec()
+    }
+
+    /// Checks the shape of `chunk` and its proof against the commitment.
+    pub fn verify_chunk(&self, crs: &Crs, chunk: &Chunk) -> Result<()> {
+        self.check_chunk(chunk)?;
+
+        let points = self.chunk_points(chunk.chunk, chunk.data.len());
+        if !kzg::verify_multi(crs, &self.commitment, &points, &chunk.data, &chunk.proof)? {
+            return Err(anyhow::anyhow!(
+                "Chunk {} doesn't match the commitment",
+                chunk.chunk
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::ENCODED_SIZE);
+        buf.extend_from_slice(&g1_to_evm(&self.commitment));
+        buf.extend_from_slice(&self.size.to_be_bytes());
+        buf.extend_from_slice(&self.k.to_be_bytes());
+        buf.extend_from_slice(&self.chunk_size.to_be_bytes());
+        buf
+    }
+
+    /// Parses a record pushed by anyone, rejecting parameters this node can't
+    /// handle: `k` over `max_k` (see `max_k`), or a `chunk_size` that isn't a
+    /// power of two of at most `2^(k+1)`, the size of the encoded data.
+    pub fn decode(buf: &[u8], max_k: u32) -> Result<Self> {
+        if buf.len() != Self::ENCODED_SIZE {
+            return Err(anyhow::anyhow!(
+                "Invalid state record length: expected {}, got {}",
+                Self::ENCODED_SIZE,
+                buf.len()
+            ));
+        }
+
+        let record = Self {
+            commitment: g1_from_evm(&buf[..64])?,
+            size: u64::from_be_bytes(buf[64..72].try_into().unwrap()),
+            k: u32::from_be_bytes(buf[72..76].try_into().unwrap()),
+            chunk_size: u32::from_be_bytes(buf[76..80].try_into().unwrap()),
+        };
+        if record.k > max_k {
+            return Err(anyhow::anyhow!(
+                "State record has k = {}, at most {} is supported",
+                record.k,
+                max_k
+            ));
+        }
+        if !record.chunk_size.is_power_of_two() || record.chunk_size as u64 > 2u64 << record.k {
+            return Err(anyhow::anyhow!(
+                "State record has chunk size {}, expected a power of two up to {}",
+                record.chunk_size,
+                2u64 << record.k
+            ));
+        }
+
+        Ok(record)
+    }
+}
+
+/// The largest `k` of the states the node can check with `crs`: the data
+/// polynomial must fit into it and `Domain::bit_reversed(k)` must exist.
+pub fn max_k(crs: &Crs) -> u32 {
+    crs.max_k().min(Domain::MAX_K as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use ark_ec::AffineRepr;
+
+    use super::*;
+
+    fn record(k: u32, chunk_size: u32) -> StateRecord {
+        StateRecord {
+            commitment: G1Affine::generator(),
+            size: 3,
+            k,
+            chunk_size,
+        }
+    }
+
+    #[test]
+    fn record_round_trips() {
+        let record = record(2, 2);
+        let buf = record.encode();
+        assert_eq!(buf.len(), StateRecord::ENCODED_SIZE);
+        assert_eq!(StateRecord::decode(&buf, 2).unwrap(), record);
+    }
+
+    #[test]
+    fn record_of_wrong_length_is_rejected() {
+        let buf = record(2, 2).encode();
+        assert!(StateRecord::decode(&buf[..79], 2).is_err());
+        assert!(StateRecord::decode(&[buf, vec![0]].concat(), 2).is_err());
+    }
+
+    #[test]
+    fn record_with_unsupported_k_is_rejected() {
+        assert!(StateRecord::decode(&record(3, 2).encode(), 2).is_err());
+        assert!(StateRecord::decode(&record(40, 2).encode(), 2).is_err());
+        assert!(StateRecord::decode(&record(u32::MAX, 2).encode(), 2).is_err());
+    }
+
+    #[test]
+    fn record_with_invalid_chunk_size_is_rejected() {
+        for chunk_size in [0, 3, 16, u32::MAX] {
+            assert!(
+                StateRecord::decode(&record(2, chunk_size).encode(), 2).is_err(),
+                "chunk size {}",
+                chunk_size
+            );
+        }
+        assert!(StateRecord::decode(&record(2, 8).encode(), 2).is_ok());
+    }
+
+    #[test]
+    fn record_with_invalid_commitment_is_rejected() {
+        let mut buf = record(2, 2).encode();
+        buf[63] ^= 1;
+        assert!(StateRecord::decode(&buf, 2).is_err());
+    }
+
+    /// Needs a chain with `StateRegistry` deployed, e.g. `anvil` (or `npx hardhat node`) and
+    /// `npx hardhat run --network localhost scripts/deploy.js`, then
+    /// `REGISTRY=0x.. PRIVATE_KEY=<key of a funded account> cargo test -- --ignored`.
+    /// `RPC_URL` is `http://localhost:8545` by default.
+    #[tokio::test]
+    #[ignore]
+    async fn pushed_states_are_read_back_from_the_chain() {
+        use ark_ec::CurveGroup;
+
+        let var = |name| std::env::var(name).unwrap_or_else(|_| panic!("{} isn't set", name));
+        let contract = RegistryContract::new(
+            &std::env::var("RPC_URL").unwrap_or_else(|_| "http://localhost:8545".to_string()),
+            &var("REGISTRY"),
+            var("PRIVATE_KEY").trim_start_matches("0x").parse().unwrap(),
+            1,
+            2,
+        )
+        .unwrap();
+        let account = contract.account();
+        let height = contract.get_state_height(account).await.unwrap();
+        let record = StateRecord {
+            commitment: (G1Affine::generator() * Fr::from(height + 2)).into_affine(),
+            ..record(2, 2)
+        };
+        assert_eq!(record.encode().len(), 80);
+
+        contract.push_state(&record).await.unwrap();
+
+        assert_eq!(
+            contract.get_state_height(account).await.unwrap(),
+            height + 1
+        );
+        assert_eq!(contract.get_state(account, height).await.unwrap(), record);
+        let block = contract.block_number().await.unwrap();
+        let pushed = contract
+            .pushed_states(block.saturating_sub(5), block)
+            .await
+            .unwrap();
+        assert!(pushed.contains(&(
+            StateId {
+                uploader: account,
+                height
+            },
+            record
+        )));
+    }
 }
@@ -1,33 +1,193 @@
+use std::{fmt, time::Duration};
+
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 
 pub type AppResult<T> = Result<T, AppError>;
 
-pub struct AppError(anyhow::Error);
+/// Errors of the API, each answered with its own status and a stable `code`:
+/// `{"error": "<message>", "code": "<code>"}`.
+#[derive(Debug)]
+pub enum AppError {
+    /// Malformed request, e.g. an element that isn't a field element.
+    Validation(anyhow::Error),
+    /// Missing or invalid credentials, or a message signed by someone else.
+    Unauthorized(anyhow::Error),
+    /// Valid credentials that don't allow the request, e.g. over quota.
+    Forbidden(anyhow::Error),
+    /// Unknown state, or nothing stored for it.
+    NotFound(anyhow::Error),
+    TooManyRequests {
+        inner: anyhow::Error,
+        /// Sent as `Retry-After`.
+        retry_after: Duration,
+    },
+    /// Too few staked peers to store the chunks of an upload.
+    InsufficientPeers(anyhow::Error),
+    /// Data can't be decoded or repaired from the chunks that are available.
+    Decode(anyhow::Error),
+    /// A chunk doesn't match the commitment of its state, or a node isn't who it claims to be.
+    Verification(anyhow::Error),
+    /// A peer failed or rejected a request made on behalf of the client.
+    UpstreamPeer(anyhow::Error),
+    Internal(anyhow::Error),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::InsufficientPeers(_) | AppError::Decode(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Verification(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::UpstreamPeer(_) => StatusCode::BAD_GATEWAY,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine-readable code of the error, part of the API: don't change them.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Validation(_) => "invalid_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::TooManyRequests { .. } => "rate_limited",
+            AppError::InsufficientPeers(_) => "insufficient_peers",
+            AppError::Decode(_) => "decode_failed",
+            AppError::Verification(_) => "verification_failed",
+            AppError::UpstreamPeer(_) => "upstream_peer_error",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    fn inner(&self) -> &anyhow::Error {
+        match self {
+            AppError::Validation(inner)
+            | AppError::Unauthorized(inner)
+            | AppError::Forbidden(inner)
+            | AppError::NotFound(inner)
+            | AppError::TooManyRequests { inner, .. }
+            | AppError::InsufficientPeers(inner)
+            | AppError::Decode(inner)
+            | AppError::Verification(inner)
+            | AppError::UpstreamPeer(inner)
+            | AppError::Internal(inner) => inner,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.inner())
+    }
+}
 
 impl From<anyhow::Error> for AppError {
     fn from(inner: anyhow::Error) -> Self {
-        AppError(inner)
+        AppError::Internal(inner)
     }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        tracing::error!("error: {}", self.0);
-
-        let error = format!("{:?}", self.0);
-        let (status, error_message) = {
-            tracing::debug!("stacktrace: {}", self.0.backtrace());
-            (StatusCode::INTERNAL_SERVER_ERROR, "something went wrong")
+        let status = self.status();
+        let inner = self.inner();
+        let error = if let AppError::Internal(_) = self {
+            // The details stay in the log, they may name files, peers or RPC endpoints.
+            tracing::error!("Internal error: {:?}", inner);
+            "Internal error".to_string()
+        } else {
+            // Other errors are expected, no need for a backtrace.
+            tracing::warn!("{} ({}): {:#}", status, self.code(), inner);
+            format!("{:#}", inner)
         };
 
         let body = Json(serde_json::json!({
             "error": error,
+            "code": self.code(),
         }));
 
-        (status, body).into_response()
+        let mut res = (status, body).into_response();
+        if let AppError::TooManyRequests { retry_after, .. } = self {
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.as_secs().max(1).into());
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::HttpBody;
+
+    use super::*;
+
+    async fn body(res: Response) -> serde_json::Value {
+        let buf = res.into_body().data().await.unwrap().unwrap();
+        serde_json::from_slice(&buf).unwrap()
+    }
+
+    #[tokio::test]
+    async fn errors_are_answered_with_their_status_and_code() {
+        let err = || anyhow::anyhow!("state #3 is not known");
+        let cases = [
+            (AppError::Validation(err()), 400, "invalid_request"),
+            (AppError::Unauthorized(err()), 401, "unauthorized"),
+            (AppError::Forbidden(err()), 403, "forbidden"),
+            (AppError::NotFound(err()), 404, "not_found"),
+            (AppError::Verification(err()), 422, "verification_failed"),
+            (
+                AppError::TooManyRequests {
+                    inner: err(),
+                    retry_after: Duration::from_secs(3),
+                },
+                429,
+                "rate_limited",
+            ),
+            (AppError::UpstreamPeer(err()), 502, "upstream_peer_error"),
+            (
+                AppError::InsufficientPeers(err()),
+                503,
+                "insufficient_peers",
+            ),
+            (AppError::Decode(err()), 503, "decode_failed"),
+        ];
+
+        for (error, status, code) in cases {
+            let res = error.into_response();
+            assert_eq!(res.status().as_u16(), status, "{}", code);
+            assert_eq!(
+                body(res).await,
+                serde_json::json!({ "error": "state #3 is not known", "code": code })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limited_errors_say_when_to_retry() {
+        let res = AppError::TooManyRequests {
+            inner: anyhow::anyhow!("Slow down"),
+            retry_after: Duration::from_millis(10),
+        }
+        .into_response();
+        assert_eq!(res.headers()[header::RETRY_AFTER], "1");
+    }
+
+    #[tokio::test]
+    async fn internal_errors_are_not_detailed() {
+        let err = anyhow::anyhow!("Connection refused").context("Failed to reach http://10.0.0.5");
+        let res = AppError::from(err).into_response();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            body(res).await,
+            serde_json::json!({ "error": "Internal error", "code": "internal" })
+        );
     }
 }
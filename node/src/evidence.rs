@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use ark_bn254::Fr;
+use ark_ff::{BigInteger, PrimeField};
+use kzg::{
+    evm::{g1_to_evm, g2_to_evm},
+    Crs,
+};
+use secp256k1::SecretKey;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use web3::{
+    ethabi::{self, Token},
+    signing::keccak256,
+    types::{Address, Bytes, H256, U256},
+};
+
+use crate::{
+    contract::StateRecord,
+    identity,
+    storage::{Chunk, ChunkSerde},
+};
+
+/// A chunk served by a node with the node's signature over `digest`.
+///
+/// The signature makes the node accountable for the chunk: one that doesn't
+/// match the commitment of its state gets the node slashed, see `Evidence`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AttestedChunk {
+    pub chunk: ChunkSerde,
+    pub signature: Bytes,
+}
+
+impl AttestedChunk {
+    pub fn new(key: &SecretKey, registry: &Registry, chunk: Chunk) -> Result<Self> {
+        Ok(Self {
+            signature: identity::sign(key, &digest(registry, &chunk))?,
+            chunk: chunk.into(),
+        })
+    }
+}
+
+/// The `StateRegistry` deployment chunks are signed for, so that a chunk
+/// served for one can't be used as evidence in another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Registry {
+    pub chain_id: U256,
+    pub address: Address,
+}
+
+/// What a node signs when serving a chunk, `StateRegistry.chunkDigest`:
+/// `keccak256("\x19Sharded Storage chunk:\n" || chain id (32 bytes) || registry || uploader
+/// || height (8 bytes) || chunk (4 bytes) || data (32 bytes per element) || proof)`, integers
+/// big-endian.
+pub fn digest(registry: &Registry, chunk: &Chunk) -> [u8; 32] {
+    let mut buf = b"\x19Sharded Storage chunk:\n".to_vec();
+    let mut chain_id = [0; 32];
+    registry.chain_id.to_big_endian(&mut chain_id);
+    buf.extend_from_slice(&chain_id);
+    buf.extend_from_slice(registry.address.as_bytes());
+    buf.extend_from_slice(chunk.state.uploader.as_bytes());
+    buf.extend_from_slice(&chunk.state.height.to_be_bytes());
+    buf.extend_from_slice(&chunk.chunk.to_be_bytes());
+    for x in &chunk.data {
+        buf.extend_from_slice(&x.into_bigint().to_bytes_be());
+    }
+    buf.extend_from_slice(&g1_to_evm(&chunk.proof));
+    keccak256(&buf)
+}
+
+/// Self-contained proof that a node has served a chunk inconsistent with the
+/// commitment of its state, checked on chain by `StateRegistry.slash`.
+#[derive(Clone, Debug)]
+pub struct Evidence {
+    pub node: Address,
+    pub chunk: Chunk,
+    /// Coefficients of the polynomial taking the values of the chunk at its
+    /// points, so that the contract doesn't have to interpolate.
+    pub interpolation: Vec<Fr>,
+    pub signature: Bytes,
+}
+
+impl Evidence {
+    /// Fails unless the chunk is signed, has the shape `record` prescribes
+    /// and doesn't match the commitment.
+    pub fn new(
+        crs: &Crs,
+        registry: &Registry,
+        record: &StateRecord,
+        chunk: Chunk,
+        signature: Bytes,
+    ) -> Result<Self> {
+        let node = identity::recover(&digest(registry, &chunk), &signature)?;
+        record.check_chunk(&chunk)?;
+        // The contract only checks chunks that are cosets of the domain.
+        if !(record.chunk_size as u64)
+            .min(2 << record.k)
+            .is_power_of_two()
+        {
+            return Err(anyhow::anyhow!(
+                "Chunks of {} elements can't be checked on chain",
+                record.chunk_size
+            ));
+        }
+        if record.verify_chunk(crs, &chunk).is_ok() {
+            return Err(anyhow::anyhow!(
+                "Chunk {} matches the commitment",
+                chunk.chunk
+            ));
+        }
+
+        let points = record.chunk_points(chunk.chunk, chunk.data.len());
+        Ok(Self {
+            node,
+            interpolation: kzg::interpolate(&points, &chunk.data)?,
+            chunk,
+            signature,
+        })
+    }
+
+    /// What `reporter` commits to with `StateRegistry.commitEvidence` before
+    /// submitting the evidence, `StateRegistry.evidenceCommitment`:
+    /// `keccak256(abi.encode(reporter, node, chunkDigest))`.
+    pub fn commitment(&self, registry: &Registry, reporter: Address) -> H256 {
+        let digest = digest(registry, &self.chunk);
+        H256(keccak256(&ethabi::encode(&[
+            Token::Address(reporter),
+            Token::Address(self.node),
+            Token::FixedBytes(digest.to_vec()),
+        ])))
+    }
+
+    /// The `StateRegistry.Evidence` struct.
+    pub fn to_token(&self) -> Token {
+        let uint = |x: &Fr| Token::Uint(U256::from_big_endian(&x.into_bigint().to_bytes_be()));
+        let proof = g1_to_evm(&self.chunk.proof);
+
+        Token::Tuple(vec![
+            Token::Address(self.node),
+            Token::Address(self.chunk.state.uploader),
+            Token::Uint(self.chunk.state.height.into()),
+            Token::Uint(self.chunk.chunk.into()),
+            Token::Array(self.chunk.data.iter().map(uint).collect()),
+            Token::FixedArray(
+                proof
+                    .chunks(32)
+                    .map(|word| Token::Uint(U256::from_big_endian(word)))
+                    .collect(),
+            ),
+            Token::Array(self.interpolation.iter().map(uint).collect()),
+            Token::Bytes(self.signature.0.clone()),
+        ])
+    }
+}
+
+/// `StateRegistry.verifierHash` of the first `g1_len` G1 and `g2_len` G2 powers
+/// of `crs`, `None` if it doesn't have that many:
+/// `keccak256(abi.encode(g1Powers, g2Powers))`.
+pub fn verifier_hash(crs: &Crs, g1_len: usize, g2_len: usize) -> Option<H256> {
+    let words = |buf: &[u8]| {
+        Token::FixedArray(
+            buf.chunks(32)
+                .map(|word| Token::Uint(U256::from_big_endian(word)))
+                .collect(),
+        )
+    };
+    let g1 = crs.powers_of_g.get(..g1_len)?;
+    let g2 = crs.powers_of_h.get(..g2_len)?;
+
+    Some(H256(keccak256(&ethabi::encode(&[
+        Token::Array(g1.iter().map(|p| words(&g1_to_evm(p))).collect()),
+        Token::Array(g2.iter().map(|p| words(&g2_to_evm(p))).collect()),
+    ]))))
+}
+
+/// Evidence waiting to be submitted, at most one per node.
+#[derive(Default)]
+pub struct EvidenceQueue {
+    pending: Mutex<HashMap<Address, Evidence>>,
+}
+
+impl EvidenceQueue {
+    pub async fn push(&self, evidence: Evidence) {
+        self.pending
+            .lock()
+            .await
+            .entry(evidence.node)
+            .or_insert(evidence);
+    }
+
+    pub async fn take(&self) -> Vec<Evidence> {
+        self.pending.lock().await.drain().map(|(_, e)| e).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ark_bn254::G1Affine;
+    use ark_ec::AffineRepr;
+    use web3::signing::{Key, SecretKeyRef};
+
+    use super::*;
+    use crate::storage::StateId;
+
+    fn chunk() -> Chunk {
+        Chunk {
+            state: StateId {
+                uploader: Address::repeat_byte(1),
+                height: 3,
+            },
+            chunk: 1,
+            data: vec![Fr::from(2u64), Fr::from(5u64)],
+            proof: G1Affine::generator(),
+        }
+    }
+
+    #[test]
+    fn digest_names_the_deployment() {
+        let registry = Registry {
+            chain_id: 1.into(),
+            address: Address::repeat_byte(2),
+        };
+        let other_chain = Registry {
+            chain_id: 5.into(),
+            ..registry
+        };
+        let other_contract = Registry {
+            address: Address::repeat_byte(3),
+            ..registry
+        };
+
+        assert_eq!(digest(&registry, &chunk()), digest(&registry, &chunk()));
+        assert_ne!(digest(&registry, &chunk()), digest(&other_chain, &chunk()));
+        assert_ne!(
+            digest(&registry, &chunk()),
+            digest(&other_contract, &chunk())
+        );
+    }
+
+    #[test]
+    fn signed_chunk_recovers_to_the_signer() {
+        let registry = Registry {
+            chain_id: 1.into(),
+            address: Address::repeat_byte(2),
+        };
+        let key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let attested = AttestedChunk::new(&key, &registry, chunk()).unwrap();
+
+        let signer = identity::recover(&digest(&registry, &chunk()), &attested.signature).unwrap();
+        assert_eq!(signer, SecretKeyRef::new(&key).address());
+        let other = Registry {
+            chain_id: 5.into(),
+            ..registry
+        };
+        let signer = identity::recover(&digest(&other, &chunk()), &attested.signature).unwrap();
+        assert_ne!(signer, SecretKeyRef::new(&key).address());
+    }
+}
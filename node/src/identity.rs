@@ -0,0 +1,300 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use secp256k1::SecretKey;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use web3::{
+    signing::{keccak256, Key, SecretKeyRef},
+    types::{Address, Bytes, H256},
+};
+
+use crate::tls::PeerClient;
+
+/// How far the timestamp of a signed message may be from the local clock.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Headers carrying the `timestamp` and `signature` of a request whose body
+/// is the signed payload, see `sign_payload`.
+pub const TIMESTAMP_HEADER: &str = "x-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// A message signed by a node.
+///
+/// Nodes are identified by the address of their key, the same one that signs
+/// their `StateRegistry` transactions. The signature covers the timestamp and
+/// the JSON payload as it has been sent, so receivers don't have to
+/// re-serialize anything:
+/// `keccak256("\x19Sharded Storage message:\n" || timestamp (8 bytes, big-endian) || payload)`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Signed {
+    pub payload: String,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// `r || s || v`, with `v` the recovery id (0 or 1).
+    pub signature: Bytes,
+}
+
+impl Signed {
+    pub fn new<T: Serialize>(key: &SecretKey, message: &T) -> Result<Self> {
+        let payload = serde_json::to_string(message)?;
+        let (timestamp, signature) = sign_payload(key, payload.as_bytes())?;
+
+        Ok(Self {
+            payload,
+            timestamp,
+            signature,
+        })
+    }
+
+    /// Checks the signature and the timestamp, returns the signer and the message.
+    pub fn verify<T: DeserializeOwned>(&self) -> Result<(Address, T)> {
+        let signer = verify_payload(self.timestamp, self.payload.as_bytes(), &self.signature)?;
+
+        Ok((signer, serde_json::from_str(&self.payload)?))
+    }
+
+    /// Like `verify`, also rejecting a message that has been received before.
+    pub fn verify_once<T: DeserializeOwned>(&self, seen: &SeenMessages) -> Result<(Address, T)> {
+        let verified = self.verify()?;
+        seen.check(self.timestamp, self.payload.as_bytes())?;
+
+        Ok(verified)
+    }
+}
+
+/// Signs a payload sent apart from its signature, e.g. a binary request body
+/// (see `wire`), the way `Signed` signs its JSON payload. Returns the timestamp
+/// and the signature.
+pub fn sign_payload(key: &SecretKey, payload: &[u8]) -> Result<(u64, Bytes)> {
+    let timestamp = now()?;
+    Ok((timestamp, sign(key, &digest(timestamp, payload))?))
+}
+
+/// Checks the timestamp and the signature of a payload, returns the signer.
+pub fn verify_payload(timestamp: u64, payload: &[u8], signature: &Bytes) -> Result<Address> {
+    let skew = now()?.abs_diff(timestamp);
+    if skew > MAX_CLOCK_SKEW.as_secs() {
+        return Err(anyhow::anyhow!(
+            "Message timestamp is {}s off the local clock",
+            skew
+        ));
+    }
+
+    recover(&digest(timestamp, payload), signature)
+}
+
+/// Digests of the signed messages accepted recently, with their timestamps,
+/// so that each one is accepted once.
+///
+/// Keyed by the signed digest rather than the signature, which ECDSA
+/// malleability would let an attacker alter. Messages older than
+/// `MAX_CLOCK_SKEW` fail `verify_payload` anyway and are forgotten.
+#[derive(Default)]
+pub struct SeenMessages {
+    seen: Mutex<HashMap<[u8; 32], u64>>,
+}
+
+impl SeenMessages {
+    /// Records a verified message, rejects it if it has been seen before.
+    pub fn check(&self, timestamp: u64, payload: &[u8]) -> Result<()> {
+        let now = now()?;
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, seen_at| now.abs_diff(*seen_at) <= MAX_CLOCK_SKEW.as_secs());
+
+        if seen.insert(digest(timestamp, payload), timestamp).is_some() {
+            return Err(anyhow::anyhow!("Signed message has already been used"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Signs a 32-byte digest, `r || s || v` with `v` the recovery id (0 or 1).
+pub fn sign(key: &SecretKey, digest: &[u8; 32]) -> Result<Bytes> {
+    let signature = SecretKeyRef::new(key).sign_message(digest)?;
+
+    let mut bytes = Vec::with_capacity(65);
+    bytes.extend_from_slice(signature.r.as_bytes());
+    bytes.extend_from_slice(signature.s.as_bytes());
+    bytes.push(signature.v as u8);
+    Ok(Bytes(bytes))
+}
+
+/// The address that has signed `digest`, see `sign`.
+pub fn recover(digest: &[u8; 32], signature: &Bytes) -> Result<Address> {
+    let [signature @ .., v] = &signature.0[..] else {
+        return Err(anyhow::anyhow!("Empty signature"));
+    };
+    if signature.len() != 64 {
+        return Err(anyhow::anyhow!(
+            "Invalid signature length: expected 65, got {}",
+            signature.len() + 1
+        ));
+    }
+
+    web3::signing::recover(digest, signature, *v as i32)
+        .map_err(|err| anyhow::anyhow!("Invalid signature: {}", err))
+}
+
+/// What a signature of `payload` at `timestamp` signs.
+pub fn digest(timestamp: u64, payload: &[u8]) -> [u8; 32] {
+    let mut buf = b"\x19Sharded Storage message:\n".to_vec();
+    buf.extend_from_slice(&timestamp.to_be_bytes());
+    buf.extend_from_slice(payload);
+    keccak256(&buf)
+}
+
+pub fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Challenge a node answers with `GET /identity` to prove it holds its key.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Challenge {
+    pub challenge: H256,
+}
+
+/// The identity of the node listening on `addr`, proven by signing a fresh
+/// challenge. Over TLS, its certificate must be bound to the same identity.
+pub async fn probe(client: &PeerClient, addr: &SocketAddr) -> Result<Address> {
+    let challenge = H256::random();
+    let res = client
+        .get(addr, "identity")
+        .query(&[("challenge", format!("{:?}", challenge))])
+        .send()
+        .await?
+        .error_for_status()?;
+    let tls_node = PeerClient::node_of(&res)?;
+    let signed = res.json::<Signed>().await?;
+
+    let (node, answer) = signed.verify::<Challenge>()?;
+    if answer.challenge != challenge {
+        return Err(anyhow::anyhow!("{} answered another challenge", addr));
+    }
+    if tls_node.is_some_and(|tls_node| tls_node != node) {
+        return Err(anyhow::anyhow!(
+            "{} is {:?}, its certificate belongs to {:?}",
+            addr,
+            node,
+            tls_node
+        ));
+    }
+
+    Ok(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn key(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    fn address(key: &SecretKey) -> Address {
+        SecretKeyRef::new(key).address()
+    }
+
+    /// A message signed at `timestamp` rather than now.
+    fn signed_at(key: &SecretKey, timestamp: u64, payload: &str) -> Signed {
+        Signed {
+            payload: payload.to_string(),
+            timestamp,
+            signature: sign(key, &digest(timestamp, payload.as_bytes())).unwrap(),
+        }
+    }
+
+    #[test]
+    fn signed_messages_recover_to_their_signer() {
+        let signed = Signed::new(&key(1), &vec![1, 2, 3]).unwrap();
+        let (signer, message) = signed.verify::<Vec<u32>>().unwrap();
+        assert_eq!(signer, address(&key(1)));
+        assert_eq!(message, [1, 2, 3]);
+
+        let (timestamp, signature) = sign_payload(&key(1), b"shard").unwrap();
+        assert_eq!(
+            verify_payload(timestamp, b"shard", &signature).unwrap(),
+            address(&key(1))
+        );
+    }
+
+    #[test]
+    fn signatures_of_other_messages_are_rejected() {
+        let signed = Signed::new(&key(1), &"hello").unwrap();
+        // The signature of another message by another node.
+        let other = Signed::new(&key(2), &"bye").unwrap();
+        let forged = Signed {
+            signature: other.signature,
+            ..signed
+        };
+        let signer = forged.verify::<Value>().map(|(signer, _)| signer);
+        assert!(signer.is_err() || signer.unwrap() != address(&key(2)));
+    }
+
+    #[test]
+    fn timestamps_outside_the_skew_are_rejected() {
+        let now = now().unwrap();
+        let skew = MAX_CLOCK_SKEW.as_secs();
+
+        signed_at(&key(1), now - skew / 2, "1")
+            .verify::<Value>()
+            .unwrap();
+        for timestamp in [now - skew - 10, now + skew + 10] {
+            let err = signed_at(&key(1), timestamp, "1")
+                .verify::<Value>()
+                .unwrap_err();
+            assert!(err.to_string().contains("off the local clock"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn tampered_payloads_are_rejected() {
+        let signed = Signed::new(&key(1), &vec![1, 2, 3]).unwrap();
+        let tampered = Signed {
+            payload: "[1,2,4]".to_string(),
+            ..signed.clone()
+        };
+        let signer = tampered.verify::<Value>().map(|(signer, _)| signer);
+        assert!(signer.is_err() || signer.unwrap() != address(&key(1)));
+
+        let (timestamp, signature) = sign_payload(&key(1), b"shard").unwrap();
+        let signer = verify_payload(timestamp, b"shart", &signature);
+        assert!(signer.is_err() || signer.unwrap() != address(&key(1)));
+        // The timestamp is signed too.
+        let signer = verify_payload(timestamp - 1, b"shard", &signature);
+        assert!(signer.is_err() || signer.unwrap() != address(&key(1)));
+
+        let mut truncated = signed;
+        truncated.signature.0.pop();
+        assert!(truncated.verify::<Value>().is_err());
+    }
+
+    #[test]
+    fn messages_are_accepted_once() {
+        let seen = SeenMessages::default();
+        let signed = Signed::new(&key(1), &"hello").unwrap();
+        signed.verify_once::<Value>(&seen).unwrap();
+        let err = signed.verify_once::<Value>(&seen).unwrap_err();
+        assert!(err.to_string().contains("already been used"), "{}", err);
+
+        // The same message signed at another time is a new one.
+        let later = signed_at(&key(1), signed.timestamp + 1, &signed.payload);
+        later.verify_once::<Value>(&seen).unwrap();
+
+        // A message that fails verification isn't recorded.
+        let other = Signed::new(&key(1), &"other").unwrap();
+        let forged = Signed {
+            timestamp: other.timestamp + 2 * MAX_CLOCK_SKEW.as_secs(),
+            ..other.clone()
+        };
+        assert!(forged.verify_once::<Value>(&seen).is_err());
+        other.verify_once::<Value>(&seen).unwrap();
+    }
+}
@@ -1,54 +1,109 @@
 use std::{
-    collections::HashSet, net::SocketAddr, ops::Deref, path::PathBuf, str::FromStr, sync::Arc,
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
 
 use anyhow::Result;
-use ark_bn254::Fr;
-use ark_serialize::{CanonicalDeserialize, CanonicalSerialize, SerializationError};
+use ark_bn254::{Fr, G1Affine};
 use axum::{
-    extract::{ConnectInfo, Path, State},
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
-    Json, Router,
+    Extension, Json, Router,
 };
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
 use clap::Parser;
-use serde::{Deserialize, Serialize};
-use shamir_ss::Domain;
-use tokio::sync::RwLock;
-use web3::types::Address;
+use kzg::{fk20::Fk20, Crs, CrsSource};
+use secp256k1::SecretKey;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use shamir_ss::{Domain, LocalGroups};
+use tokio::sync::{Mutex, RwLock};
+use web3::types::{Address, Bytes, U256};
 
 use crate::{
-    contract::RegistryContract,
-    error::AppResult,
-    storage::{Chunk, ChunkSerde, Storage},
+    auth::Tenants,
+    contract::{check_chunk_shape, RegistryContract, StateRecord},
+    error::{AppError, AppResult},
+    evidence::{AttestedChunk, Evidence, EvidenceQueue, Registry},
+    identity::{Challenge, SeenMessages, Signed},
+    storage::{
+        format_element, parse_element, Chunk, ChunkSerde, Parity, ParitySerde, StateId, Storage,
+        StorageError,
+    },
+    tls::{PeerAcceptor, PeerClient, TlsIdentity, TlsPeer},
+    watcher::{StateIndex, Watcher},
 };
 
-// mod commitment;
+mod auth;
 mod contract;
 mod error;
+mod evidence;
+mod identity;
+mod signer;
 mod storage;
-// mod commitment;
+mod tls;
+mod watcher;
+mod wire;
 
 const CHUNK_SIZE: usize = 2;
+/// Number of chunks per local parity, see `shamir_ss::LocalGroups`.
+const GROUP_SIZE: usize = 2;
+/// Most states of one uploader kept before they are published, see `check_pending_sender`.
+const MAX_PENDING_STATES: usize = 16;
+/// Chunks and parities of states that aren't published this long after they
+/// have been received are removed, see `expire_pending`.
+const PENDING_TTL: Duration = Duration::from_secs(60 * 60);
 
 struct AppState {
     storage: Storage,
     // TODO: Replace with URL?
-    peers: RwLock<HashSet<SocketAddr>>,
-    // contract: RegistryContract,
+    /// Identities of the peers, proven when they connect.
+    peers: RwLock<HashMap<SocketAddr, Address>>,
+    /// Client for requests to `peers`, see `tls`.
+    client: PeerClient,
+    /// Identifies this node to its peers, see `identity`.
+    key: SecretKey,
+    /// Nodes with enough stake in the registry, the only ones chunks are placed on.
+    staked: RwLock<HashSet<Address>>,
+    contract: RegistryContract,
+    /// The deployment served chunks are signed for.
+    registry: Registry,
+    index: StateIndex,
+    /// Uploads are serialized so that each one gets the next state height.
+    upload_lock: Mutex<()>,
+    /// Clients allowed to upload, anyone can if there are none, see `auth`.
+    tenants: Option<Tenants>,
     domain: Domain,
+    crs: Crs,
+    /// Lagrange basis for the data points of `domain`.
+    lagrange: Vec<G1Affine>,
+    /// Prover of the chunks of `domain`.
+    fk20: Fk20,
+    /// Evidence against nodes that have served invalid chunks, to be submitted.
+    evidence: EvidenceQueue,
+    /// Messages signed by nodes that have been accepted, so that they can't be replayed.
+    seen: SeenMessages,
 }
 
+/// Sent to `/p2p` signed by the sending node, see `identity::Signed`.
 #[derive(Debug, Serialize, Deserialize)]
 enum P2PRequest {
-    /// Connect request from a peer.
+    /// Connect request from a peer. An unspecified IP is replaced with the one
+    /// the request comes from.
     Connect { addr: SocketAddr },
     /// New peer notification for other peers.
-    NewPeer { addr: SocketAddr },
+    NewPeer { addr: SocketAddr, node: Address },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 enum P2PResponse {
-    Connected { other_peers: Vec<SocketAddr> },
+    Connected {
+        other_peers: Vec<(SocketAddr, Address)>,
+    },
     Success,
 }
 
@@ -58,12 +113,58 @@ struct Args {
     addr: SocketAddr,
     #[clap(short, long)]
     peer: Option<SocketAddr>,
+    /// Directory to keep the chunks in.
     #[clap(short, long)]
-    file: String,
-    // #[clap(long)]
-    // rpc_url: String,
-    // #[clap(long)]
-    // contract: String,
+    dir: String,
+    #[clap(long)]
+    rpc_url: String,
+    #[clap(long)]
+    contract: String,
+    /// Key used to sign `StateRegistry` transactions.
+    #[clap(long, env = "PRIVATE_KEY", hide_env_values = true)]
+    private_key: Option<SecretKey>,
+    /// Encrypted JSON keystore to use instead of a raw private key.
+    #[clap(long)]
+    keystore: Option<PathBuf>,
+    #[clap(long, env = "KEYSTORE_PASSWORD", hide_env_values = true)]
+    keystore_password: Option<String>,
+    /// Number of blocks a pushed state must be confirmed by.
+    #[clap(long, default_value_t = 1)]
+    confirmations: u64,
+    /// Block to start following `StateRegistry` events from.
+    #[clap(long, default_value_t = 0)]
+    from_block: u64,
+    /// Seconds between polls for new `StateRegistry` events.
+    #[clap(long, default_value_t = 5)]
+    sync_interval: u64,
+    /// Seconds between scrubs of the stored chunks and parities, 0 to disable them.
+    #[clap(long, default_value_t = 3600)]
+    scrub_interval: u64,
+    /// Address to register in the node registry, `--addr` by default.
+    #[clap(long)]
+    endpoint: Option<SocketAddr>,
+    /// Registers this node in the node registry on startup with this stake (in wei),
+    /// unless it is already active.
+    #[clap(long, value_parser = parse_wei)]
+    stake: Option<U256>,
+    /// Serves HTTPS and talks to peers over mutual TLS, with a certificate bound to the node key.
+    #[clap(long)]
+    tls: bool,
+    /// Certificate chain (PEM) served to clients connecting by name (SNI), e.g. one issued by a
+    /// public CA. Peers still get the node certificate.
+    #[clap(long, requires_all = ["tls", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+    /// Private key (PEM) of `--tls-cert`.
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// JSON list of the tenants allowed to upload, with their API keys or addresses and quotas.
+    /// Without it anyone who can reach the node can upload.
+    #[clap(long)]
+    tenants: Option<PathBuf>,
+    /// KZG setup to commit with: `embedded`, `file:<path>` or `ptau:<path>`. The default file is
+    /// written by `kzg10 export`.
+    #[clap(long, default_value = "file:../res/crs.bin")]
+    crs: CrsSource,
 }
 
 #[tokio::main]
@@ -74,29 +175,140 @@ async fn main() {
 
     tracing::info!("{:#?}", &args);
 
-    let domain = Domain::from_k(2);
+    let domain = Domain::bit_reversed(2);
+    let crs = Crs::load(&args.crs, 1 << domain.k, CHUNK_SIZE + 1).unwrap();
+    let fk20 = Fk20::new(&crs, 2 << domain.k, CHUNK_SIZE).unwrap();
+    let points = domain.data_points();
+    let lagrange = match crs.lagrange(&points) {
+        Some(basis) => basis.to_vec(),
+        None => {
+            tracing::warn!(
+                "The CRS has no Lagrange basis for k = {}, computing it",
+                domain.k
+            );
+            crs.lagrange_basis(&points).unwrap()
+        }
+    };
+    let key = signer::load_key(
+        args.private_key,
+        args.keystore.as_deref(),
+        args.keystore_password.as_deref(),
+    )
+    .unwrap();
+    let tenants = match &args.tenants {
+        Some(path) => Some(
+            Tenants::load(path, PathBuf::from(&args.dir).join("usage.json"))
+                .await
+                .unwrap(),
+        ),
+        None => {
+            tracing::warn!("No --tenants, anyone who can reach this node can upload");
+            None
+        }
+    };
+    let tls = args
+        .tls
+        .then(|| TlsIdentity::generate(&key))
+        .transpose()
+        .unwrap();
+    let contract = RegistryContract::new(
+        &args.rpc_url,
+        &args.contract,
+        key,
+        args.confirmations,
+        contract::max_k(&crs),
+    )
+    .unwrap();
+    let registry = contract.registry().await.unwrap();
+    check_verifier(&contract, &crs).await.unwrap();
 
     let state = Arc::new(AppState {
-        storage: Storage::new(&args.file).await,
-        peers: RwLock::new(args.peer.into_iter().collect()),
-        // contract: RegistryContract::new(&args.rpc_url, &args.contract).unwrap(),
+        storage: Storage::new(&args.dir).await,
+        peers: RwLock::new(HashMap::new()),
+        client: PeerClient::new(tls.as_ref()).unwrap(),
+        key,
+        staked: RwLock::new(HashSet::new()),
+        contract,
+        registry,
+        index: StateIndex::default(),
+        upload_lock: Mutex::new(()),
+        tenants,
         domain,
+        crs,
+        lagrange,
+        fk20,
+        evidence: EvidenceQueue::default(),
+        seen: SeenMessages::default(),
     });
 
+    if let Some(stake) = args.stake {
+        let endpoint = args.endpoint.unwrap_or(args.addr);
+        if state
+            .contract
+            .is_active_node(state.contract.account())
+            .await
+            .unwrap()
+        {
+            tracing::info!("Already registered as a storage node");
+        } else {
+            let tx = state
+                .contract
+                .register_node(&endpoint.to_string(), stake)
+                .await
+                .unwrap();
+            tracing::info!(
+                "Registered as a storage node at {} in tx {:?}",
+                endpoint,
+                tx
+            );
+        }
+    }
+
     let app = Router::new()
-        .route("/", get(|| async { () }))
+        .route("/", get(|| async {}))
         .route("/data", get(get_data).post(set_data))
+        .route("/data/params", get(get_upload_params))
+        .route("/data/partial", post(set_partial_data))
+        .route("/data/parity", post(set_parity))
+        .route("/state/:address", get(get_latest_state))
+        .route("/state/:address/:height", get(get_state_data))
+        .route("/state/:address/:height/partial", get(get_partial_data))
         .route(
-            "/data/partial",
-            get(get_partial_data).post(set_partial_data),
+            "/state/:address/:height/partial/index",
+            get(get_chunk_index),
         )
+        .route("/state/:address/:height/samples", get(get_samples))
+        .route("/state/:address/:height/parity", get(get_parity))
+        .route("/state/:address/:height/repair", get(get_repaired_chunk))
         // Shameful pseudo p2p. Rewrite with libp2p using the request/response behaviour.
         .route("/p2p", post(p2p))
+        .route("/identity", get(get_identity))
         .with_state(state.clone());
 
-    tracing::info!("Listening on {}", args.addr);
-    let http = axum::Server::bind(&args.addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let http = async {
+        match &tls {
+            Some(identity) => {
+                let public = match (&args.tls_cert, &args.tls_key) {
+                    (Some(cert), Some(key)) => Some(tls::load_certified_key(cert, key).unwrap()),
+                    _ => None,
+                };
+                let config = RustlsConfig::from_config(Arc::new(
+                    tls::server_config(identity, public).unwrap(),
+                ));
+
+                tracing::info!("Listening on https://{}", args.addr);
+                axum_server::bind(args.addr)
+                    .acceptor(PeerAcceptor::new(RustlsAcceptor::new(config)))
+                    .serve(app)
+                    .await
+            }
+            None => {
+                tracing::info!("Listening on http://{}", args.addr);
+                axum_server::bind(args.addr).serve(app).await
+            }
+        }
+    };
 
     let is_master = args.peer.is_none();
     let heartbeat = async {
@@ -109,8 +321,8 @@ async fn main() {
             }
 
             let peers = state.peers.read().await.clone();
-            for peer in peers {
-                let res = reqwest::get(format!("http://{}/", peer)).await;
+            for peer in peers.into_keys() {
+                let res = state.client.get(&peer, "/").send().await;
 
                 match res {
                     Ok(res) => {
@@ -129,30 +341,51 @@ async fn main() {
         }
     };
 
-    if let Some(peer) = args.peer {
-        tracing::info!("Connecting to peer {}", peer);
-        let res = reqwest::Client::new()
-            .post(format!("http://{}/p2p", peer))
-            .json(&P2PRequest::Connect { addr: args.addr })
-            .send()
-            .await
-            .unwrap();
+    let mut watcher = Watcher::new(args.from_block, args.confirmations);
+    let sync = async {
+        loop {
+            match watcher.sync(&state.contract, &state.index).await {
+                Ok(new_states) => {
+                    if let Err(err) = check_pending_chunks(&state, &new_states).await {
+                        tracing::error!("Failed to check stored chunks: {}", err);
+                    }
+                }
+                Err(err) => tracing::warn!("Failed to sync StateRegistry events: {}", err),
+            }
+            if let Err(err) = sync_nodes(&state).await {
+                tracing::warn!("Failed to sync the node registry: {}", err);
+            }
+            if let Err(err) = expire_pending(&state).await {
+                tracing::warn!("Failed to expire pending chunks: {}", err);
+            }
+            submit_evidence(&state).await;
 
-        if res.status() != 200 {
-            tracing::error!("Failed to connect to peer {}: {}", peer, res.status());
+            tokio::time::sleep(tokio::time::Duration::from_secs(args.sync_interval)).await;
         }
+    };
 
-        let json = res.json::<P2PResponse>().await.unwrap();
-
-        if let P2PResponse::Connected { other_peers } = json {
-            tracing::info!("Connected to peer {}", peer);
-            tracing::info!("Other peers: {:?}", other_peers);
-
-            let mut peers = state.peers.write().await;
-            peers.extend(other_peers);
-        } else {
-            tracing::error!("Unexpected response from peer {}: {:?}", peer, json);
+    let scrub = async {
+        if args.scrub_interval == 0 {
+            return std::future::pending().await;
         }
+        loop {
+            // Waits first, so that the states are synced before their chunks are verified.
+            tokio::time::sleep(tokio::time::Duration::from_secs(args.scrub_interval)).await;
+            if let Err(err) = scrub(&state).await {
+                tracing::error!("Failed to scrub the storage: {}", err);
+            }
+        }
+    };
+
+    // The peer probes the identity of this node, so the server must be running by then.
+    if let Some(peer) = args.peer {
+        let state = state.clone();
+        tokio::spawn(async move {
+            tracing::info!("Connecting to peer {}", peer);
+            if let Err(err) = connect(&state, peer, args.addr).await {
+                tracing::error!("Failed to connect to peer {}: {}", peer, err);
+            }
+        });
     }
 
     tokio::select! {
@@ -162,47 +395,415 @@ async fn main() {
         _ = heartbeat => {
             tracing::error!("Heartbeat error");
         }
+        _ = sync => {
+            tracing::error!("StateRegistry sync error");
+        }
+        _ = scrub => {
+            tracing::error!("Scrubber error");
+        }
     }
 }
 
-async fn get_data(State(state): State<Arc<AppState>>) -> AppResult<Json<Vec<String>>> {
-    let mut chunks = vec![state.storage.read().await.unwrap_or(Chunk::new())];
+fn parse_wei(s: &str) -> Result<U256> {
+    U256::from_dec_str(s).map_err(|err| anyhow::anyhow!("Invalid amount {:?}: {}", s, err))
+}
 
-    for peer in state.peers.read().await.iter() {
-        let res = reqwest::Client::new()
-            .get(format!("http://{}/data/partial", peer))
-            .send()
-            .await;
+/// Refreshes the staked nodes from the registry and adds the ones that aren't
+/// peers yet, once they prove they listen on their registered endpoints.
+async fn sync_nodes(state: &AppState) -> Result<()> {
+    let account = state.contract.account();
+    let nodes = state.contract.active_nodes().await?;
 
-        if let Ok(res) = res {
-            let json = res
-                .json::<ChunkSerde>()
-                .await
-                .map_err(|err| anyhow::anyhow!(err))?;
-            let chunk = json.into();
-            tracing::info!("! Got chunk from peer: {:?}", chunk);
-            chunks.push(chunk);
+    // Only warned about on startup and when the stake goes away.
+    let staked = state.staked.read().await;
+    if !nodes.iter().any(|(node, _)| *node == account)
+        && (staked.is_empty() || staked.contains(&account))
+    {
+        tracing::warn!("This node isn't staked, its peers won't place chunks on it");
+    }
+    drop(staked);
+
+    for (node, endpoint) in &nodes {
+        if *node == account || state.peers.read().await.values().any(|peer| peer == node) {
+            continue;
+        }
+
+        let addr: SocketAddr = match endpoint.parse() {
+            Ok(addr) => addr,
+            Err(err) => {
+                tracing::warn!("Invalid endpoint {:?} of {:?}: {}", endpoint, node, err);
+                continue;
+            }
+        };
+        match identity::probe(&state.client, &addr).await {
+            Ok(id) if id == *node => {
+                tracing::info!("Staked peer {} ({:?}) found in the registry", addr, node);
+                state.peers.write().await.insert(addr, *node);
+            }
+            Ok(id) => tracing::warn!("Peer {} is {:?}, not {:?}", addr, id, node),
+            Err(err) => tracing::warn!("Failed to check the identity of peer {}: {}", addr, err),
         }
     }
 
-    chunks.sort_by_key(|c| c.chunk);
+    *state.staked.write().await = nodes.into_iter().map(|(node, _)| node).collect();
 
-    tracing::info!("Chunks: {:?}", chunks);
+    Ok(())
+}
 
-    // Reassemble chunks
-    // FIXME: something's wrong here
-    let mut elements: Vec<Option<Fr>> = vec![];
-    for (i, chunk) in chunks.iter().enumerate() {
-        if chunk.data.is_empty() {
+/// Checks that `StateRegistry` checks evidence against the same setup as this
+/// node, otherwise it could slash the node for chunks that match their commitments.
+async fn check_verifier(contract: &RegistryContract, crs: &Crs) -> Result<()> {
+    let (hash, g1_len, g2_len) = contract.verifier().await?;
+    if hash.is_zero() {
+        tracing::warn!("StateRegistry has no verifier, nodes can't be slashed until it is set");
+        return Ok(());
+    }
+
+    if evidence::verifier_hash(crs, g1_len as usize, g2_len as usize) != Some(hash) {
+        return Err(anyhow::anyhow!(
+            "The StateRegistry verifier ({} G1 and {} G2 powers, hash {:?}) isn't part of the CRS",
+            g1_len,
+            g2_len,
+            hash
+        ));
+    }
+
+    Ok(())
+}
+
+/// Submits the queued evidence, see `StateRegistry.slash`: commits to it
+/// first, so that the reward can't be taken by someone copying it, then
+/// reveals it once the commitment is mined. Evidence that is rejected, e.g.
+/// because the node has been slashed already, is dropped.
+async fn submit_evidence(state: &AppState) {
+    for evidence in state.evidence.take().await {
+        // Fails if the evidence has been committed to by an earlier attempt,
+        // in which case it can still be submitted.
+        let commitment = evidence.commitment(&state.registry, state.contract.account());
+        if let Err(err) = state.contract.commit_evidence(commitment).await {
+            tracing::warn!(
+                "Failed to commit to evidence against node {:?}: {}",
+                evidence.node,
+                err
+            );
+        }
+
+        match state.contract.slash(&evidence).await {
+            Ok(tx) => tracing::info!("Slashed node {:?} in tx {:?}", evidence.node, tx),
+            Err(err) => tracing::warn!("Failed to slash node {:?}: {}", evidence.node, err),
+        }
+    }
+}
+
+/// Drops stored chunks that turn out not to match their states once the states are published.
+async fn check_pending_chunks(state: &AppState, new_states: &[StateId]) -> Result<()> {
+    for id in new_states {
+        let Some(chunk) = stored(state.storage.read(id).await) else {
+            continue;
+        };
+        let Some(record) = state.index.get(id).await else {
+            continue;
+        };
+
+        if let Err(err) = record.verify_chunk(&state.crs, &chunk) {
+            tracing::warn!(
+                "Dropping chunk {} of state #{} of {:?}: {}",
+                chunk.chunk,
+                id.height,
+                id.uploader,
+                err
+            );
+            state.storage.remove(id).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the chunks and parities of states that haven't been published
+/// within `PENDING_TTL` of being received, so that uploads that are never
+/// published don't take up the disk for good.
+async fn expire_pending(state: &AppState) -> Result<()> {
+    for id in state.storage.list().await? {
+        if state.index.get(&id).await.is_some() {
+            continue;
+        }
+        let Some(modified) = state.storage.modified(&id).await else {
+            continue;
+        };
+        if modified.elapsed().unwrap_or_default() < PENDING_TTL {
+            continue;
+        }
+        // The state may be published before the block the index starts at.
+        if state.contract.get_state_height(id.uploader).await? > id.height {
             continue;
         }
 
-        if chunk.chunk > i as u32 {
-            for _ in 0..(chunk.chunk - i as u32) {
-                elements.extend(std::iter::repeat(None).take(CHUNK_SIZE));
+        tracing::warn!(
+            "State #{} of {:?} hasn't been published in {:?}, removing its chunk and parity",
+            id.height,
+            id.uploader,
+            PENDING_TTL
+        );
+        state.storage.remove(&id).await?;
+        state.storage.remove_parity(&id).await?;
+    }
+
+    Ok(())
+}
+
+/// Checks that a node may send a chunk or a parity of the pending state `id`:
+/// it must be a peer or a staked node, and have at most `MAX_PENDING_STATES`
+/// pending states stored here, this one included.
+async fn check_pending_sender(state: &AppState, sender: Address, id: StateId) -> AppResult<()> {
+    let known = state.staked.read().await.contains(&sender)
+        || state
+            .peers
+            .read()
+            .await
+            .values()
+            .any(|peer| *peer == sender);
+    if !known {
+        return Err(AppError::Forbidden(anyhow::anyhow!(
+            "{:?} is neither a peer nor a staked node",
+            sender
+        )));
+    }
+
+    let mut pending = 0;
+    for other in state.storage.list_uploader(sender).await? {
+        if other != id && state.index.get(&other).await.is_none() {
+            pending += 1;
+        }
+    }
+    if pending >= MAX_PENDING_STATES {
+        return Err(AppError::Forbidden(anyhow::anyhow!(
+            "{:?} has {} states waiting to be published already",
+            sender,
+            pending
+        )));
+    }
+
+    Ok(())
+}
+
+/// Re-reads every stored chunk and parity to find bit rot before it's served.
+/// Corrupted files are quarantined and their chunks repaired from the peers.
+/// Failures are logged and don't stop the scrub of the other states.
+async fn scrub(state: &AppState) -> Result<()> {
+    let ids = state.storage.list().await?;
+    let (mut corrupted, mut repaired) = (0, 0);
+    for id in &ids {
+        match scrub_chunk(state, *id).await {
+            Ok(Some(chunk)) => {
+                corrupted += 1;
+                if let Some(chunk) = chunk {
+                    match repair_stored_chunk(state, *id, chunk).await {
+                        Ok(done) => repaired += done as usize,
+                        Err(err) => tracing::error!(
+                            "Failed to store repaired chunk {} of state #{} of {:?}: {}",
+                            chunk,
+                            id.height,
+                            id.uploader,
+                            err
+                        ),
+                    }
+                }
             }
-        } else {
-            elements.extend(chunk.data.iter().map(|e| Some(*e)));
+            Ok(None) => {}
+            Err(err) => tracing::error!(
+                "Failed to scrub the chunk of state #{} of {:?}: {}",
+                id.height,
+                id.uploader,
+                err
+            ),
+        }
+
+        // Parities are checked by the repairs that use them, only their checksums are checked here.
+        if let Err(err @ StorageError::Corrupted { .. }) = state.storage.read_parity(id).await {
+            tracing::error!("{}, quarantining it", err);
+            corrupted += 1;
+            if let Err(err) = state.storage.quarantine_parity(id).await {
+                tracing::error!(
+                    "Failed to quarantine the parity of state #{} of {:?}: {}",
+                    id.height,
+                    id.uploader,
+                    err
+                );
+            }
+        }
+    }
+
+    if corrupted > 0 {
+        tracing::warn!(
+            "Scrubbed {} states: {} corrupted files, {} chunks repaired",
+            ids.len(),
+            corrupted,
+            repaired
+        );
+    } else {
+        tracing::info!("Scrubbed {} states, no corruption found", ids.len());
+    }
+
+    Ok(())
+}
+
+/// Checks the stored chunk of a state and quarantines it if it's corrupted or
+/// doesn't match the commitment. Chunks of states that aren't published yet
+/// are only checked against their checksums.
+///
+/// Returns `None` if the chunk is intact or missing, otherwise its index if
+/// it can still be read or recovered, see `recover_chunk_index`.
+async fn scrub_chunk(state: &AppState, id: StateId) -> Result<Option<Option<u32>>> {
+    let chunk = match state.storage.read(&id).await {
+        Ok(Some(chunk)) => chunk,
+        Ok(None) => return Ok(None),
+        Err(err @ StorageError::Corrupted { .. }) => {
+            tracing::error!("{}, quarantining it", err);
+            let index = match state.storage.chunk_index(&id).await {
+                Some(index) => Some(index),
+                None => recover_chunk_index(state, id).await,
+            };
+            state.storage.quarantine(&id).await?;
+            return Ok(Some(index));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let Some(record) = state.index.get(&id).await else {
+        return Ok(None);
+    };
+
+    if let Err(err) = record.verify_chunk(&state.crs, &chunk) {
+        tracing::error!(
+            "Chunk {} of state #{} of {:?} doesn't match the commitment, quarantining it: {}",
+            chunk.chunk,
+            id.height,
+            id.uploader,
+            err
+        );
+        state.storage.quarantine(&id).await?;
+        return Ok(Some(Some(chunk.chunk)));
+    }
+
+    Ok(None)
+}
+
+/// Index of the chunk of a published state this node holds, for when the
+/// header of the file is corrupted.
+///
+/// The uploader stores the parity of group `g` with the first chunk of group
+/// `g + 1`, so a readable parity names the chunk. Otherwise the peers are
+/// asked for their chunks, and the index is known if only one is missing.
+/// A wrong guess is caught when the repaired chunk is verified.
+async fn recover_chunk_index(state: &AppState, id: StateId) -> Option<u32> {
+    let record = state.index.get(&id).await?;
+    let num_chunks = record.num_chunks() as usize;
+    let groups = LocalGroups::new(record.chunk_size as usize, GROUP_SIZE);
+
+    if let Some(parity) = stored(state.storage.read_parity(&id).await) {
+        let num_groups = groups.group(num_chunks - 1) + 1;
+        let group = (parity.group as usize + 1) % num_groups;
+        return Some(groups.shards(group, num_chunks).start as u32);
+    }
+
+    let mut held = vec![false; num_chunks];
+    for peer in state.peers.read().await.keys() {
+        if let Some(chunk) = fetch_chunk(state, peer, id, record).await {
+            held[chunk.chunk as usize] = true;
+        }
+    }
+    let mut missing = held.iter().enumerate().filter(|(_, held)| !**held);
+    match (missing.next(), missing.next()) {
+        (Some((index, _)), None) => Some(index as u32),
+        _ => {
+            tracing::warn!(
+                "Can't tell which chunk of state #{} of {:?} is corrupted",
+                id.height,
+                id.uploader
+            );
+            None
+        }
+    }
+}
+
+/// Rebuilds a quarantined chunk from the rest of its group. Returns whether it
+/// has been repaired.
+async fn repair_stored_chunk(state: &AppState, id: StateId, chunk: u32) -> Result<bool> {
+    let Some(record) = state.index.get(&id).await else {
+        tracing::warn!(
+            "State #{} of {:?} isn't published yet, chunk {} can't be repaired",
+            id.height,
+            id.uploader,
+            chunk
+        );
+        return Ok(false);
+    };
+    if chunk as u64 >= record.num_chunks() {
+        return Ok(false);
+    }
+
+    match repair_chunk(state, id, record, chunk).await {
+        Ok(repaired) => {
+            state.storage.write(&repaired).await?;
+            tracing::info!(
+                "Repaired chunk {} of state #{} of {:?}",
+                chunk,
+                id.height,
+                id.uploader
+            );
+            Ok(true)
+        }
+        Err(err) => {
+            tracing::error!(
+                "Failed to repair chunk {} of state #{} of {:?}, it stays quarantined: {}",
+                chunk,
+                id.height,
+                id.uploader,
+                err
+            );
+            Ok(false)
+        }
+    }
+}
+
+async fn get_data(State(state): State<Arc<AppState>>) -> AppResult<Json<Vec<String>>> {
+    let account = state.contract.account();
+    let (id, record) = state.index.latest(account).await.ok_or_else(|| {
+        AppError::NotFound(anyhow::anyhow!("No states of {:?} are known", account))
+    })?;
+
+    Ok(Json(reconstruct(&state, id, record).await?))
+}
+
+async fn get_state_data(
+    State(state): State<Arc<AppState>>,
+    Path((address, height)): Path<(Address, u64)>,
+) -> AppResult<Json<Vec<String>>> {
+    let id = StateId {
+        uploader: address,
+        height,
+    };
+    let record = state.index.get(&id).await.ok_or_else(|| {
+        AppError::NotFound(anyhow::anyhow!(
+            "State #{} of {:?} is not known",
+            height,
+            address
+        ))
+    })?;
+
+    Ok(Json(reconstruct(&state, id, record).await?))
+}
+
+/// Collects the chunks of a state from this node and its peers and decodes the data.
+async fn reconstruct(state: &AppState, id: StateId, record: StateRecord) -> AppResult<Vec<String>> {
+    let chunks = collect_chunks(state, id, record).await?;
+
+    // Reassemble chunks
+    let mut elements: Vec<Option<Fr>> = vec![None; 2 << record.k];
+    for chunk in &chunks {
+        let start = chunk.chunk as usize * record.chunk_size as usize;
+        for (element, value) in elements[start..].iter_mut().zip(&chunk.data) {
+            *element = Some(*value);
         }
     }
 
@@ -210,98 +811,923 @@ async fn get_data(State(state): State<Arc<AppState>>) -> AppResult<Json<Vec<Stri
 
     // TODO: No need to decode on the server side. Just respond with assembled elements
     //       or just chunks. It's fine for testing purposes though.
-    let elements = state
-        .domain
+    let elements = Domain::bit_reversed(record.k as usize)
         .decode(&elements)
-        .ok_or_else(|| anyhow::anyhow!("Invalid data"))?
+        .ok_or_else(|| {
+            AppError::Decode(anyhow::anyhow!(
+                "Not enough chunks of state #{} of {:?} to decode it, got {}",
+                id.height,
+                id.uploader,
+                chunks.len()
+            ))
+        })?
         .into_iter()
-        .map(|e| e.to_string())
+        .take(record.size as usize)
+        .map(|e| format_element(&e))
         .collect();
 
-    Ok(Json(elements))
+    Ok(elements)
 }
 
-async fn get_partial_data(State(state): State<Arc<AppState>>) -> AppResult<Json<ChunkSerde>> {
-    let data = state.storage.read().await.unwrap_or(Chunk::new()).into();
+/// The chunks of a state stored by this node and its peers that match the commitment.
+async fn collect_chunks(state: &AppState, id: StateId, record: StateRecord) -> Result<Vec<Chunk>> {
+    let mut chunks: Vec<Chunk> = local_chunk(state, id, record).await.into_iter().collect();
 
-    Ok(Json(data))
+    for peer in state.peers.read().await.keys() {
+        if let Some(chunk) = fetch_chunk(state, peer, id, record).await {
+            tracing::info!("Got chunk from peer {}: {:?}", peer, chunk);
+            chunks.push(chunk);
+        }
+    }
+
+    Ok(chunks)
 }
 
-async fn set_data(
+/// The chunks of a state with the given indices that match the commitment.
+///
+/// Peers are asked for the index of their chunk first, so only the chunks
+/// that are asked for are fetched.
+async fn collect_samples(
+    state: &AppState,
+    id: StateId,
+    record: StateRecord,
+    indices: &HashSet<u32>,
+) -> Vec<Chunk> {
+    let mut samples = vec![];
+    if let Some(index) = state.storage.chunk_index(&id).await {
+        if indices.contains(&index) {
+            samples.extend(local_chunk(state, id, record).await);
+        }
+    }
+
+    for peer in state.peers.read().await.keys() {
+        if samples.len() == indices.len() {
+            break;
+        }
+        let Some(index) = fetch_chunk_index(state, peer, id).await else {
+            continue;
+        };
+        if !indices.contains(&index) || samples.iter().any(|chunk| chunk.chunk == index) {
+            continue;
+        }
+        match fetch_chunk(state, peer, id, record).await {
+            Some(chunk) if chunk.chunk == index => samples.push(chunk),
+            Some(chunk) => tracing::warn!(
+                "Peer {} claimed chunk {} but sent chunk {}",
+                peer,
+                index,
+                chunk.chunk
+            ),
+            None => {}
+        }
+    }
+
+    samples
+}
+
+/// Index of the chunk of a state stored by `peer`, see `get_chunk_index`.
+async fn fetch_chunk_index(state: &AppState, peer: &SocketAddr, id: StateId) -> Option<u32> {
+    let res = state
+        .client
+        .get(
+            peer,
+            &format!("state/{:?}/{}/partial/index", id.uploader, id.height),
+        )
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+    res.json::<Option<u32>>().await.ok()?
+}
+
+/// A chunk or a parity read from the storage. One that can't be read is
+/// reported and treated as missing.
+fn stored<T>(res: Result<Option<T>, StorageError>) -> Option<T> {
+    res.unwrap_or_else(|err| {
+        tracing::error!("Failed to read from the storage: {}", err);
+        None
+    })
+}
+
+/// The chunk of a state stored by this node, if it matches the commitment.
+async fn local_chunk(state: &AppState, id: StateId, record: StateRecord) -> Option<Chunk> {
+    let chunk = stored(state.storage.read(&id).await)?;
+    match record.verify_chunk(&state.crs, &chunk) {
+        Ok(()) => Some(chunk),
+        Err(err) => {
+            tracing::warn!("Ignoring stored chunk {}: {}", chunk.chunk, err);
+            None
+        }
+    }
+}
+
+/// The chunk of a state stored by `peer`, if it matches the commitment. A
+/// chunk that doesn't is queued as evidence against the node that signed it.
+async fn fetch_chunk(
+    state: &AppState,
+    peer: &SocketAddr,
+    id: StateId,
+    record: StateRecord,
+) -> Option<Chunk> {
+    let res = state
+        .client
+        .get(
+            peer,
+            &format!("state/{:?}/{}/partial", id.uploader, id.height),
+        )
+        .header(header::ACCEPT, wire::CONTENT_TYPE)
+        .send()
+        .await
+        .ok()?;
+    let (chunk, signature) = match read_chunk(res).await {
+        Ok(attested) => attested?,
+        Err(err) => {
+            tracing::warn!("Invalid chunk response from peer {}: {}", peer, err);
+            return None;
+        }
+    };
+
+    if chunk.state != id {
+        tracing::warn!("Peer {} sent a chunk of another state", peer);
+        return None;
+    }
+    let err = match record.verify_chunk(&state.crs, &chunk) {
+        Ok(()) => return Some(chunk),
+        Err(err) => err,
+    };
+
+    tracing::warn!("Peer {} sent invalid chunk {}: {}", peer, chunk.chunk, err);
+    match Evidence::new(&state.crs, &state.registry, &record, chunk, signature) {
+        Ok(evidence) => {
+            tracing::warn!("Queuing evidence against node {:?}", evidence.node);
+            state.evidence.push(evidence).await;
+        }
+        Err(err) => tracing::warn!("The chunk can't be used as evidence: {}", err),
+    }
+    None
+}
+
+/// A signed chunk served by `GET /state/{address}/{height}/partial`, in the
+/// binary format or, for peers that don't use it, JSON.
+async fn read_chunk(res: reqwest::Response) -> Result<Option<(Chunk, Bytes)>> {
+    if res.status() == StatusCode::NO_CONTENT {
+        return Ok(None);
+    }
+    if !wire::is_binary(res.headers()) {
+        return res
+            .json::<Option<AttestedChunk>>()
+            .await?
+            .map(|attested| Ok((attested.chunk.try_into()?, attested.signature)))
+            .transpose();
+    }
+
+    let (chunk, signature) = wire::decode_chunk(&res.bytes().await?)?;
+    let signature = signature.ok_or_else(|| anyhow::anyhow!("The chunk isn't signed"))?;
+    Ok(Some((chunk, signature)))
+}
+
+/// A parity served by `GET /state/{address}/{height}/parity`, see `read_chunk`.
+async fn read_parity(res: reqwest::Response) -> Result<Option<Parity>> {
+    if res.status() == StatusCode::NO_CONTENT {
+        return Ok(None);
+    }
+    if !wire::is_binary(res.headers()) {
+        return res
+            .json::<Option<ParitySerde>>()
+            .await?
+            .map(Parity::try_from)
+            .transpose();
+    }
+
+    Ok(Some(wire::decode_parity(&res.bytes().await?)?))
+}
+
+#[derive(Deserialize)]
+struct SamplesQuery {
+    /// Comma-separated chunk indices.
+    chunks: String,
+}
+
+/// Verified chunks of a state for data availability sampling. Chunks that
+/// can't be found are left out.
+async fn get_samples(
     State(state): State<Arc<AppState>>,
-    Json(data): Json<Vec<String>>,
-) -> AppResult<()> {
-    let data = data
+    Path((address, height)): Path<(Address, u64)>,
+    Query(query): Query<SamplesQuery>,
+) -> AppResult<Json<Vec<ChunkSerde>>> {
+    let id = StateId {
+        uploader: address,
+        height,
+    };
+    let record = state.index.get(&id).await.ok_or_else(|| {
+        AppError::NotFound(anyhow::anyhow!(
+            "State #{} of {:?} is not known",
+            height,
+            address
+        ))
+    })?;
+    let indices = query
+        .chunks
+        .split(',')
+        .map(|s| {
+            s.trim()
+                .parse()
+                .map_err(|_| AppError::Validation(anyhow::anyhow!("Invalid chunk index {:?}", s)))
+        })
+        .collect::<AppResult<HashSet<u32>>>()?;
+
+    let samples = collect_samples(&state, id, record, &indices)
+        .await
         .into_iter()
-        .map(|s| s.parse().map_err(|_| anyhow::anyhow!("Invalid element")))
-        .collect::<Result<Vec<_>>>()?;
+        .map(ChunkSerde::from)
+        .collect();
 
-    let encoded = state.domain.encode(data);
-    let num_peers = state.peers.read().await.len();
-    let num_chunks = (encoded.len() + CHUNK_SIZE - 1) / CHUNK_SIZE;
+    Ok(Json(samples))
+}
 
-    if num_chunks > num_peers {
-        return Err(anyhow::anyhow!(
-            "Not enough peers to store data: expected at least {}, got {}",
-            num_chunks,
-            num_peers
-        )
-        .into());
+/// The chunk of a published state stored by this node, signed by it, as a
+/// shard if the request accepts one (see `wire`).
+///
+/// The signature makes the chunk evidence against this node if it doesn't
+/// match the commitment, so the chunk is checked before it is served and
+/// dropped if it doesn't.
+async fn get_partial_data(
+    State(state): State<Arc<AppState>>,
+    Path((address, height)): Path<(Address, u64)>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let id = StateId {
+        uploader: address,
+        height,
+    };
+    let chunk = match state.index.get(&id).await {
+        Some(record) => verified_local_chunk(&state, id, record).await?,
+        None => None,
+    };
+
+    if wire::accepts_binary(&headers) {
+        let shard = chunk
+            .map(|chunk| {
+                let signature =
+                    identity::sign(&state.key, &evidence::digest(&state.registry, &chunk))?;
+                Ok::<_, anyhow::Error>(wire::encode_chunk(&chunk, Some(&signature)))
+            })
+            .transpose()?;
+        return Ok(wire::response(shard));
+    }
+
+    let attested = chunk
+        .map(|chunk| AttestedChunk::new(&state.key, &state.registry, chunk))
+        .transpose()?;
+    Ok(Json(attested).into_response())
+}
+
+/// Index of the chunk of a published state stored by this node, read from the
+/// header of its file without checking the chunk, so that samplers can tell
+/// which node to fetch a chunk from.
+async fn get_chunk_index(
+    State(state): State<Arc<AppState>>,
+    Path((address, height)): Path<(Address, u64)>,
+) -> Json<Option<u32>> {
+    let id = StateId {
+        uploader: address,
+        height,
+    };
+    if state.index.get(&id).await.is_none() {
+        return Json(None);
+    }
+
+    Json(state.storage.chunk_index(&id).await)
+}
+
+/// The stored chunk of a published state, dropped if it doesn't match the commitment.
+async fn verified_local_chunk(
+    state: &AppState,
+    id: StateId,
+    record: StateRecord,
+) -> Result<Option<Chunk>> {
+    let Some(chunk) = stored(state.storage.read(&id).await) else {
+        return Ok(None);
+    };
+
+    if let Err(err) = record.verify_chunk(&state.crs, &chunk) {
+        tracing::warn!(
+            "Dropping chunk {} of state #{} of {:?}: {}",
+            chunk.chunk,
+            id.height,
+            id.uploader,
+            err
+        );
+        state.storage.remove(&id).await?;
+        return Ok(None);
     }
 
+    Ok(Some(chunk))
+}
+
+/// Encodes the data of a state into its chunks with their proofs and the
+/// local parities of their groups.
+fn encode_shards(
+    state: &AppState,
+    id: StateId,
+    data: Vec<Fr>,
+) -> Result<(Vec<Chunk>, Vec<Parity>)> {
+    let encoded = state.domain.encode(data);
+    let num_chunks = encoded.len().div_ceil(CHUNK_SIZE);
+    let proofs = state.fk20.prove_evaluations(&encoded)?;
+
     let chunks = encoded
         .chunks(CHUNK_SIZE)
         .enumerate()
-        .map(|(n, elements)| Chunk {
+        .zip(proofs)
+        .map(|((n, elements), proof)| Chunk {
+            state: id,
             chunk: n as u32,
             data: elements.to_vec(),
+            proof,
         })
         .collect::<Vec<_>>();
 
-    state.storage.write(&chunks[0]).await?;
+    // Local parities, the one of every group is kept by the holder of the first
+    // chunk of the next group, so that it survives the loss of a chunk of its own.
+    let groups = LocalGroups::new(CHUNK_SIZE, GROUP_SIZE);
+    let parities = groups
+        .parities(&encoded)
+        .into_iter()
+        .enumerate()
+        .map(|(group, data)| Parity {
+            state: id,
+            group: group as u32,
+            data,
+            proofs: groups
+                .shards(group, num_chunks)
+                .map(|n| chunks[n].proof)
+                .collect(),
+        })
+        .collect();
+
+    Ok((chunks, parities))
+}
+
+async fn set_data(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: String,
+) -> AppResult<Json<StateId>> {
+    let tenant = match &state.tenants {
+        Some(tenants) => {
+            let tenant = tenants.authenticate(&headers, &body)?;
+            tenants.check_rate(tenant).await?;
+            Some(tenant)
+        }
+        None => None,
+    };
+
+    let data = serde_json::from_str::<Vec<String>>(&body)
+        .map_err(|err| AppError::Validation(anyhow::anyhow!("Invalid request body: {}", err)))?
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            parse_element(s).map_err(|err| {
+                AppError::Validation(anyhow::anyhow!("Invalid element {}: {}", i, err))
+            })
+        })
+        .collect::<AppResult<Vec<Fr>>>()?;
+    // The commitment is computed over all the data points of the domain.
+    if data.len() != 1 << state.domain.k {
+        return Err(AppError::Validation(anyhow::anyhow!(
+            "Expected {} elements, got {}",
+            1 << state.domain.k,
+            data.len()
+        )));
+    }
+    let bytes = data.len() as u64 * 32;
+
+    let _upload = state.upload_lock.lock().await;
+    if let (Some(tenants), Some(tenant)) = (&state.tenants, tenant) {
+        tenants.check_quota(tenant, bytes).await?;
+    }
+    let account = state.contract.account();
+    let id = StateId {
+        uploader: account,
+        height: state.contract.get_state_height(account).await?,
+    };
 
-    // let address = Address::from_str(&address).map_err(|_| anyhow::anyhow!("Invalid address"))?;
-    // FIXME: Calculate commitment and push it to the contract
-    // state.contract.push_state(address, commit).await?;
+    let (chunks, parities) = encode_shards(&state, id, data.clone())?;
+    let staked = state.staked.read().await.clone();
+    let peers = state
+        .peers
+        .read()
+        .await
+        .iter()
+        .filter(|(_, node)| staked.contains(node))
+        .map(|(addr, _)| *addr)
+        .collect::<Vec<_>>();
+    let num_peers = peers.len();
+    let num_chunks = chunks.len();
+
+    if num_chunks > num_peers {
+        return Err(AppError::InsufficientPeers(anyhow::anyhow!(
+            "Not enough staked peers to store data: expected at least {}, got {}",
+            num_chunks,
+            num_peers
+        )));
+    }
 
+    let groups = LocalGroups::new(CHUNK_SIZE, GROUP_SIZE);
+    // Chunk 0 is kept locally, the others go to the peers in order.
     // Assuming none of the peers are disconnected
-    let peers = state.peers.read().await.clone();
-    for (chunk, peer) in chunks[1..].into_iter().zip(peers.into_iter()) {
-        let res = reqwest::Client::new()
-            .post(format!("http://{}/data/partial", peer))
-            .json(&ChunkSerde::from(chunk.clone()))
-            .send()
-            .await;
-
-        match res {
-            Ok(res) => {
-                if res.status() != 200 {
-                    tracing::error!(
-                        "Failed to send partial data to peer {}: {}",
-                        peer,
-                        res.status()
-                    );
-                }
+    let holders = std::iter::once(None)
+        .chain(peers.into_iter().map(Some))
+        .take(num_chunks)
+        .collect::<Vec<_>>();
+
+    let mut failed = 0;
+    for (chunk, holder) in chunks.iter().zip(&holders) {
+        let res = match holder {
+            None => state.storage.write(chunk).await,
+            Some(peer) => {
+                send_to_peer(
+                    &state,
+                    peer,
+                    "data/partial",
+                    wire::encode_chunk(chunk, None),
+                )
+                .await
             }
-            Err(err) => {
-                tracing::error!("Peer {} is dead: {}", peer, err);
-                // state.peers.write().await.remove(&peer);
+        };
+        if let Err(err) = res {
+            tracing::error!("Failed to store chunk {}: {}", chunk.chunk, err);
+            failed += 1;
+        }
+    }
+
+    for parity in &parities {
+        let next = (parity.group as usize + 1) % parities.len();
+        let res = match holders[groups.shards(next, num_chunks).start] {
+            None => state.storage.write_parity(parity).await,
+            Some(peer) => {
+                send_to_peer(&state, &peer, "data/parity", wire::encode_parity(parity)).await
             }
+        };
+        if let Err(err) = res {
+            tracing::error!("Failed to store parity of group {}: {}", parity.group, err);
+            failed += 1;
         }
     }
 
-    Ok(())
+    if failed > 0 {
+        return Err(AppError::UpstreamPeer(anyhow::anyhow!(
+            "Failed to distribute {} of {} chunks and parities, not publishing the state",
+            failed,
+            num_chunks + parities.len()
+        )));
+    }
+
+    let record = StateRecord {
+        commitment: kzg::commit_lagrange(&data, &state.lagrange)?,
+        size: data.len() as u64,
+        k: state.domain.k as u32,
+        chunk_size: CHUNK_SIZE as u32,
+    };
+    let tx = state.contract.push_state(&record).await?;
+    let published = state.contract.get_state(account, id.height).await?;
+    if published != record {
+        return Err(anyhow::anyhow!(
+            "Unexpected state #{} for {:?}: {:?}",
+            id.height,
+            account,
+            published
+        )
+        .into());
+    }
+    state.index.insert(id, record).await;
+    tracing::info!(
+        "Published state #{} for {:?} in tx {:?}: {:?}",
+        id.height,
+        account,
+        tx,
+        record
+    );
+    if let (Some(tenants), Some(tenant)) = (&state.tenants, tenant) {
+        tenants.record(tenant, bytes).await?;
+        tracing::info!(
+            "Tenant {:?} has uploaded {:?}",
+            tenant.name,
+            tenants.usage(tenant).await
+        );
+    }
+
+    Ok(Json(id))
 }
 
+/// The sender and the shard of a request signed by a node: a binary shard
+/// (see `wire`) or a `Signed` JSON message. Replayed requests are rejected.
+fn read_signed<S, T>(
+    seen: &SeenMessages,
+    headers: &HeaderMap,
+    body: &[u8],
+    tls_peer: Option<Extension<TlsPeer>>,
+    decode: impl FnOnce(&[u8]) -> Result<T>,
+) -> AppResult<(Address, T)>
+where
+    S: DeserializeOwned,
+    T: TryFrom<S, Error = anyhow::Error>,
+{
+    let (sender, shard) = if wire::is_binary(headers) {
+        let sender = wire::signer(headers, body, seen).map_err(AppError::Unauthorized)?;
+        (sender, decode(body).map_err(AppError::Validation)?)
+    } else {
+        let signed = serde_json::from_slice::<Signed>(body).map_err(|err| {
+            AppError::Validation(anyhow::anyhow!("Invalid request body: {}", err))
+        })?;
+        let (sender, data) = signed
+            .verify_once::<S>(seen)
+            .map_err(AppError::Unauthorized)?;
+        (sender, data.try_into().map_err(AppError::Validation)?)
+    };
+    if let Some(Extension(peer)) = tls_peer {
+        peer.check(sender).map_err(AppError::Unauthorized)?;
+    }
+
+    Ok((sender, shard))
+}
+
+/// Stores a chunk signed by a node. Chunks of published states are checked
+/// against the commitment, the ones of pending states must come from the
+/// uploader.
 async fn set_partial_data(
     State(state): State<Arc<AppState>>,
-    Json(data): Json<ChunkSerde>,
+    tls_peer: Option<Extension<TlsPeer>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
 ) -> AppResult<()> {
-    let chunk = data.into();
+    let (sender, chunk) =
+        read_signed::<ChunkSerde, Chunk>(&state.seen, &headers, &body, tls_peer, |buf| {
+            Ok(wire::decode_chunk(buf)?.0)
+        })?;
+
+    match state.index.get(&chunk.state).await {
+        Some(record) => {
+            record.check_chunk(&chunk).map_err(AppError::Validation)?;
+            record
+                .verify_chunk(&state.crs, &chunk)
+                .map_err(AppError::Verification)?;
+        }
+        None if sender != chunk.state.uploader => {
+            return Err(AppError::Forbidden(anyhow::anyhow!(
+                "Chunk {} of pending state #{} of {:?} is sent by {:?}",
+                chunk.chunk,
+                chunk.state.height,
+                chunk.state.uploader,
+                sender
+            )))
+        }
+        // States are published after distribution, so it's rechecked once the state shows up.
+        None => {
+            check_chunk_shape(state.domain.k as u32, CHUNK_SIZE as u32, &chunk)
+                .map_err(AppError::Validation)?;
+            check_pending_sender(&state, sender, chunk.state).await?;
+            tracing::info!(
+                "State #{} of {:?} is not published yet, accepting chunk {} as pending",
+                chunk.state.height,
+                chunk.state.uploader,
+                chunk.chunk
+            );
+        }
+    }
+
     state.storage.write(&chunk).await?;
 
-    // let address = Address::from_str(&address).map_err(|_| anyhow::anyhow!("Invalid address"))?;
-    // state.contract.push_state(address, commit).await?;
+    Ok(())
+}
+
+async fn set_parity(
+    State(state): State<Arc<AppState>>,
+    tls_peer: Option<Extension<TlsPeer>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> AppResult<()> {
+    let (sender, parity) = read_signed::<ParitySerde, Parity>(
+        &state.seen,
+        &headers,
+        &body,
+        tls_peer,
+        wire::decode_parity,
+    )?;
+    if sender != parity.state.uploader {
+        return Err(AppError::Forbidden(anyhow::anyhow!(
+            "Parity of state #{} of {:?} is sent by {:?}",
+            parity.state.height,
+            parity.state.uploader,
+            sender
+        )));
+    }
+
+    let (k, chunk_size) = match state.index.get(&parity.state).await {
+        Some(record) => (record.k, record.chunk_size),
+        None => {
+            check_pending_sender(&state, sender, parity.state).await?;
+            (state.domain.k as u32, CHUNK_SIZE as u32)
+        }
+    };
+    check_parity_shape(k, chunk_size, &parity).map_err(AppError::Validation)?;
+
+    // The parity can't be checked on its own, the chunks repaired with it are.
+    state.storage.write_parity(&parity).await?;
+
+    Ok(())
+}
+
+/// Checks that `parity` has the shape of a parity of data encoded with
+/// `Domain::bit_reversed(k)`, see `contract::check_chunk_shape`.
+fn check_parity_shape(k: u32, chunk_size: u32, parity: &Parity) -> Result<()> {
+    let groups = LocalGroups::new(chunk_size as usize, GROUP_SIZE);
+    let num_chunks = (2usize << k).div_ceil(chunk_size as usize);
+    let members = groups.shards(parity.group as usize, num_chunks);
+    if members.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Group {} is out of range, state has {} groups",
+            parity.group,
+            num_chunks.div_ceil(GROUP_SIZE)
+        ));
+    }
+    if parity.data.len() != chunk_size as usize {
+        return Err(anyhow::anyhow!(
+            "Parity has {} elements, expected {}",
+            parity.data.len(),
+            chunk_size
+        ));
+    }
+    if parity.proofs.len() != members.len() {
+        return Err(anyhow::anyhow!(
+            "Parity has {} proofs, group {} has {} chunks",
+            parity.proofs.len(),
+            parity.group,
+            members.len()
+        ));
+    }
+
+    Ok(())
+}
+
+async fn get_parity(
+    State(state): State<Arc<AppState>>,
+    Path((address, height)): Path<(Address, u64)>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let id = StateId {
+        uploader: address,
+        height,
+    };
+    let parity = stored(state.storage.read_parity(&id).await);
+
+    if wire::accepts_binary(&headers) {
+        return Ok(wire::response(parity.as_ref().map(wire::encode_parity)));
+    }
+    Ok(Json(parity.map(ParitySerde::from)).into_response())
+}
+
+#[derive(Deserialize)]
+struct RepairQuery {
+    chunk: u32,
+}
+
+/// Rebuilds a chunk of a state from the other chunks of its group and their
+/// parity, without decoding the data. A node that lost its chunk can store
+/// the result again with `POST /data/partial`.
+async fn get_repaired_chunk(
+    State(state): State<Arc<AppState>>,
+    Path((address, height)): Path<(Address, u64)>,
+    Query(query): Query<RepairQuery>,
+) -> AppResult<Json<ChunkSerde>> {
+    let id = StateId {
+        uploader: address,
+        height,
+    };
+    let record = state.index.get(&id).await.ok_or_else(|| {
+        AppError::NotFound(anyhow::anyhow!(
+            "State #{} of {:?} is not known",
+            height,
+            address
+        ))
+    })?;
+    if query.chunk as u64 >= record.num_chunks() {
+        return Err(AppError::Validation(anyhow::anyhow!(
+            "Chunk {} is out of range, state has {} chunks",
+            query.chunk,
+            record.num_chunks()
+        )));
+    }
+
+    Ok(Json(
+        repair_chunk(&state, id, record, query.chunk).await?.into(),
+    ))
+}
+
+/// Fetches the rest of the group of `chunk` and its parity, stopping as soon
+/// as they have all been found, and rebuilds the chunk.
+async fn repair_chunk(
+    state: &AppState,
+    id: StateId,
+    record: StateRecord,
+    chunk: u32,
+) -> AppResult<Chunk> {
+    let groups = LocalGroups::new(record.chunk_size as usize, GROUP_SIZE);
+    let group = groups.group(chunk as usize);
+    let members = groups.shards(group, record.num_chunks() as usize);
+
+    let mut shards: Vec<Option<Vec<Fr>>> = vec![None; members.len()];
+    let mut parity = stored(state.storage.read_parity(&id).await)
+        .filter(|parity| parity.group as usize == group);
+    let keep = |found: Chunk, shards: &mut Vec<Option<Vec<Fr>>>| {
+        let n = found.chunk as usize;
+        if found.chunk != chunk && members.contains(&n) {
+            shards[n - members.start] = Some(found.data);
+        }
+    };
+    if let Some(local) = local_chunk(state, id, record).await {
+        keep(local, &mut shards);
+    }
+
+    for peer in state.peers.read().await.keys() {
+        if parity.is_some() && shards.iter().filter(|shard| shard.is_none()).count() == 1 {
+            break;
+        }
+
+        if let Some(found) = fetch_chunk(state, peer, id, record).await {
+            keep(found, &mut shards);
+        }
+        let path = format!("state/{:?}/{}", id.uploader, id.height);
+        if parity.is_none() {
+            let res = state
+                .client
+                .get(peer, &format!("{}/parity", path))
+                .header(header::ACCEPT, wire::CONTENT_TYPE)
+                .send()
+                .await;
+            if let Ok(res) = res {
+                match read_parity(res).await {
+                    Ok(Some(found)) if found.state == id && found.group as usize == group => {
+                        parity = Some(found);
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        tracing::warn!("Invalid parity response from peer {}: {}", peer, err)
+                    }
+                }
+            }
+        }
+    }
+
+    let parity = parity
+        .ok_or_else(|| AppError::NotFound(anyhow::anyhow!("No parity of group {} found", group)))?;
+    let mut data = groups.repair(&shards, &parity.data).ok_or_else(|| {
+        AppError::Decode(anyhow::anyhow!(
+            "Not enough chunks of group {} to repair chunk {}",
+            group,
+            chunk
+        ))
+    })?;
+    let start = chunk as usize * record.chunk_size as usize;
+    data.truncate((2 << record.k) - start);
+
+    let repaired = Chunk {
+        state: id,
+        chunk,
+        data,
+        proof: *parity
+            .proofs
+            .get(chunk as usize - members.start)
+            .ok_or_else(|| {
+                AppError::Verification(anyhow::anyhow!(
+                    "Parity of group {} has no proof of chunk {}",
+                    group,
+                    chunk
+                ))
+            })?,
+    };
+    record
+        .verify_chunk(&state.crs, &repaired)
+        .map_err(AppError::Verification)?;
+
+    Ok(repaired)
+}
+
+/// Sends a shard to `peer`, signed by this node.
+async fn send_to_peer(
+    state: &AppState,
+    peer: &SocketAddr,
+    path: &str,
+    shard: Vec<u8>,
+) -> Result<()> {
+    let res = wire::signed_request(state.client.post(peer, path), &state.key, shard)?
+        .send()
+        .await?;
+
+    if res.status() != 200 {
+        return Err(anyhow::anyhow!(
+            "Peer {} responded with {}",
+            peer,
+            res.status()
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct StateResponse {
+    uploader: Address,
+    height: u64,
+    /// Commitment in the EVM precompile encoding.
+    commitment: Bytes,
+    size: u64,
+    k: u32,
+    chunk_size: u32,
+}
+
+/// Shape of the data `POST /data` accepts.
+#[derive(Serialize)]
+struct UploadParams {
+    /// Parameter of the `Domain`, uploads have `2^k` elements.
+    k: u32,
+    /// Number of encoded elements per chunk.
+    chunk_size: u32,
+}
+
+async fn get_upload_params(State(state): State<Arc<AppState>>) -> Json<UploadParams> {
+    Json(UploadParams {
+        k: state.domain.k as u32,
+        chunk_size: CHUNK_SIZE as u32,
+    })
+}
+
+async fn get_latest_state(
+    State(state): State<Arc<AppState>>,
+    Path(address): Path<Address>,
+) -> AppResult<Json<StateResponse>> {
+    let (id, record) = state.index.latest(address).await.ok_or_else(|| {
+        AppError::NotFound(anyhow::anyhow!("No states of {:?} are known", address))
+    })?;
+
+    Ok(Json(StateResponse {
+        uploader: id.uploader,
+        height: id.height,
+        commitment: Bytes(kzg::evm::g1_to_evm(&record.commitment).to_vec()),
+        size: record.size,
+        k: record.k,
+        chunk_size: record.chunk_size,
+    }))
+}
+
+/// Proves the identity of this node, see `identity::probe`.
+async fn get_identity(
+    State(state): State<Arc<AppState>>,
+    Query(challenge): Query<Challenge>,
+) -> AppResult<Json<Signed>> {
+    Ok(Json(Signed::new(&state.key, &challenge)?))
+}
+
+/// Joins the network through `peer`, announcing this node at `addr`.
+async fn connect(state: &AppState, peer: SocketAddr, addr: SocketAddr) -> Result<()> {
+    let node = identity::probe(&state.client, &peer).await?;
+    state.peers.write().await.insert(peer, node);
+
+    let (sender, res) = state
+        .client
+        .post(&peer, "p2p")
+        .json(&Signed::new(&state.key, &P2PRequest::Connect { addr })?)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Signed>()
+        .await?
+        .verify::<P2PResponse>()?;
+    if sender != node {
+        return Err(anyhow::anyhow!(
+            "Response of {:?} is signed by {:?}",
+            node,
+            sender
+        ));
+    }
+
+    let P2PResponse::Connected { other_peers } = res else {
+        return Err(anyhow::anyhow!("Unexpected response: {:?}", res));
+    };
+    tracing::info!("Connected to peer {} ({:?})", peer, node);
+    tracing::info!("Other peers: {:?}", other_peers);
+
+    for (addr, node) in other_peers {
+        match identity::probe(&state.client, &addr).await {
+            Ok(id) if id == node => {
+                state.peers.write().await.insert(addr, node);
+            }
+            Ok(id) => tracing::warn!("Peer {} is {:?}, not {:?}", addr, id, node),
+            Err(err) => tracing::warn!("Failed to check the identity of peer {}: {}", addr, err),
+        }
+    }
 
     Ok(())
 }
@@ -309,23 +1735,48 @@ async fn set_partial_data(
 async fn p2p(
     ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
     State(state): State<Arc<AppState>>,
-    Json(req): Json<P2PRequest>,
-) -> Json<P2PResponse> {
-    match req {
+    tls_peer: Option<Extension<TlsPeer>>,
+    Json(signed): Json<Signed>,
+) -> AppResult<Json<Signed>> {
+    let (sender, req) = signed
+        .verify_once::<P2PRequest>(&state.seen)
+        .map_err(AppError::Unauthorized)?;
+    if let Some(Extension(peer)) = tls_peer {
+        peer.check(sender).map_err(AppError::Unauthorized)?;
+    }
+
+    let res = match req {
         P2PRequest::Connect { mut addr } => {
-            // FIXME: Not good
-            addr.set_ip(client_addr.ip());
-            tracing::info!("Peer {} connected", addr);
+            if addr.ip().is_unspecified() {
+                addr.set_ip(client_addr.ip());
+            }
+            // Whoever listens on `addr` must hold the key the request is signed with.
+            let node = identity::probe(&state.client, &addr)
+                .await
+                .map_err(AppError::UpstreamPeer)?;
+            if node != sender {
+                return Err(AppError::Verification(anyhow::anyhow!(
+                    "{:?} announced {}, which belongs to {:?}",
+                    sender,
+                    addr,
+                    node
+                )));
+            }
+            tracing::info!("Peer {} ({:?}) connected", addr, node);
 
             let mut peers = state.peers.write().await;
-            let other_peers = peers.iter().copied().collect();
-            peers.insert(addr);
-
-            // TODO: notify other peers
-            for peer in &other_peers {
-                let res = reqwest::Client::new()
-                    .post(format!("http://{}/p2p", peer))
-                    .json(&P2PRequest::NewPeer { addr })
+            let other_peers = peers
+                .iter()
+                .map(|(addr, node)| (*addr, *node))
+                .collect::<Vec<_>>();
+            peers.insert(addr, node);
+
+            let notification = Signed::new(&state.key, &P2PRequest::NewPeer { addr, node })?;
+            for (peer, _) in &other_peers {
+                let res = state
+                    .client
+                    .post(peer, "p2p")
+                    .json(&notification)
                     .send()
                     .await;
 
@@ -345,15 +1796,335 @@ async fn p2p(
 
             tracing::info!("Connected peers: {:?}", peers);
 
-            Json(P2PResponse::Connected { other_peers })
+            P2PResponse::Connected { other_peers }
+        }
+        P2PRequest::NewPeer { addr, node } => {
+            if !state
+                .peers
+                .read()
+                .await
+                .values()
+                .any(|peer| *peer == sender)
+            {
+                return Err(AppError::Forbidden(anyhow::anyhow!(
+                    "New peer announced by unknown node {:?}",
+                    sender
+                )));
+            }
+            let found = identity::probe(&state.client, &addr)
+                .await
+                .map_err(AppError::UpstreamPeer)?;
+            if found != node {
+                return Err(AppError::Verification(anyhow::anyhow!(
+                    "Peer {} is not {:?}",
+                    addr,
+                    node
+                )));
+            }
+            tracing::info!("Peer {} ({:?}) connected", addr, node);
+
+            state.peers.write().await.insert(addr, node);
+
+            P2PResponse::Success
         }
-        P2PRequest::NewPeer { addr } => {
-            tracing::info!("Peer {} connected", addr);
+    };
 
-            let mut peers = state.peers.write().await;
-            peers.insert(addr);
+    Ok(Json(Signed::new(&state.key, &res)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use ark_bn254::G2Affine;
+    use ark_ec::{AffineRepr, CurveGroup};
+    use ark_ff::Field;
+    use web3::signing::{Key, SecretKeyRef};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("node-{}-{}", name, std::process::id()))
+    }
+
+    /// A node without a chain or peers storing under `root`, the RPC URL isn't listened on.
+    async fn app_state(root: &std::path::Path) -> Arc<AppState> {
+        let tau = Fr::from(17);
+        let crs = Crs {
+            powers_of_g: (0..8u64)
+                .map(|i| (G1Affine::generator() * tau.pow([i])).into_affine())
+                .collect(),
+            powers_of_h: (0..3u64)
+                .map(|i| (G2Affine::generator() * tau.pow([i])).into_affine())
+                .collect(),
+            lagrange: vec![],
+        };
+        let domain = Domain::bit_reversed(2);
+        let fk20 = Fk20::new(&crs, 2 << domain.k, CHUNK_SIZE).unwrap();
+        let lagrange = crs.lagrange_basis(&domain.data_points()).unwrap();
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let _ = tokio::fs::remove_dir_all(root).await;
+
+        Arc::new(AppState {
+            storage: Storage::new(root.to_str().unwrap()).await,
+            peers: RwLock::new(HashMap::new()),
+            client: PeerClient::new(None).unwrap(),
+            key,
+            staked: RwLock::new(HashSet::new()),
+            contract: RegistryContract::new(
+                "http://127.0.0.1:1",
+                &format!("{:?}", Address::repeat_byte(2)),
+                key,
+                0,
+                contract::max_k(&crs),
+            )
+            .unwrap(),
+            registry: Registry {
+                chain_id: 1.into(),
+                address: Address::repeat_byte(2),
+            },
+            index: StateIndex::default(),
+            upload_lock: Mutex::new(()),
+            tenants: None,
+            domain,
+            crs,
+            lagrange,
+            fk20,
+            evidence: EvidenceQueue::default(),
+            seen: SeenMessages::default(),
+        })
+    }
+
+    /// Encodes a state as `set_data` does and adds it to the index as published.
+    async fn publish(state: &AppState) -> (StateId, Vec<Chunk>, Vec<Parity>) {
+        let id = StateId {
+            uploader: Address::repeat_byte(0xaa),
+            height: 3,
+        };
+        let data = (1..=4).map(Fr::from).collect::<Vec<_>>();
+        let record = StateRecord {
+            commitment: kzg::commit_lagrange(&data, &state.lagrange).unwrap(),
+            size: data.len() as u64,
+            k: state.domain.k as u32,
+            chunk_size: CHUNK_SIZE as u32,
+        };
+        let (chunks, parities) = encode_shards(state, id, data).unwrap();
+        state.index.insert(id, record).await;
+        (id, chunks, parities)
+    }
+
+    /// A peer serving `chunk` signed by `key` and `parity`, counting the requests for the
+    /// chunk itself.
+    fn mock_peer(
+        key: SecretKey,
+        registry: Registry,
+        chunk: Chunk,
+        parity: Option<Parity>,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let fetched = Arc::new(AtomicUsize::new(0));
+        let index = chunk.chunk;
+        let served = fetched.clone();
+        let app = Router::new()
+            .route(
+                "/state/:address/:height/partial/index",
+                get(move || async move { Json(Some(index)) }),
+            )
+            .route(
+                "/state/:address/:height/partial",
+                get(move || async move {
+                    served.fetch_add(1, Ordering::SeqCst);
+                    let signature =
+                        identity::sign(&key, &evidence::digest(&registry, &chunk)).unwrap();
+                    wire::response(Some(wire::encode_chunk(&chunk, Some(&signature))))
+                }),
+            )
+            .route(
+                "/state/:address/:height/parity",
+                get(
+                    move || async move { wire::response(parity.as_ref().map(wire::encode_parity)) },
+                ),
+            );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        (addr, fetched)
+    }
+
+    /// Adds a peer for each of `chunks`, the one holding the first chunk of a group also holds
+    /// the parity of the group before it, as the uploader places them.
+    async fn add_peers(state: &AppState, chunks: &[Chunk], parities: &[Parity]) {
+        for chunk in chunks {
+            let parity = parities
+                .iter()
+                .find(|parity| {
+                    let next = (parity.group as usize + 1) % parities.len();
+                    chunk.chunk as usize == next * GROUP_SIZE
+                })
+                .cloned();
+            let key = SecretKey::from_slice(&[10 + chunk.chunk as u8; 32]).unwrap();
+            let (addr, _) = mock_peer(key, state.registry, chunk.clone(), parity);
+            let node = SecretKeyRef::new(&key).address();
+            state.peers.write().await.insert(addr, node);
+        }
+    }
+
+    /// Flips a byte of the file holding the chunk of `id`, counted from its end if negative.
+    async fn corrupt(root: &std::path::Path, id: StateId, offset: isize) {
+        let path = root
+            .join(format!("{:x}", id.uploader))
+            .join(id.height.to_string());
+        let mut buf = tokio::fs::read(&path).await.unwrap();
+        let offset = offset.rem_euclid(buf.len() as isize) as usize;
+        buf[offset] ^= 0xff;
+        tokio::fs::write(&path, buf).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn samples_only_fetch_the_requested_chunks() {
+        let root = temp_dir("samples");
+        let state = app_state(&root).await;
+        let (id, chunks, _) = publish(&state).await;
+        state.storage.write(&chunks[0]).await.unwrap();
 
-            Json(P2PResponse::Success)
+        let mut fetched = vec![];
+        for chunk in &chunks[1..] {
+            let key = SecretKey::from_slice(&[10 + chunk.chunk as u8; 32]).unwrap();
+            let (addr, count) = mock_peer(key, state.registry, chunk.clone(), None);
+            let node = SecretKeyRef::new(&key).address();
+            state.peers.write().await.insert(addr, node);
+            fetched.push(count);
         }
+
+        let Json(samples) = get_samples(
+            State(state.clone()),
+            Path((id.uploader, id.height)),
+            Query(SamplesQuery {
+                chunks: "0,2".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+
+        let mut indices = samples.iter().map(|s| s.chunk).collect::<Vec<_>>();
+        indices.sort();
+        assert_eq!(indices, [0, 2]);
+        let fetched = fetched
+            .iter()
+            .map(|count| count.load(Ordering::SeqCst))
+            .collect::<Vec<_>>();
+        assert_eq!(fetched, [0, 1, 0]);
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn signed_chunks_are_accepted_once() {
+        let root = temp_dir("replay");
+        let state = app_state(&root).await;
+        let (_, chunks, _) = publish(&state).await;
+
+        let key = SecretKey::from_slice(&[10; 32]).unwrap();
+        let req = wire::signed_request(
+            state
+                .client
+                .post(&"127.0.0.1:1".parse().unwrap(), "data/partial"),
+            &key,
+            wire::encode_chunk(&chunks[1], None),
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        let body = axum::body::Bytes::copy_from_slice(req.body().unwrap().as_bytes().unwrap());
+        let send = || {
+            set_partial_data(
+                State(state.clone()),
+                None,
+                req.headers().clone(),
+                body.clone(),
+            )
+        };
+
+        send().await.unwrap();
+        assert_eq!(
+            state.storage.read(&chunks[1].state).await.unwrap(),
+            Some(chunks[1].clone())
+        );
+        assert!(matches!(send().await, Err(AppError::Unauthorized(_))));
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupted_chunks_are_quarantined_and_repaired() {
+        let root = temp_dir("scrub");
+        let state = app_state(&root).await;
+        let (id, chunks, parities) = publish(&state).await;
+        state.storage.write(&chunks[0]).await.unwrap();
+        state.storage.write_parity(&parities[1]).await.unwrap();
+        add_peers(&state, &chunks[1..], &parities).await;
+
+        // The last data block, the header stays intact.
+        corrupt(&root, id, -5).await;
+        assert!(state.storage.read(&id).await.is_err());
+        assert_eq!(state.storage.chunk_index(&id).await, Some(0));
+
+        scrub(&state).await.unwrap();
+        assert_eq!(
+            state.storage.read(&id).await.unwrap(),
+            Some(chunks[0].clone())
+        );
+        let quarantined = root
+            .join(format!("{:x}", id.uploader))
+            .join(format!("{}.corrupt", id.height));
+        assert!(quarantined.exists());
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunk_index_is_recovered_from_the_parity() {
+        let root = temp_dir("recover-parity");
+        let state = app_state(&root).await;
+        let (id, chunks, parities) = publish(&state).await;
+        state.storage.write(&chunks[2]).await.unwrap();
+        state.storage.write_parity(&parities[0]).await.unwrap();
+
+        corrupt(&root, id, 1).await;
+        assert_eq!(state.storage.chunk_index(&id).await, None);
+        assert_eq!(recover_chunk_index(&state, id).await, Some(2));
+
+        // Chunk 2 is rebuilt from chunk 3 and the parity of its group, held with chunk 0.
+        add_peers(&state, &[&chunks[..2], &chunks[3..]].concat(), &parities).await;
+        scrub(&state).await.unwrap();
+        assert_eq!(
+            state.storage.read(&id).await.unwrap(),
+            Some(chunks[2].clone())
+        );
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunk_index_is_recovered_from_the_peers() {
+        let root = temp_dir("recover-peers");
+        let state = app_state(&root).await;
+        let (id, chunks, parities) = publish(&state).await;
+        state.storage.write(&chunks[1]).await.unwrap();
+
+        corrupt(&root, id, 1).await;
+        assert_eq!(recover_chunk_index(&state, id).await, None);
+
+        add_peers(&state, &[&chunks[..1], &chunks[2..]].concat(), &parities).await;
+        assert_eq!(recover_chunk_index(&state, id).await, Some(1));
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
     }
 }
@@ -0,0 +1,619 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::Result;
+use secp256k1::SecretKey;
+use tokio::sync::Mutex;
+use web3::{
+    api::{Accounts, Eth, Namespace},
+    signing::{Key, SecretKeyRef},
+    transports::Http,
+    types::{
+        Address, BlockNumber, Bytes, CallRequest, TransactionParameters, TransactionReceipt, H256,
+        U256, U64,
+    },
+    Transport,
+};
+
+/// How many times a transaction is re-sent with bumped fees before giving up.
+const MAX_ATTEMPTS: usize = 5;
+/// How long to wait for a sent transaction to be mined before replacing it.
+const INCLUSION_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait before re-sending an underpriced transaction, doubled on every attempt,
+/// so that the fees it is priced against can move.
+const UNDERPRICED_BACKOFF: Duration = Duration::from_secs(2);
+/// Percentile of the last block's priority fees used as the tip.
+const TIP_PERCENTILE: f64 = 50.0;
+
+/// Reads the signing key either from an encrypted JSON keystore or from a raw secret key.
+pub fn load_key(
+    private_key: Option<SecretKey>,
+    keystore: Option<&Path>,
+    password: Option<&str>,
+) -> Result<SecretKey> {
+    match (private_key, keystore) {
+        (Some(key), None) => Ok(key),
+        (None, Some(path)) => {
+            let password =
+                password.ok_or_else(|| anyhow::anyhow!("Keystore password is not set"))?;
+            let secret = eth_keystore::decrypt_key(path, password)
+                .map_err(|err| anyhow::anyhow!("Failed to decrypt {}: {}", path.display(), err))?;
+
+            Ok(SecretKey::from_slice(&secret)?)
+        }
+        (Some(_), Some(_)) => Err(anyhow::anyhow!(
+            "Either a private key or a keystore must be set, not both"
+        )),
+        (None, None) => Err(anyhow::anyhow!("No private key or keystore is set")),
+    }
+}
+
+/// Signs transactions locally and submits them as raw transactions.
+///
+/// Nonces are assigned locally so that concurrent sends don't collide, and are
+/// re-synchronized from the chain whenever a transaction fails to go through.
+pub struct Signer<T: Transport = Http> {
+    eth: Eth<T>,
+    accounts: Accounts<T>,
+    key: SecretKey,
+    confirmations: u64,
+    nonce: Mutex<Option<U256>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Fees {
+    Legacy {
+        gas_price: U256,
+    },
+    Eip1559 {
+        max_fee_per_gas: U256,
+        max_priority_fee_per_gas: U256,
+    },
+}
+
+impl Fees {
+    /// Geth requires at least a 10% bump for a replacement to be accepted.
+    fn bump(self) -> Self {
+        let bump = |fee: U256| fee + fee / 8 + 1;
+
+        match self {
+            Fees::Legacy { gas_price } => Fees::Legacy {
+                gas_price: bump(gas_price),
+            },
+            Fees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            } => Fees::Eip1559 {
+                max_fee_per_gas: bump(max_fee_per_gas),
+                max_priority_fee_per_gas: bump(max_priority_fee_per_gas),
+            },
+        }
+    }
+}
+
+impl<T: Transport> Signer<T> {
+    pub fn new(transport: T, key: SecretKey, confirmations: u64) -> Self {
+        Self {
+            eth: Eth::new(transport.clone()),
+            accounts: Accounts::new(transport),
+            key,
+            confirmations,
+            nonce: Mutex::new(None),
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        SecretKeyRef::new(&self.key).address()
+    }
+
+    /// Sends a call to `to` with `value` wei attached and waits until it has the configured
+    /// number of confirmations.
+    pub async fn send(
+        &self,
+        to: Address,
+        data: Vec<u8>,
+        value: U256,
+    ) -> Result<TransactionReceipt> {
+        let data = Bytes(data);
+        let chain_id = self.eth.chain_id().await?.as_u64();
+        let gas = self.estimate_gas(to, data.clone(), value).await?;
+        let mut fees = self.fees().await?;
+        let mut nonce = self.next_nonce().await?;
+
+        let mut sent = vec![];
+        for attempt in 1..=MAX_ATTEMPTS {
+            let tx = self.transaction(to, data.clone(), value, gas, nonce, chain_id, fees);
+            let signed = self
+                .accounts
+                .sign_transaction(tx, SecretKeyRef::new(&self.key))
+                .await?;
+
+            match self.eth.send_raw_transaction(signed.raw_transaction).await {
+                Ok(hash) => sent.push(hash),
+                Err(err) if is_already_known(&err) => sent.push(signed.transaction_hash),
+                Err(err) if is_underpriced(&err) => {
+                    tracing::warn!(
+                        "Transaction with nonce {} is underpriced (attempt {}): {}",
+                        nonce,
+                        attempt,
+                        err
+                    );
+                    fees = fees.bump();
+                    tokio::time::sleep(UNDERPRICED_BACKOFF * 2u32.pow(attempt as u32 - 1)).await;
+                    continue;
+                }
+                Err(err) if is_nonce_too_low(&err) => {
+                    // Otherwise one of the previously sent versions has been mined meanwhile.
+                    if sent.is_empty() {
+                        tracing::warn!("Nonce {} is already used, resyncing: {}", nonce, err);
+                        self.reset_nonce().await;
+                        nonce = self.next_nonce().await?;
+                        continue;
+                    }
+                }
+                Err(err) => {
+                    self.reset_nonce().await;
+                    return Err(err.into());
+                }
+            }
+
+            if let Some(receipt) = self.wait_for_receipt(&sent).await? {
+                return Ok(receipt);
+            }
+
+            tracing::warn!(
+                "Transaction with nonce {} was not mined in {:?}, replacing it",
+                nonce,
+                INCLUSION_TIMEOUT
+            );
+            fees = fees.bump();
+        }
+
+        self.reset_nonce().await;
+        Err(anyhow::anyhow!(
+            "Transaction with nonce {} was not mined after {} attempts",
+            nonce,
+            MAX_ATTEMPTS
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn transaction(
+        &self,
+        to: Address,
+        data: Bytes,
+        value: U256,
+        gas: U256,
+        nonce: U256,
+        chain_id: u64,
+        fees: Fees,
+    ) -> TransactionParameters {
+        let tx = TransactionParameters {
+            nonce: Some(nonce),
+            to: Some(to),
+            gas,
+            value,
+            data,
+            chain_id: Some(chain_id),
+            ..Default::default()
+        };
+
+        match fees {
+            Fees::Legacy { gas_price } => TransactionParameters {
+                gas_price: Some(gas_price),
+                ..tx
+            },
+            Fees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            } => TransactionParameters {
+                transaction_type: Some(U64::from(2)),
+                max_fee_per_gas: Some(max_fee_per_gas),
+                max_priority_fee_per_gas: Some(max_priority_fee_per_gas),
+                ..tx
+            },
+        }
+    }
+
+    async fn estimate_gas(&self, to: Address, data: Bytes, value: U256) -> Result<U256> {
+        let req = CallRequest {
+            from: Some(self.address()),
+            to: Some(to),
+            data: Some(data),
+            value: Some(value),
+            ..Default::default()
+        };
+        let gas = self.eth.estimate_gas(req, None).await?;
+
+        // Leave some headroom in case the state changes before inclusion.
+        Ok(gas + gas / 5)
+    }
+
+    /// Uses EIP-1559 fees when the chain supports them and falls back to a legacy gas price,
+    /// also when the endpoint doesn't support `eth_feeHistory`.
+    async fn fees(&self) -> Result<Fees> {
+        let history = match self
+            .eth
+            .fee_history(1.into(), BlockNumber::Latest, Some(vec![TIP_PERCENTILE]))
+            .await
+        {
+            Ok(history) => Some(history),
+            Err(err) => {
+                tracing::debug!("No fee history, using a legacy gas price: {}", err);
+                None
+            }
+        };
+        let base_fee = history
+            .as_ref()
+            .and_then(|history| history.base_fee_per_gas.last().copied())
+            .unwrap_or_default();
+
+        if base_fee.is_zero() {
+            let gas_price = self.eth.gas_price().await?;
+            return Ok(Fees::Legacy { gas_price });
+        }
+
+        let tip = history
+            .and_then(|history| history.reward)
+            .and_then(|reward| reward.first().and_then(|r| r.first().copied()))
+            .unwrap_or_default()
+            .max(U256::one());
+
+        Ok(Fees::Eip1559 {
+            max_fee_per_gas: base_fee * 2 + tip,
+            max_priority_fee_per_gas: tip,
+        })
+    }
+
+    async fn next_nonce(&self) -> Result<U256> {
+        let mut cached = self.nonce.lock().await;
+        let nonce = match *cached {
+            Some(nonce) => nonce,
+            None => {
+                self.eth
+                    .transaction_count(self.address(), Some(BlockNumber::Pending))
+                    .await?
+            }
+        };
+        *cached = Some(nonce + 1);
+
+        Ok(nonce)
+    }
+
+    /// Forgets the locally tracked nonce so the next transaction picks it up from the chain.
+    async fn reset_nonce(&self) {
+        *self.nonce.lock().await = None;
+    }
+
+    /// Waits for any of the sent versions of a transaction to be mined and confirmed.
+    ///
+    /// The receipt is fetched again once confirmed: if the block has been
+    /// reorganized away meanwhile, the transaction is waited for again.
+    async fn wait_for_receipt(&self, hashes: &[H256]) -> Result<Option<TransactionReceipt>> {
+        let deadline = tokio::time::Instant::now() + INCLUSION_TIMEOUT;
+
+        loop {
+            for hash in hashes {
+                let Some(mut receipt) = self.eth.transaction_receipt(*hash).await? else {
+                    continue;
+                };
+
+                // Already mined, so wait for the confirmations regardless of the deadline.
+                while let Some(mined_at) = receipt.block_number {
+                    let current = self.eth.block_number().await?;
+                    if current.as_u64() + 1 < mined_at.as_u64() + self.confirmations {
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                        continue;
+                    }
+
+                    match self.eth.transaction_receipt(*hash).await? {
+                        Some(latest) if latest.block_hash == receipt.block_hash => {
+                            return Ok(Some(latest))
+                        }
+                        Some(latest) => {
+                            tracing::warn!(
+                                "Transaction {:?} has been reorganized into block {:?}",
+                                hash,
+                                latest.block_number
+                            );
+                            receipt = latest;
+                        }
+                        None => {
+                            tracing::warn!(
+                                "Transaction {:?} has been reorganized out of block {}",
+                                hash,
+                                mined_at
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+
+            if tokio::time::Instant::now() >= deadline {
+                return Ok(None);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+fn rpc_message(err: &web3::Error) -> Option<String> {
+    match err {
+        web3::Error::Rpc(err) => Some(err.message.to_lowercase()),
+        _ => None,
+    }
+}
+
+fn is_underpriced(err: &web3::Error) -> bool {
+    rpc_message(err).is_some_and(|msg| msg.contains("underpriced"))
+}
+
+fn is_already_known(err: &web3::Error) -> bool {
+    rpc_message(err).is_some_and(|msg| msg.contains("already known"))
+}
+
+fn is_nonce_too_low(err: &web3::Error) -> bool {
+    rpc_message(err).is_some_and(|msg| msg.contains("nonce too low"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    };
+
+    use jsonrpc_core::{Call, ErrorCode, Params};
+    use serde_json::{json, Value};
+    use web3::RequestId;
+
+    use super::*;
+
+    type Handler = dyn Fn(&str) -> web3::Result<Value> + Send + Sync;
+
+    /// Answers every call with `handler`, recording the methods called.
+    #[derive(Clone)]
+    struct MockTransport {
+        handler: Arc<Handler>,
+        calls: Arc<StdMutex<Vec<String>>>,
+    }
+
+    impl std::fmt::Debug for MockTransport {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.debug_struct("MockTransport").finish_non_exhaustive()
+        }
+    }
+
+    impl Transport for MockTransport {
+        type Out = std::future::Ready<web3::Result<Value>>;
+
+        fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+            (0, web3::helpers::build_request(0, method, params))
+        }
+
+        fn send(&self, _: RequestId, request: Call) -> Self::Out {
+            let Call::MethodCall(call) = request else {
+                panic!("Unexpected request {:?}", request);
+            };
+            assert!(matches!(call.params, Params::Array(_)));
+            self.calls.lock().unwrap().push(call.method.clone());
+            std::future::ready((self.handler)(&call.method))
+        }
+    }
+
+    impl MockTransport {
+        fn count(&self, method: &str) -> usize {
+            let calls = self.calls.lock().unwrap();
+            calls.iter().filter(|call| *call == method).count()
+        }
+    }
+
+    fn mock_signer(
+        confirmations: u64,
+        handler: impl Fn(&str) -> web3::Result<Value> + Send + Sync + 'static,
+    ) -> (Signer<MockTransport>, MockTransport) {
+        let transport = MockTransport {
+            handler: Arc::new(handler),
+            calls: Default::default(),
+        };
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        (
+            Signer::new(transport.clone(), key, confirmations),
+            transport,
+        )
+    }
+
+    /// The `n`-th answer of `answers`, then the last one.
+    fn sequence<T: Clone>(counter: &AtomicUsize, answers: &[T]) -> T {
+        let n = counter.fetch_add(1, Ordering::SeqCst);
+        answers[n.min(answers.len() - 1)].clone()
+    }
+
+    fn rpc_error(message: &str) -> web3::Error {
+        web3::Error::Rpc(jsonrpc_core::Error {
+            code: ErrorCode::ServerError(-32000),
+            message: message.to_string(),
+            data: None,
+        })
+    }
+
+    fn mined(block: u64, hash: u8) -> Value {
+        serde_json::to_value(TransactionReceipt {
+            block_number: Some(block.into()),
+            block_hash: Some(H256::repeat_byte(hash)),
+            status: Some(1.into()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    /// A chain with EIP-1559 fees on which every transaction is mined at once.
+    fn chain(method: &str) -> web3::Result<Value> {
+        Ok(match method {
+            "eth_chainId" => json!("0x1"),
+            "eth_estimateGas" => json!("0x5208"),
+            "eth_gasPrice" => json!("0x3b9aca00"),
+            "eth_feeHistory" => json!({
+                "oldestBlock": "0x10",
+                "baseFeePerGas": ["0x64", "0x64"],
+                "gasUsedRatio": [0.5],
+                "reward": [["0x2"]],
+            }),
+            "eth_getTransactionCount" => json!("0x7"),
+            "eth_sendRawTransaction" => json!(H256::repeat_byte(1)),
+            "eth_blockNumber" => json!("0x10"),
+            "eth_getTransactionReceipt" => mined(0x10, 2),
+            _ => panic!("Unexpected call {}", method),
+        })
+    }
+
+    #[tokio::test]
+    async fn nonces_are_allocated_locally_until_reset() {
+        let counts = AtomicUsize::new(0);
+        let (signer, transport) = mock_signer(1, move |method| match method {
+            "eth_getTransactionCount" => Ok(json!(sequence(&counts, &["0x7", "0x9"]))),
+            _ => chain(method),
+        });
+
+        assert_eq!(signer.next_nonce().await.unwrap(), 7.into());
+        assert_eq!(signer.next_nonce().await.unwrap(), 8.into());
+        assert_eq!(transport.count("eth_getTransactionCount"), 1);
+
+        signer.reset_nonce().await;
+        assert_eq!(signer.next_nonce().await.unwrap(), 9.into());
+        assert_eq!(transport.count("eth_getTransactionCount"), 2);
+    }
+
+    #[tokio::test]
+    async fn used_nonces_are_resynced_from_the_chain() {
+        let counts = AtomicUsize::new(0);
+        let sends = AtomicUsize::new(0);
+        let (signer, transport) = mock_signer(1, move |method| match method {
+            "eth_getTransactionCount" => Ok(json!(sequence(&counts, &["0x7", "0x9"]))),
+            "eth_sendRawTransaction" if sends.fetch_add(1, Ordering::SeqCst) == 0 => {
+                Err(rpc_error("nonce too low"))
+            }
+            _ => chain(method),
+        });
+
+        signer
+            .send(Address::repeat_byte(3), vec![], U256::zero())
+            .await
+            .unwrap();
+        assert_eq!(transport.count("eth_sendRawTransaction"), 2);
+        assert_eq!(transport.count("eth_getTransactionCount"), 2);
+        assert_eq!(signer.next_nonce().await.unwrap(), 10.into());
+    }
+
+    #[test]
+    fn bumped_fees_replace_the_previous_ones() {
+        let Fees::Legacy { gas_price } = (Fees::Legacy {
+            gas_price: 100.into(),
+        })
+        .bump() else {
+            panic!("The fees changed their type");
+        };
+        assert!(gas_price >= 110.into());
+
+        let Fees::Eip1559 {
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+        } = (Fees::Eip1559 {
+            max_fee_per_gas: 200.into(),
+            max_priority_fee_per_gas: 0.into(),
+        })
+        .bump()
+        else {
+            panic!("The fees changed their type");
+        };
+        assert!(max_fee_per_gas >= 220.into());
+        assert!(max_priority_fee_per_gas > 0.into());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn underpriced_transactions_are_resent_with_bumped_fees_after_a_backoff() {
+        let sends = AtomicUsize::new(0);
+        let (signer, transport) = mock_signer(1, move |method| match method {
+            "eth_sendRawTransaction" if sends.fetch_add(1, Ordering::SeqCst) < 2 => {
+                Err(rpc_error("replacement transaction underpriced"))
+            }
+            _ => chain(method),
+        });
+
+        let start = tokio::time::Instant::now();
+        signer
+            .send(Address::repeat_byte(3), vec![], U256::zero())
+            .await
+            .unwrap();
+        assert_eq!(transport.count("eth_sendRawTransaction"), 3);
+        assert!(start.elapsed() >= UNDERPRICED_BACKOFF * 3);
+        // The same nonce is kept.
+        assert_eq!(transport.count("eth_getTransactionCount"), 1);
+    }
+
+    #[tokio::test]
+    async fn legacy_gas_price_is_used_without_fee_history() {
+        let (signer, _) = mock_signer(1, |method| match method {
+            "eth_feeHistory" => Err(rpc_error("the method eth_feeHistory does not exist")),
+            _ => chain(method),
+        });
+        assert!(matches!(
+            signer.fees().await.unwrap(),
+            Fees::Legacy { gas_price } if gas_price == 1_000_000_000u64.into()
+        ));
+
+        let (signer, _) = mock_signer(1, |method| match method {
+            "eth_feeHistory" => Ok(json!({
+                "oldestBlock": "0x10",
+                "baseFeePerGas": ["0x0", "0x0"],
+                "gasUsedRatio": [0.5],
+            })),
+            _ => chain(method),
+        });
+        assert!(matches!(signer.fees().await.unwrap(), Fees::Legacy { .. }));
+
+        let (signer, _) = mock_signer(1, chain);
+        assert!(matches!(
+            signer.fees().await.unwrap(),
+            Fees::Eip1559 { max_fee_per_gas, max_priority_fee_per_gas }
+                if max_fee_per_gas == 202.into() && max_priority_fee_per_gas == 2.into()
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn receipts_are_checked_again_after_the_confirmations() {
+        // Mined in block 0x10, then reorganized into another block 0x10.
+        let receipts = AtomicUsize::new(0);
+        let (signer, transport) = mock_signer(3, move |method| match method {
+            "eth_getTransactionReceipt" => {
+                Ok(sequence(&receipts, &[mined(0x10, 2), mined(0x10, 4)]))
+            }
+            "eth_blockNumber" => Ok(json!("0x12")),
+            _ => chain(method),
+        });
+        let receipt = signer
+            .wait_for_receipt(&[H256::repeat_byte(1)])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(receipt.block_hash, Some(H256::repeat_byte(4)));
+        assert_eq!(transport.count("eth_getTransactionReceipt"), 3);
+
+        // Reorganized out and mined again later.
+        let receipts = AtomicUsize::new(0);
+        let (signer, _) = mock_signer(1, move |method| match method {
+            "eth_getTransactionReceipt" => Ok(sequence(
+                &receipts,
+                &[mined(0x10, 2), Value::Null, mined(0x10, 5)],
+            )),
+            _ => chain(method),
+        });
+        let receipt = signer
+            .wait_for_receipt(&[H256::repeat_byte(1)])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(receipt.block_hash, Some(H256::repeat_byte(5)));
+    }
+}
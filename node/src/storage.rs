@@ -1,107 +1,646 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeSet,
+    fmt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
 use anyhow::Result;
-use ark_bn254::Fr;
-use ark_ff::Field;
-use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_bn254::{Fr, G1Affine};
+use ark_ff::Zero;
+use kzg::evm::{g1_from_evm, g1_to_evm};
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
+use web3::types::{Address, Bytes};
+
+use crate::wire;
+
+/// Identifies a version of the data by the `StateRegistry` entry it is published as.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct StateId {
+    pub uploader: Address,
+    pub height: u64,
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ChunkSerde {
+    pub state: StateId,
     pub chunk: u32,
     pub data: Vec<String>,
+    /// Proof in the EVM precompile encoding.
+    pub proof: Bytes,
 }
 
 impl From<Chunk> for ChunkSerde {
     fn from(chunk: Chunk) -> Self {
         Self {
+            state: chunk.state,
             chunk: chunk.chunk,
-            data: chunk.data.iter().map(|x| x.to_string()).collect(),
+            data: chunk.data.iter().map(format_element).collect(),
+            proof: Bytes(g1_to_evm(&chunk.proof).to_vec()),
         }
     }
 }
 
-#[derive(Clone, CanonicalSerialize, CanonicalDeserialize, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Chunk {
+    pub state: StateId,
     pub chunk: u32,
     pub data: Vec<Fr>,
+    /// Multi-point KZG proof of `data` against the commitment of the state.
+    pub proof: G1Affine,
+}
+
+impl TryFrom<ChunkSerde> for Chunk {
+    type Error = anyhow::Error;
+
+    fn try_from(chunk: ChunkSerde) -> Result<Self> {
+        Ok(Self {
+            state: chunk.state,
+            chunk: chunk.chunk,
+            data: parse_elements(&chunk.data)?,
+            proof: g1_from_evm(&chunk.proof.0)
+                .map_err(|err| anyhow::anyhow!("Invalid proof: {}", err))?,
+        })
+    }
+}
+
+/// Local parity of a group of chunks, see `shamir_ss::LocalGroups`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parity {
+    pub state: StateId,
+    pub group: u32,
+    /// Element-wise sum of the chunks of the group.
+    pub data: Vec<Fr>,
+    /// Proofs of the chunks of the group, so that repaired chunks can be checked.
+    pub proofs: Vec<G1Affine>,
 }
 
-impl Chunk {
-    pub fn new() -> Self {
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ParitySerde {
+    pub state: StateId,
+    pub group: u32,
+    pub data: Vec<String>,
+    /// Proofs in the EVM precompile encoding.
+    pub proofs: Vec<Bytes>,
+}
+
+impl From<Parity> for ParitySerde {
+    fn from(parity: Parity) -> Self {
         Self {
-            chunk: 0,
-            data: vec![],
+            state: parity.state,
+            group: parity.group,
+            data: parity.data.iter().map(format_element).collect(),
+            proofs: parity
+                .proofs
+                .iter()
+                .map(|proof| Bytes(g1_to_evm(proof).to_vec()))
+                .collect(),
         }
     }
+}
+
+impl TryFrom<ParitySerde> for Parity {
+    type Error = anyhow::Error;
+
+    fn try_from(parity: ParitySerde) -> Result<Self> {
+        Ok(Self {
+            state: parity.state,
+            group: parity.group,
+            data: parse_elements(&parity.data)?,
+            proofs: parity
+                .proofs
+                .iter()
+                .enumerate()
+                .map(|(i, proof)| {
+                    g1_from_evm(&proof.0)
+                        .map_err(|err| anyhow::anyhow!("Invalid proof {}: {}", i, err))
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+}
 
-    pub fn new_empty(chunk: u32, size: usize) -> Self {
-        Self {
-            chunk,
-            data: vec![Fr::ZERO; size],
+/// Formats a field element in its canonical decimal form. `Fr` displays zero
+/// as an empty string, which isn't a number.
+pub fn format_element(element: &Fr) -> String {
+    if element.is_zero() {
+        return "0".to_string();
+    }
+    element.to_string()
+}
+
+/// Parses a field element in its canonical decimal form, the one
+/// `format_element` produces: no sign, no leading zeros and less than the
+/// modulus.
+///
+/// `Fr::from_str` reduces larger values instead of rejecting them, which
+/// would let the same element be sent in many forms.
+pub fn parse_element(s: &str) -> Result<Fr> {
+    let element: Fr = s
+        .parse()
+        .map_err(|_| anyhow::anyhow!("{:?} is not a decimal number", s))?;
+    if format_element(&element) != s {
+        return Err(anyhow::anyhow!("{:?} is not a canonical field element", s));
+    }
+
+    Ok(element)
+}
+
+pub fn parse_elements(elements: &[String]) -> Result<Vec<Fr>> {
+    elements
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            parse_element(s).map_err(|err| anyhow::anyhow!("Invalid element {}: {}", i, err))
+        })
+        .collect()
+}
+
+/// Keeps the local chunk of every state under `<root>/<uploader>/<height>` and
+/// the parity a node holds for it, if any, under `<root>/<uploader>/<height>.parity`.
+///
+/// Both are stored in the `wire` format, framed with CRC-32s: the shard's
+/// header (`wire::HEADER_SIZE` bytes) and then the rest of it in blocks of
+/// `BLOCK_SIZE` bytes, each followed by its CRC-32 (big-endian). The index of
+/// a chunk can thus still be read when its values are corrupted. Files are
+/// replaced atomically.
+///
+/// A file that doesn't match its checksums, or holds another state, is
+/// reported as `StorageError::Corrupted` rather than as missing.
+pub struct Storage {
+    root: PathBuf,
+}
+
+/// Bytes per checksummed block, 64 values.
+const BLOCK_SIZE: usize = 2048;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    /// The file exists but doesn't hold what it should.
+    Corrupted {
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(err) => write!(f, "{}", err),
+            StorageError::Corrupted { path, reason } => {
+                write!(f, "{} is corrupted: {}", path.display(), reason)
+            }
         }
     }
 }
 
-impl From<ChunkSerde> for Chunk {
-    fn from(chunk: ChunkSerde) -> Self {
-        Self {
-            chunk: chunk.chunk,
-            data: chunk.data.iter().map(|x| x.parse().unwrap()).collect(),
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+/// What a file holds, see `Storage`.
+trait Stored: Sized {
+    const KIND: u8;
+
+    fn encode(&self) -> Vec<u8>;
+
+    fn decode(buf: &[u8]) -> Result<Self>;
+}
+
+impl Stored for Chunk {
+    const KIND: u8 = wire::CHUNK;
+
+    fn encode(&self) -> Vec<u8> {
+        wire::encode_chunk(self, None)
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        match wire::decode_chunk(buf)? {
+            (chunk, None) => Ok(chunk),
+            (_, Some(_)) => Err(anyhow::anyhow!("Stored chunks aren't signed")),
         }
     }
 }
 
-pub struct Storage {
-    path: PathBuf,
+impl Stored for Parity {
+    const KIND: u8 = wire::PARITY;
+
+    fn encode(&self) -> Vec<u8> {
+        wire::encode_parity(self)
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        wire::decode_parity(buf)
+    }
 }
 
 impl Storage {
-    pub async fn new(path: &str) -> Self {
-        let path = path.parse().unwrap();
+    pub async fn new(root: &str) -> Self {
+        let root: PathBuf = root.parse().unwrap();
+
+        tokio::fs::create_dir_all(&root).await.unwrap();
 
-        let _ = std::fs::remove_file(&path);
+        Self { root }
+    }
+
+    fn path(&self, id: &StateId) -> PathBuf {
+        self.root
+            .join(format!("{:x}", id.uploader))
+            .join(id.height.to_string())
+    }
 
-        Self { path }
+    fn parity_path(&self, id: &StateId) -> PathBuf {
+        self.path(id).with_extension("parity")
     }
 
     pub async fn write(&self, chunk: &Chunk) -> Result<()> {
-        let mut file = tokio::fs::OpenOptions::new()
-            .read(true)
-            .create(true)
-            .write(true)
-            .open(&self.path)
-            .await?;
+        write_file(&self.path(&chunk.state), chunk).await
+    }
+
+    pub async fn write_parity(&self, parity: &Parity) -> Result<()> {
+        write_file(&self.parity_path(&parity.state), parity).await
+    }
 
-        let mut buf = vec![];
-        chunk
-            .serialize_compressed(&mut buf)
-            .map_err(|_| anyhow::anyhow!("Serialization error"))?;
+    pub async fn remove(&self, id: &StateId) -> Result<()> {
+        match tokio::fs::remove_file(self.path(id)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn remove_parity(&self, id: &StateId) -> Result<()> {
+        match tokio::fs::remove_file(self.parity_path(id)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
 
-        file.write_all(&buf).await?;
+    /// When the chunk or the parity of a state has last been written, `None`
+    /// if there are none.
+    pub async fn modified(&self, id: &StateId) -> Option<SystemTime> {
+        let mut modified = None;
+        for path in [self.path(id), self.parity_path(id)] {
+            if let Ok(time) = tokio::fs::metadata(path).await.and_then(|m| m.modified()) {
+                modified = modified.max(Some(time));
+            }
+        }
+        modified
+    }
 
-        Ok(())
+    /// The chunk of a state, `None` if there is none.
+    pub async fn read(&self, id: &StateId) -> Result<Option<Chunk>, StorageError> {
+        read_file(&self.path(id), id).await
     }
 
-    pub async fn read(&self) -> Option<Chunk> {
-        let mut file = match tokio::fs::OpenOptions::new()
-            .read(true)
-            .open(&self.path)
-            .await
-        {
-            Ok(file) => file,
-            Err(err) => {
-                tracing::warn!("Error opening file: {}", err);
-                return None;
+    pub async fn read_parity(&self, id: &StateId) -> Result<Option<Parity>, StorageError> {
+        read_file(&self.parity_path(id), id).await
+    }
+
+    /// Index of the chunk a file claims to hold, as long as its header is intact.
+    pub async fn chunk_index(&self, id: &StateId) -> Option<u32> {
+        let buf = tokio::fs::read(self.path(id)).await.ok()?;
+        let header = decode_header(&buf).ok()?;
+        (header.kind == Chunk::KIND && header.state == *id).then_some(header.index)
+    }
+
+    /// States with a chunk or a parity stored.
+    pub async fn list(&self) -> Result<BTreeSet<StateId>> {
+        let mut ids = BTreeSet::new();
+        let mut dirs = tokio::fs::read_dir(&self.root).await?;
+        while let Some(dir) = dirs.next_entry().await? {
+            let Some(uploader) = dir
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<Address>().ok())
+            else {
+                continue;
+            };
+            if !dir.file_type().await?.is_dir() {
+                continue;
+            }
+
+            ids.extend(self.list_uploader(uploader).await?);
+        }
+
+        Ok(ids)
+    }
+
+    /// States of `uploader` with a chunk or a parity stored.
+    pub async fn list_uploader(&self, uploader: Address) -> Result<BTreeSet<StateId>> {
+        let mut ids = BTreeSet::new();
+        let dir = self.root.join(format!("{:x}", uploader));
+        let mut files = match tokio::fs::read_dir(dir).await {
+            Ok(files) => files,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(ids),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(file) = files.next_entry().await? {
+            let name = file.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            // Skips temporary and quarantined files.
+            let height = name.strip_suffix(".parity").unwrap_or(name);
+            if let Ok(height) = height.parse() {
+                ids.insert(StateId { uploader, height });
             }
+        }
+
+        Ok(ids)
+    }
+
+    /// Moves a corrupted chunk out of the way to `<height>.corrupt`, kept for inspection.
+    pub async fn quarantine(&self, id: &StateId) -> Result<()> {
+        quarantine_file(&self.path(id)).await
+    }
+
+    pub async fn quarantine_parity(&self, id: &StateId) -> Result<()> {
+        quarantine_file(&self.parity_path(id)).await
+    }
+}
+
+fn encode<T: Stored>(value: &T) -> Vec<u8> {
+    let shard = value.encode();
+    let (header, rest) = shard.split_at(wire::HEADER_SIZE);
+
+    let mut buf = Vec::with_capacity(shard.len() + (rest.len() / BLOCK_SIZE + 2) * 4);
+    for block in std::iter::once(header).chain(rest.chunks(BLOCK_SIZE)) {
+        buf.extend_from_slice(block);
+        buf.extend_from_slice(&crc32fast::hash(block).to_be_bytes());
+    }
+
+    buf
+}
+
+/// Checks the checksum of the header of a file and reads it.
+fn decode_header(buf: &[u8]) -> Result<wire::Header> {
+    if buf.len() < wire::HEADER_SIZE + 4 {
+        return Err(anyhow::anyhow!("Truncated header"));
+    }
+    let (header, checksum) = buf[..wire::HEADER_SIZE + 4].split_at(wire::HEADER_SIZE);
+    if crc32fast::hash(header).to_be_bytes() != checksum {
+        return Err(anyhow::anyhow!("Header checksum mismatch"));
+    }
+
+    wire::decode_header(header)
+}
+
+fn decode<T: Stored>(buf: &[u8], id: &StateId) -> Result<T> {
+    let header = decode_header(buf)?;
+    if header.kind != T::KIND {
+        return Err(anyhow::anyhow!(
+            "Holds a shard of kind {}, expected {}",
+            header.kind,
+            T::KIND
+        ));
+    }
+    if header.state != *id {
+        return Err(anyhow::anyhow!("Holds state {:?}", header.state));
+    }
+
+    let mut shard = buf[..wire::HEADER_SIZE].to_vec();
+    for (i, block) in buf[wire::HEADER_SIZE + 4..]
+        .chunks(BLOCK_SIZE + 4)
+        .enumerate()
+    {
+        let Some(split) = block.len().checked_sub(4).filter(|split| *split > 0) else {
+            return Err(anyhow::anyhow!("Truncated block {}", i));
         };
+        let (block, checksum) = block.split_at(split);
+        if crc32fast::hash(block).to_be_bytes() != checksum {
+            return Err(anyhow::anyhow!("Checksum mismatch in block {}", i));
+        }
+        shard.extend_from_slice(block);
+    }
+
+    T::decode(&shard)
+}
+
+/// Writes to a temporary file first, so that a crash never leaves a partial file behind.
+async fn write_file<T: Stored>(path: &Path, value: &T) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    file.write_all(&encode(value)).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await?;
+
+    Ok(())
+}
+
+async fn quarantine_file(path: &Path) -> Result<()> {
+    let mut corrupt = path.as_os_str().to_owned();
+    corrupt.push(".corrupt");
+    tokio::fs::rename(path, corrupt).await?;
+
+    Ok(())
+}
+
+async fn read_file<T: Stored>(path: &Path, id: &StateId) -> Result<Option<T>, StorageError> {
+    let buf = match tokio::fs::read(path).await {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
 
-        let mut buf = vec![];
-        if let Err(_) = file.read_to_end(&mut buf).await {
-            return None;
+    decode(&buf, id)
+        .map(Some)
+        .map_err(|err| StorageError::Corrupted {
+            path: path.to_owned(),
+            reason: format!("{:#}", err),
+        })
+}
+
+/// Shards shared by the tests of the modules that encode them.
+#[cfg(test)]
+pub mod fixtures {
+    use ark_ec::{AffineRepr, CurveGroup};
+
+    use super::*;
+
+    pub fn id() -> StateId {
+        StateId {
+            uploader: Address::repeat_byte(0xaa),
+            height: 7,
         }
+    }
+
+    /// Spans two checksummed blocks when stored.
+    pub fn chunk() -> Chunk {
+        Chunk {
+            state: id(),
+            chunk: 3,
+            data: (0..100).map(Fr::from).collect(),
+            proof: (G1Affine::generator() * Fr::from(11)).into_affine(),
+        }
+    }
+
+    pub fn parity() -> Parity {
+        Parity {
+            state: id(),
+            group: 1,
+            data: (10..15).map(Fr::from).collect(),
+            proofs: (1..4)
+                .map(|i| (G1Affine::generator() * Fr::from(i)).into_affine())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fixtures::*, *};
+
+    #[test]
+    fn chunk_and_parity_round_trip() {
+        assert_eq!(decode::<Chunk>(&encode(&chunk()), &id()).unwrap(), chunk());
+        assert_eq!(
+            decode::<Parity>(&encode(&parity()), &id()).unwrap(),
+            parity()
+        );
+    }
+
+    #[test]
+    fn header_holds_the_index() {
+        let header = decode_header(&encode(&chunk())).unwrap();
+        assert_eq!(
+            (header.kind, header.state, header.index),
+            (Chunk::KIND, id(), 3)
+        );
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let buf = encode(&chunk());
+        for len in 0..buf.len() {
+            assert!(
+                decode::<Chunk>(&buf[..len], &id()).is_err(),
+                "length {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut buf = encode(&chunk());
+        buf.push(0);
+        assert!(decode::<Chunk>(&buf, &id()).is_err());
+    }
+
+    #[test]
+    fn bad_checksums_are_rejected() {
+        let buf = encode(&chunk());
+
+        let mut corrupted = buf.clone();
+        corrupted[30] ^= 1;
+        let err = decode_header(&corrupted).err().unwrap();
+        assert!(err.to_string().contains("Header checksum"), "{}", err);
+
+        // A byte in the second block.
+        let mut corrupted = buf;
+        corrupted[wire::HEADER_SIZE + 4 + (BLOCK_SIZE + 4) + 5] ^= 1;
+        assert!(decode_header(&corrupted).is_ok());
+        let err = decode::<Chunk>(&corrupted, &id()).err().unwrap();
+        assert!(err.to_string().contains("block 1"), "{}", err);
+    }
+
+    #[test]
+    fn other_kinds_and_states_are_rejected() {
+        assert!(decode::<Parity>(&encode(&chunk()), &id()).is_err());
+
+        let other = StateId { height: 8, ..id() };
+        assert!(decode::<Chunk>(&encode(&chunk()), &other).is_err());
+    }
+
+    #[tokio::test]
+    async fn rewriting_a_chunk_truncates_the_file() {
+        let root = std::env::temp_dir().join(format!("storage-rewrite-{}", std::process::id()));
+        let storage = Storage::new(root.to_str().unwrap()).await;
+        let mut short = chunk();
+        short.data.truncate(2);
+        storage.write(&chunk()).await.unwrap();
+        storage.write(&short).await.unwrap();
+
+        let len = tokio::fs::metadata(storage.path(&id()))
+            .await
+            .unwrap()
+            .len();
+        assert_eq!(len as usize, encode(&short).len());
+        assert_eq!(storage.read(&id()).await.unwrap().unwrap(), short);
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn every_version_is_kept() {
+        let root = std::env::temp_dir().join(format!("storage-versions-{}", std::process::id()));
+        let storage = Storage::new(root.to_str().unwrap()).await;
+        let versions = [7, 8].map(|height| Chunk {
+            state: StateId { height, ..id() },
+            ..chunk()
+        });
+        let other = Chunk {
+            state: StateId {
+                uploader: Address::repeat_byte(0xbb),
+                ..id()
+            },
+            ..chunk()
+        };
+        for chunk in versions.iter().chain([&other]) {
+            storage.write(chunk).await.unwrap();
+        }
+
+        for chunk in versions.iter().chain([&other]) {
+            assert_eq!(
+                storage.read(&chunk.state).await.unwrap().as_ref(),
+                Some(chunk)
+            );
+        }
+        assert_eq!(
+            storage.list_uploader(id().uploader).await.unwrap(),
+            BTreeSet::from(versions.map(|chunk| chunk.state))
+        );
+        assert_eq!(storage.list().await.unwrap().len(), 3);
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupted_files_keep_their_index_and_are_quarantined() {
+        let root = std::env::temp_dir().join(format!("storage-test-{}", std::process::id()));
+        let storage = Storage::new(root.to_str().unwrap()).await;
+        storage.write(&chunk()).await.unwrap();
+        storage.write_parity(&parity()).await.unwrap();
+        assert_eq!(storage.list().await.unwrap(), BTreeSet::from([id()]));
+
+        let mut buf = tokio::fs::read(storage.path(&id())).await.unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        tokio::fs::write(storage.path(&id()), buf).await.unwrap();
+
+        assert!(matches!(
+            storage.read(&id()).await,
+            Err(StorageError::Corrupted { .. })
+        ));
+        assert_eq!(storage.chunk_index(&id()).await, Some(3));
+
+        storage.quarantine(&id()).await.unwrap();
+        assert!(storage.read(&id()).await.unwrap().is_none());
+        assert!(storage.read_parity(&id()).await.unwrap().is_some());
 
-        Chunk::deserialize_compressed(&mut &buf[..]).ok()
+        tokio::fs::remove_dir_all(root).await.unwrap();
     }
 }
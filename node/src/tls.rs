@@ -0,0 +1,395 @@
+//! TLS for the node API.
+//!
+//! Nodes have no CA to vouch for them, so, like libp2p, every node serves a
+//! self-signed certificate for a fresh P-256 key with an extension binding it
+//! to the node's identity key (see `identity`): the identity's signature over
+//! the certificate's public key. Nodes only accept such certificates from each
+//! other, in both directions (mutual TLS), and learn the identity of the other
+//! side from the handshake. Clients connecting by name (SNI) can be served an
+//! ordinary certificate instead, see `--tls-cert`.
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use axum::{middleware::AddExtension, Extension};
+use axum_server::{accept::Accept, tls_rustls::RustlsAcceptor};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    server::{ClientCertVerified, ClientCertVerifier, ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, CertificateError, ClientConfig, DistinguishedName, PrivateKey, ServerConfig,
+    ServerName,
+};
+use secp256k1::SecretKey;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower_layer::Layer;
+use web3::{
+    signing::keccak256,
+    types::{Address, Bytes},
+};
+use x509_parser::{oid_registry::Oid, prelude::FromDer};
+
+use crate::identity;
+
+/// Subject of node certificates, also sent as the only acceptable issuer
+/// when asking for a client certificate.
+const SUBJECT: &str = "sharded-storage node";
+/// Private OID of the extension binding a certificate to a node identity.
+const IDENTITY_EXTENSION: &[u64] = &[2, 25, 0x5348_4152_4445_4421];
+
+/// The certificate this node presents to its peers.
+pub struct TlsIdentity {
+    cert: Certificate,
+    key: PrivateKey,
+}
+
+impl TlsIdentity {
+    /// A certificate for a fresh key, bound to the identity of `key`.
+    pub fn generate(key: &SecretKey) -> Result<Self> {
+        let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+        let signature = identity::sign(key, &digest(&key_pair.public_key_der()))?;
+
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.key_pair = Some(key_pair);
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, SUBJECT);
+        // DER OCTET STRING of the 65-byte signature.
+        let mut content = vec![0x04, signature.0.len() as u8];
+        content.extend_from_slice(&signature.0);
+        params
+            .custom_extensions
+            .push(rcgen::CustomExtension::from_oid_content(
+                IDENTITY_EXTENSION,
+                content,
+            ));
+
+        let cert = rcgen::Certificate::from_params(params)?;
+        Ok(Self {
+            cert: Certificate(cert.serialize_der()?),
+            key: PrivateKey(cert.serialize_private_key_der()),
+        })
+    }
+
+    fn certified_key(&self) -> Result<CertifiedKey> {
+        Ok(CertifiedKey::new(
+            vec![self.cert.clone()],
+            sign::any_supported_type(&self.key)?,
+        ))
+    }
+}
+
+/// What the identity key signs: `keccak256("\x19Sharded Storage TLS key:\n" || SubjectPublicKeyInfo)`.
+fn digest(public_key: &[u8]) -> [u8; 32] {
+    let mut buf = b"\x19Sharded Storage TLS key:\n".to_vec();
+    buf.extend_from_slice(public_key);
+    keccak256(&buf)
+}
+
+/// The node a certificate is bound to.
+pub fn node_of(cert: &[u8]) -> Result<Address> {
+    let (_, cert) = x509_parser::certificate::X509Certificate::from_der(cert)
+        .map_err(|err| anyhow::anyhow!("Invalid certificate: {}", err))?;
+    let oid = Oid::from(IDENTITY_EXTENSION)
+        .map_err(|_| anyhow::anyhow!("Invalid identity extension OID"))?;
+    let extension = cert
+        .extensions()
+        .iter()
+        .find(|ext| ext.oid == oid)
+        .ok_or_else(|| anyhow::anyhow!("The certificate isn't bound to a node"))?;
+
+    let signature = match extension.value {
+        [0x04, len, signature @ ..] if *len as usize == signature.len() => signature,
+        _ => return Err(anyhow::anyhow!("Invalid identity extension")),
+    };
+    identity::recover(&digest(cert.public_key().raw), &Bytes(signature.to_vec()))
+}
+
+/// Accepts node certificates only, whoever they are bound to: the identity
+/// is checked against the messages sent over the connection.
+struct NodeCertVerifier {
+    subjects: Vec<DistinguishedName>,
+}
+
+impl NodeCertVerifier {
+    fn new(identity: &TlsIdentity) -> Result<Self> {
+        let (_, cert) = x509_parser::certificate::X509Certificate::from_der(&identity.cert.0)
+            .map_err(|err| anyhow::anyhow!("Invalid certificate: {}", err))?;
+
+        Ok(Self {
+            subjects: vec![DistinguishedName::from(cert.subject().as_raw().to_vec())],
+        })
+    }
+
+    fn verify(cert: &Certificate) -> Result<(), rustls::Error> {
+        node_of(&cert.0).map(|_| ()).map_err(|err| {
+            tracing::debug!("Rejecting certificate: {}", err);
+            rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)
+        })
+    }
+}
+
+impl ServerCertVerifier for NodeCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Self::verify(end_entity).map(|_| ServerCertVerified::assertion())
+    }
+}
+
+impl ClientCertVerifier for NodeCertVerifier {
+    /// Clients without a certificate can still use the public API.
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &self.subjects
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Self::verify(end_entity).map(|_| ClientCertVerified::assertion())
+    }
+}
+
+/// Serves the node certificate, or `public` to clients that ask for a name.
+struct CertResolver {
+    node: Arc<CertifiedKey>,
+    public: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        match (&self.public, client_hello.server_name()) {
+            (Some(public), Some(_)) => Some(public.clone()),
+            _ => Some(self.node.clone()),
+        }
+    }
+}
+
+pub fn server_config(identity: &TlsIdentity, public: Option<CertifiedKey>) -> Result<ServerConfig> {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(Arc::new(NodeCertVerifier::new(identity)?))
+        .with_cert_resolver(Arc::new(CertResolver {
+            node: Arc::new(identity.certified_key()?),
+            public: public.map(Arc::new),
+        }));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Loads a PEM certificate chain and its private key (PKCS#8, PKCS#1 or SEC1).
+pub fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey> {
+    let chain = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if chain.is_empty() {
+        return Err(anyhow::anyhow!("No certificates in {}", cert.display()));
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("No private key in {}", key.display()))?;
+
+    Ok(CertifiedKey::new(chain, sign::any_supported_type(&key)?))
+}
+
+/// The node on the other side of a connection to this node's API, `None`
+/// for clients without a node certificate.
+#[derive(Clone, Copy, Debug)]
+pub struct TlsPeer(pub Option<Address>);
+
+/// Adds the `TlsPeer` of every connection to its requests.
+#[derive(Clone)]
+pub struct PeerAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl PeerAcceptor {
+    pub fn new(inner: RustlsAcceptor) -> Self {
+        Self { inner }
+    }
+}
+
+impl<I, S> Accept<I, S> for PeerAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, TlsPeer>;
+    type Future = std::pin::Pin<
+        Box<dyn std::future::Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>,
+    >;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let peer = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| node_of(&cert.0).ok());
+
+            Ok((stream, Extension(TlsPeer(peer)).layer(service)))
+        })
+    }
+}
+
+/// HTTP client for requests to peers, over mutual TLS if this node has a
+/// `TlsIdentity`.
+pub struct PeerClient {
+    http: reqwest::Client,
+    scheme: &'static str,
+}
+
+impl PeerClient {
+    pub fn new(identity: Option<&TlsIdentity>) -> Result<Self> {
+        let Some(identity) = identity else {
+            return Ok(Self {
+                http: reqwest::Client::new(),
+                scheme: "http",
+            });
+        };
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(NodeCertVerifier::new(identity)?))
+            .with_client_auth_cert(vec![identity.cert.clone()], identity.key.clone())?;
+
+        Ok(Self {
+            http: reqwest::Client::builder()
+                .use_preconfigured_tls(config)
+                .tls_info(true)
+                .build()?,
+            scheme: "https",
+        })
+    }
+
+    pub fn get(&self, peer: &SocketAddr, path: &str) -> reqwest::RequestBuilder {
+        self.http.get(self.url(peer, path))
+    }
+
+    pub fn post(&self, peer: &SocketAddr, path: &str) -> reqwest::RequestBuilder {
+        self.http.post(self.url(peer, path))
+    }
+
+    fn url(&self, peer: &SocketAddr, path: &str) -> String {
+        format!(
+            "{}://{}/{}",
+            self.scheme,
+            peer,
+            path.trim_start_matches('/')
+        )
+    }
+
+    /// The node that has answered `res` according to its certificate, `None`
+    /// without TLS.
+    pub fn node_of(res: &reqwest::Response) -> Result<Option<Address>> {
+        let Some(info) = res.extensions().get::<reqwest::tls::TlsInfo>() else {
+            return Ok(None);
+        };
+        let cert = info
+            .peer_certificate()
+            .ok_or_else(|| anyhow::anyhow!("No peer certificate"))?;
+
+        node_of(cert).map(Some)
+    }
+}
+
+impl TlsPeer {
+    /// Checks that a message signed by `sender` comes over its own connection.
+    pub fn check(&self, sender: Address) -> Result<()> {
+        match self.0 {
+            Some(node) if node == sender => Ok(()),
+            Some(node) => Err(anyhow::anyhow!(
+                "Message of {:?} is sent by {:?}",
+                sender,
+                node
+            )),
+            None => Err(anyhow::anyhow!(
+                "Message of {:?} is sent without a node certificate",
+                sender
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use web3::signing::{Key, SecretKeyRef};
+
+    use super::*;
+
+    #[test]
+    fn generated_certificate_names_its_node() {
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let identity = TlsIdentity::generate(&key).unwrap();
+        assert_eq!(
+            node_of(&identity.cert.0).unwrap(),
+            SecretKeyRef::new(&key).address()
+        );
+    }
+
+    #[test]
+    fn certificate_without_the_extension_is_rejected() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let err = node_of(&cert.serialize_der().unwrap()).err().unwrap();
+        assert!(err.to_string().contains("isn't bound"), "{}", err);
+    }
+
+    #[test]
+    fn malformed_certificate_is_rejected() {
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let cert = TlsIdentity::generate(&key).unwrap().cert.0;
+        assert!(node_of(&cert[..cert.len() / 2]).is_err());
+        assert!(node_of(b"not a certificate").is_err());
+    }
+
+    #[test]
+    fn tampered_binding_names_another_node() {
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let mut cert = TlsIdentity::generate(&key).unwrap().cert.0;
+        // Corrupts the signature in the extension, not the certificate's own.
+        let content = cert
+            .windows(2)
+            .position(|window| window == [0x04, 65])
+            .unwrap();
+        cert[content + 2] ^= 1;
+
+        assert_ne!(node_of(&cert).ok(), Some(SecretKeyRef::new(&key).address()));
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use tokio::sync::RwLock;
+use web3::types::Address;
+
+use crate::{
+    contract::{RegistryContract, StateRecord},
+    storage::StateId,
+};
+
+/// Local copy of the states published to `StateRegistry`.
+#[derive(Default)]
+pub struct StateIndex {
+    states: RwLock<BTreeMap<StateId, StateRecord>>,
+}
+
+impl StateIndex {
+    pub async fn insert(&self, id: StateId, record: StateRecord) {
+        self.states.write().await.insert(id, record);
+    }
+
+    pub async fn get(&self, id: &StateId) -> Option<StateRecord> {
+        self.states.read().await.get(id).copied()
+    }
+
+    /// The most recent state of `uploader` known to the index.
+    pub async fn latest(&self, uploader: Address) -> Option<(StateId, StateRecord)> {
+        let from = StateId {
+            uploader,
+            height: 0,
+        };
+        let to = StateId {
+            uploader,
+            height: u64::MAX,
+        };
+
+        self.states
+            .read()
+            .await
+            .range(from..=to)
+            .next_back()
+            .map(|(id, record)| (*id, *record))
+    }
+}
+
+/// Most blocks queried for events at once, RPC providers reject larger ranges.
+const MAX_BLOCK_RANGE: u64 = 1000;
+
+/// Follows `StatePushed` events of the registry.
+pub struct Watcher {
+    next_block: u64,
+    confirmations: u64,
+}
+
+impl Watcher {
+    pub fn new(from_block: u64, confirmations: u64) -> Self {
+        Self {
+            next_block: from_block,
+            confirmations,
+        }
+    }
+
+    /// Adds the states pushed in blocks with enough confirmations since the
+    /// last call to `index` and returns their ids.
+    ///
+    /// Events are queried `MAX_BLOCK_RANGE` blocks at a time. If a query fails
+    /// after some have succeeded, the states found so far are returned and the
+    /// next call resumes from the failed range.
+    pub async fn sync(
+        &mut self,
+        contract: &RegistryContract,
+        index: &StateIndex,
+    ) -> Result<Vec<StateId>> {
+        let latest = contract.block_number().await?;
+        let Some(to) = (latest + 1).checked_sub(self.confirmations.max(1)) else {
+            return Ok(vec![]);
+        };
+        if to < self.next_block {
+            return Ok(vec![]);
+        }
+
+        let mut ids = vec![];
+        while self.next_block <= to {
+            let end = to.min(self.next_block + MAX_BLOCK_RANGE - 1);
+            let states = match contract.pushed_states(self.next_block, end).await {
+                Ok(states) => states,
+                Err(err) if ids.is_empty() => return Err(err),
+                Err(err) => {
+                    tracing::warn!(
+                        "Failed to get StatePushed events from block {}: {}",
+                        self.next_block,
+                        err
+                    );
+                    break;
+                }
+            };
+
+            for (id, record) in states {
+                tracing::info!(
+                    "New state #{} of {:?}: {:?}",
+                    id.height,
+                    id.uploader,
+                    record
+                );
+                index.insert(id, record).await;
+                ids.push(id);
+            }
+            self.next_block = end + 1;
+        }
+
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ark_bn254::G1Affine;
+    use ark_ec::AffineRepr;
+
+    use super::*;
+
+    fn record(size: u64) -> StateRecord {
+        StateRecord {
+            commitment: G1Affine::generator(),
+            size,
+            k: 2,
+            chunk_size: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn latest_is_the_highest_state_of_the_uploader() {
+        let index = StateIndex::default();
+        let state = |uploader, height| StateId {
+            uploader: Address::repeat_byte(uploader),
+            height,
+        };
+        index.insert(state(0xaa, 3), record(3)).await;
+        index.insert(state(0xaa, 12), record(12)).await;
+        index.insert(state(0xaa, 5), record(5)).await;
+        index.insert(state(0xbb, 20), record(20)).await;
+
+        assert_eq!(
+            index.latest(Address::repeat_byte(0xaa)).await,
+            Some((state(0xaa, 12), record(12)))
+        );
+        // Older versions stay addressable.
+        assert_eq!(index.get(&state(0xaa, 3)).await, Some(record(3)));
+        assert_eq!(index.latest(Address::repeat_byte(0x99)).await, None);
+    }
+}
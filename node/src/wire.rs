@@ -0,0 +1,404 @@
+//! Binary format of chunks and parities sent between nodes.
+//!
+//! JSON carries every element as a decimal string, about three times its
+//! size. Nodes send chunks and parities to each other in this format instead,
+//! with the `Content-Type` and `Accept` of `CONTENT_TYPE`; requests without
+//! them still get JSON, which is handy for debugging.
+//!
+//! A shard is encoded as, integers big-endian:
+//!
+//! | Field     | Size        |                                                      |
+//! |-----------|-------------|------------------------------------------------------|
+//! | magic     | 4           | `SHRD`                                               |
+//! | version   | 1           | `VERSION`                                            |
+//! | kind      | 1           | 0 for a chunk, 1 for a parity                        |
+//! | uploader  | 20          | `StateId`                                            |
+//! | height    | 8           | `StateId`                                            |
+//! | index     | 4           | chunk index, or group of a parity                    |
+//! | elements  | 4 + 32 each | count, then compressed `Fr` (`ark-serialize`)        |
+//! | proofs    | 4 + 32 each | count, then compressed `G1Affine` (`ark-serialize`)  |
+//! | signature | 1 + len     | length (0 or 65), then the signature of a served chunk |
+//!
+//! A signed request body carries the `timestamp` and `signature` of
+//! `identity::sign_payload` in the `X-Timestamp` and `X-Signature` headers.
+
+use anyhow::Result;
+use ark_bn254::{Fr, G1Affine};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use secp256k1::SecretKey;
+use web3::types::{Address, Bytes};
+
+use crate::{
+    identity::{self, SeenMessages, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+    storage::{Chunk, Parity, StateId},
+};
+
+pub const CONTENT_TYPE: &str = "application/vnd.sharded-storage.shard";
+const MAGIC: &[u8; 4] = b"SHRD";
+/// Bumped on any change of the layout, older versions are rejected.
+pub const VERSION: u8 = 1;
+
+pub const CHUNK: u8 = 0;
+pub const PARITY: u8 = 1;
+/// Magic, version, kind, `StateId` and index.
+pub const HEADER_SIZE: usize = 4 + 1 + 1 + 20 + 8 + 4;
+/// Size of a compressed `Fr` or `G1Affine`.
+const ELEMENT_SIZE: usize = 32;
+
+/// The fields of a shard before its counts, see `decode_header`.
+pub struct Header {
+    pub kind: u8,
+    pub state: StateId,
+    pub index: u32,
+}
+
+/// The common layout of chunks and parities.
+struct Shard {
+    kind: u8,
+    state: StateId,
+    index: u32,
+    data: Vec<Fr>,
+    proofs: Vec<G1Affine>,
+    signature: Option<Bytes>,
+}
+
+impl Shard {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(
+            HEADER_SIZE + 8 + (self.data.len() + self.proofs.len()) * ELEMENT_SIZE + 1 + 65,
+        );
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.push(self.kind);
+        buf.extend_from_slice(self.state.uploader.as_bytes());
+        buf.extend_from_slice(&self.state.height.to_be_bytes());
+        buf.extend_from_slice(&self.index.to_be_bytes());
+
+        buf.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        for x in &self.data {
+            x.serialize_compressed(&mut buf)
+                .expect("Writing to a Vec can't fail");
+        }
+        buf.extend_from_slice(&(self.proofs.len() as u32).to_be_bytes());
+        for proof in &self.proofs {
+            proof
+                .serialize_compressed(&mut buf)
+                .expect("Writing to a Vec can't fail");
+        }
+
+        match &self.signature {
+            Some(signature) => {
+                buf.push(signature.0.len() as u8);
+                buf.extend_from_slice(&signature.0);
+            }
+            None => buf.push(0),
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        let mut reader = Reader(buf);
+        let Header { kind, state, index } = decode_header(reader.take(HEADER_SIZE)?)?;
+
+        let data = reader.elements("element", |mut bytes| {
+            Fr::deserialize_compressed(&mut bytes)
+        })?;
+        let proofs = reader.elements("proof", |mut bytes| {
+            G1Affine::deserialize_compressed(&mut bytes)
+        })?;
+
+        let signature = match reader.take(1)?[0] {
+            0 => None,
+            len => Some(Bytes(reader.take(len as usize)?.to_vec())),
+        };
+        if !reader.0.is_empty() {
+            return Err(anyhow::anyhow!(
+                "{} trailing bytes after the shard",
+                reader.0.len()
+            ));
+        }
+
+        Ok(Self {
+            kind,
+            state,
+            index,
+            data,
+            proofs,
+            signature,
+        })
+    }
+}
+
+/// Reads the first `HEADER_SIZE` bytes of a shard.
+pub fn decode_header(buf: &[u8]) -> Result<Header> {
+    let mut reader = Reader(buf);
+    if reader.take(4)? != MAGIC {
+        return Err(anyhow::anyhow!("Not a shard"));
+    }
+    let version = reader.take(1)?[0];
+    if version != VERSION {
+        return Err(anyhow::anyhow!(
+            "Unsupported shard version {}, expected {}",
+            version,
+            VERSION
+        ));
+    }
+
+    Ok(Header {
+        kind: reader.take(1)?[0],
+        state: StateId {
+            uploader: Address::from_slice(reader.take(20)?),
+            height: u64::from_be_bytes(reader.array()?),
+        },
+        index: u32::from_be_bytes(reader.array()?),
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(anyhow::anyhow!("Truncated shard"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// A count followed by as many compressed values, which must be canonical.
+    fn elements<T, E: std::fmt::Display>(
+        &mut self,
+        name: &str,
+        decode: impl Fn(&[u8]) -> Result<T, E>,
+    ) -> Result<Vec<T>> {
+        let count = u32::from_be_bytes(self.array()?) as usize;
+        // Checked before allocating anything for the count.
+        let bytes = self.take(count.saturating_mul(ELEMENT_SIZE))?;
+
+        bytes
+            .chunks(ELEMENT_SIZE)
+            .enumerate()
+            .map(|(i, bytes)| {
+                decode(bytes).map_err(|err| anyhow::anyhow!("Invalid {} {}: {}", name, i, err))
+            })
+            .collect()
+    }
+}
+
+/// Encodes a chunk with the signature of the node serving it, if any.
+pub fn encode_chunk(chunk: &Chunk, signature: Option<&Bytes>) -> Vec<u8> {
+    Shard {
+        kind: CHUNK,
+        state: chunk.state,
+        index: chunk.chunk,
+        data: chunk.data.clone(),
+        proofs: vec![chunk.proof],
+        signature: signature.cloned(),
+    }
+    .encode()
+}
+
+pub fn decode_chunk(buf: &[u8]) -> Result<(Chunk, Option<Bytes>)> {
+    let shard = Shard::decode(buf)?;
+    if shard.kind != CHUNK {
+        return Err(anyhow::anyhow!(
+            "Expected a chunk, got a shard of kind {}",
+            shard.kind
+        ));
+    }
+    let [proof] = shard.proofs[..] else {
+        return Err(anyhow::anyhow!(
+            "A chunk has a single proof, got {}",
+            shard.proofs.len()
+        ));
+    };
+
+    Ok((
+        Chunk {
+            state: shard.state,
+            chunk: shard.index,
+            data: shard.data,
+            proof,
+        },
+        shard.signature,
+    ))
+}
+
+pub fn encode_parity(parity: &Parity) -> Vec<u8> {
+    Shard {
+        kind: PARITY,
+        state: parity.state,
+        index: parity.group,
+        data: parity.data.clone(),
+        proofs: parity.proofs.clone(),
+        signature: None,
+    }
+    .encode()
+}
+
+pub fn decode_parity(buf: &[u8]) -> Result<Parity> {
+    let shard = Shard::decode(buf)?;
+    if shard.kind != PARITY {
+        return Err(anyhow::anyhow!(
+            "Expected a parity, got a shard of kind {}",
+            shard.kind
+        ));
+    }
+    if shard.signature.is_some() {
+        return Err(anyhow::anyhow!("Parities aren't signed"));
+    }
+
+    Ok(Parity {
+        state: shard.state,
+        group: shard.index,
+        data: shard.data,
+        proofs: shard.proofs,
+    })
+}
+
+/// Whether the body of a request or a response is a shard.
+pub fn is_binary(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes() == CONTENT_TYPE.as_bytes())
+}
+
+/// Whether a request asks for a shard rather than JSON.
+pub fn accepts_binary(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| media.split(';').next().unwrap_or_default().trim() == CONTENT_TYPE)
+}
+
+/// A shard response, `204 No Content` if there is none.
+pub fn response(shard: Option<Vec<u8>>) -> Response {
+    match shard {
+        Some(shard) => (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE))],
+            shard,
+        )
+            .into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+/// Sends `shard` signed by this node.
+pub fn signed_request(
+    req: reqwest::RequestBuilder,
+    key: &SecretKey,
+    shard: Vec<u8>,
+) -> Result<reqwest::RequestBuilder> {
+    let (timestamp, signature) = identity::sign_payload(key, &shard)?;
+    let signature = signature
+        .0
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    Ok(req
+        .header(header::CONTENT_TYPE, CONTENT_TYPE)
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(SIGNATURE_HEADER, format!("0x{}", signature))
+        .body(shard))
+}
+
+/// The node that has signed a binary request body, see `signed_request`. A
+/// body that has been received before is rejected.
+pub fn signer(headers: &HeaderMap, body: &[u8], seen: &SeenMessages) -> Result<Address> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| anyhow::anyhow!("Missing {} header", name))
+    };
+    let timestamp = header(TIMESTAMP_HEADER)?
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid timestamp"))?;
+    // `Bytes` only deserializes from 0x-prefixed hex.
+    let signature = serde_json::from_value::<Bytes>(header(SIGNATURE_HEADER)?.into())
+        .map_err(|_| anyhow::anyhow!("Invalid signature"))?;
+
+    let signer = identity::verify_payload(timestamp, body, &signature)?;
+    seen.check(timestamp, body)?;
+
+    Ok(signer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::fixtures::{chunk, parity};
+
+    #[test]
+    fn chunk_round_trips() {
+        assert_eq!(
+            decode_chunk(&encode_chunk(&chunk(), None)).unwrap(),
+            (chunk(), None)
+        );
+
+        let signature = Bytes(vec![1; 65]);
+        assert_eq!(
+            decode_chunk(&encode_chunk(&chunk(), Some(&signature))).unwrap(),
+            (chunk(), Some(signature))
+        );
+    }
+
+    #[test]
+    fn parity_round_trips() {
+        assert_eq!(decode_parity(&encode_parity(&parity())).unwrap(), parity());
+    }
+
+    #[test]
+    fn truncated_shards_are_rejected() {
+        let buf = encode_chunk(&chunk(), Some(&Bytes(vec![1; 65])));
+        for len in 0..buf.len() {
+            assert!(Shard::decode(&buf[..len]).is_err(), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut buf = encode_parity(&parity());
+        buf.push(0);
+        let err = Shard::decode(&buf).err().unwrap();
+        assert!(err.to_string().contains("trailing"), "{}", err);
+    }
+
+    #[test]
+    fn other_versions_and_kinds_are_rejected() {
+        let mut buf = encode_chunk(&chunk(), None);
+        buf[4] = VERSION + 1;
+        assert!(Shard::decode(&buf).is_err());
+
+        assert!(decode_parity(&encode_chunk(&chunk(), None)).is_err());
+        assert!(decode_chunk(&encode_parity(&parity())).is_err());
+    }
+
+    #[test]
+    fn non_canonical_elements_are_rejected() {
+        let mut buf = encode_chunk(&chunk(), None);
+        // The first element, past the header and the count.
+        buf[HEADER_SIZE + 4..][..ELEMENT_SIZE].fill(0xff);
+        assert!(Shard::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn huge_counts_are_rejected_without_allocating() {
+        let mut buf = encode_chunk(&chunk(), None);
+        buf[HEADER_SIZE..][..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = Shard::decode(&buf).err().unwrap();
+        assert!(err.to_string().contains("Truncated"), "{}", err);
+    }
+}
@@ -0,0 +1,134 @@
+use ark_bn254::Fr;
+
+use crate::Domain;
+
+/// Encodes 2^column.k x 2^row.k values (row-major) as a 2^(column.k+1) x
+/// 2^(row.k+1) matrix: every row is extended with `row.encode`, then every
+/// column with `column.encode`.
+///
+/// Every row and every column of the result is a codeword, so lost values can
+/// be repaired from any row or column that has at least half of its values,
+/// without decoding the whole object.
+///
+/// The node doesn't use this layout yet, see "2D layout" in `node/README.md`.
+pub struct Domain2D {
+    pub row: Domain,
+    pub column: Domain,
+}
+
+impl Domain2D {
+
+    pub fn new(row: Domain, column: Domain) -> Self {
+        Domain2D { row, column }
+    }
+
+    /// Number of columns of the encoded matrix.
+    pub fn width(&self) -> usize {
+        2 << self.row.k
+    }
+
+    /// Number of rows of the encoded matrix.
+    pub fn height(&self) -> usize {
+        2 << self.column.k
+    }
+
+    /// The original values can be found at even rows and even columns. Returns
+    /// `None` unless there are exactly `2^column.k * 2^row.k` values:
+    ///
+    /// ```
+    /// use shamir_ss::{Domain, Domain2D};
+    /// use ark_bn254::Fr;
+    /// let d = Domain2D::new(Domain::from_k(1), Domain::from_k(1));
+    /// let v : Vec<Fr> = vec![1, 2, 3, 4].iter().map(|&x| Fr::from(x)).collect();
+    /// let c = d.encode(v.clone()).unwrap();
+    /// assert_eq!(c.len(), 16);
+    /// assert_eq!(v, vec![c[0], c[2], c[8], c[10]]);
+    /// assert!(d.encode(v[..3].to_vec()).is_none());
+    /// ```
+    pub fn encode(&self, value: Vec<Fr>) -> Option<Vec<Fr>> {
+        let w = 1 << self.row.k;
+        if value.len() != w << self.column.k {
+            return None;
+        }
+        let rows: Vec<Vec<Fr>> = value.chunks(w).map(|row| self.row.encode(row.to_vec())).collect();
+
+        let columns: Vec<Vec<Fr>> = (0..self.width()).map(|j| {
+            self.column.encode(rows.iter().map(|row| row[j]).collect())
+        }).collect();
+
+        Some((0..self.height()).flat_map(|i| columns.iter().map(move |column| column[i])).collect())
+    }
+
+    /// Fills in the missing values of `code` by repeatedly decoding the rows
+    /// and columns that have enough values, returns whether all the values
+    /// are known in the end. `code` must have `width() * height()` values,
+    /// otherwise nothing is repaired:
+    ///
+    /// ```
+    /// use shamir_ss::{Domain, Domain2D};
+    /// use ark_bn254::Fr;
+    /// let d = Domain2D::new(Domain::from_k(1), Domain::from_k(1));
+    /// let v : Vec<Fr> = vec![1, 2, 3, 4].iter().map(|&x| Fr::from(x)).collect();
+    /// let c : Vec<Option<Fr>> = d.encode(v).unwrap().into_iter().map(|x| Some(x)).collect();
+    /// let mut lost = c.clone();
+    /// // Three values of the first row and of the first column
+    /// for i in [0, 1, 2, 4, 8] {
+    ///     lost[i] = None;
+    /// }
+    /// assert!(d.repair(&mut lost));
+    /// assert_eq!(c, lost);
+    /// ```
+    pub fn repair(&self, code: &mut [Option<Fr>]) -> bool {
+        let (w, h) = (self.width(), self.height());
+        if code.len() != w * h {
+            return false;
+        }
+        loop {
+            let mut progress = false;
+
+            for i in 0..h {
+                let row = code[i * w..(i + 1) * w].to_vec();
+                if let Some(full) = Self::repair_line(&self.row, &row) {
+                    code[i * w..(i + 1) * w].iter_mut().zip(full).for_each(|(x, y)| *x = Some(y));
+                    progress = true;
+                }
+            }
+
+            for j in 0..w {
+                let column: Vec<_> = (0..h).map(|i| code[i * w + j]).collect();
+                if let Some(full) = Self::repair_line(&self.column, &column) {
+                    for (i, y) in full.into_iter().enumerate() {
+                        code[i * w + j] = Some(y);
+                    }
+                    progress = true;
+                }
+            }
+
+            if !progress {
+                return code.iter().all(|x| x.is_some());
+            }
+        }
+    }
+
+    /// The whole line if some of it is missing and it can be recovered.
+    fn repair_line(domain: &Domain, line: &Vec<Option<Fr>>) -> Option<Vec<Fr>> {
+        if line.iter().all(|x| x.is_some()) {
+            return None;
+        }
+        domain.decode(line).map(|value| domain.encode(value))
+    }
+
+    /// Repairs `code` and takes the original values out of it.
+    pub fn decode(&self, code: &[Option<Fr>]) -> Option<Vec<Fr>> {
+        let mut code = code.to_vec();
+        if !self.repair(&mut code) {
+            return None;
+        }
+
+        let w = self.width();
+        Some((0..self.height()).step_by(2).flat_map(|i| {
+            code[i * w..(i + 1) * w].iter().step_by(2).map(|x| x.unwrap()).collect::<Vec<_>>()
+        }).collect())
+    }
+
+}
@@ -1,9 +1,17 @@
 use std::iter::repeat_with;
 
 // use ark_poly::domain::radix2::Radix2EvaluationDomain;
-use ark_ff::fields::Field;
+use ark_ff::fields::{FftField, Field};
 use ark_bn254::Fr;
 
+pub use domain2d::Domain2D;
+pub use local::LocalGroups;
+pub use secret::SecretSharing;
+
+mod domain2d;
+mod local;
+mod secret;
+
 /// Defines a set of points to evaluate the polynomial in.
 pub struct Domain {
     pub g: Fr,
@@ -12,31 +20,62 @@ pub struct Domain {
 }
 
 impl Domain {
+    /// The largest `k` of `bit_reversed`: Fr has 2^28-th roots of unity and
+    /// the domain has 2^(k+1) points.
+    pub const MAX_K: usize = 27;
 
     /// If you want to encode a vector of 2^k filed elements, use
     /// `Domain::from_k(k)`.
     pub fn from_k(k: usize) -> Self {
         // This is hardcoded for ark_bn254::Fr
         let n : u64 = 28;
-        /// 5 must generate the whole Fr^*
+        // 5 must generate the whole Fr^*
         let g = Fr::from(5).pow(vec![n - k as u64].as_slice());
         Self::new(g, k)
     }
 
+    /// Like `from_k`, but the points are the 2^(k+1)-th roots of unity in
+    /// bit-reversed order, so that every aligned block of 2^j positions is a
+    /// coset {x : x^(2^j) = a}, which is what `kzg::fk20` proves. Panics if
+    /// `k > MAX_K`.
+    ///
+    /// ```
+    /// use shamir_ss::Domain;
+    /// use ark_ff::fields::Field;
+    /// let d = Domain::bit_reversed(2);
+    /// assert_eq!(d.degrees[2].square(), d.degrees[3].square());
+    /// assert_ne!(d.degrees[1].square(), d.degrees[2].square());
+    /// ```
+    pub fn bit_reversed(k: usize) -> Self {
+        let n = 1 << (k + 1);
+        let g = Fr::get_root_of_unity(n).unwrap();
+        let degrees = (0..n).map(|i| {
+            let j = i.reverse_bits() >> (64 - (k + 1));
+            g.pow([j])
+        }).collect();
+        Domain { g, k, degrees }
+    }
+
     /// `g` is the generator of the domain group <g>, k defines the number of
     /// elements 2^k to evaluate the polynomial in.
     pub fn new(g: Fr, k: usize) -> Self {
-        let mut acc = g.clone();
+        let mut acc = g;
         let degrees = repeat_with(|| {
             acc *= g;
-            acc.clone()
+            acc
         }).take(1 << (k + 1)).collect();
         Domain { g, k, degrees }
     }
 
+    /// The points the original values sit at, i.e. the even points of the
+    /// domain.
+    pub fn data_points(&self) -> Vec<Fr> {
+        self.degrees.iter().cloned().step_by(2).take(1 << self.k).collect()
+    }
+
     /// Takes a list of (x, y) points, produces the list of polynomial's
     /// coefficients
-    fn interpolate(ps: &Vec<(Fr, Fr)>, xs: &Vec<Fr>) -> Vec<Fr> {
+    fn interpolate(ps: &[(Fr, Fr)], xs: &[Fr]) -> Vec<Fr> {
         // computes δ_j(x)
         let delta = |j: usize, x: &Fr| -> Fr {
             let (x_j, _y_j) = ps[j];
@@ -59,7 +98,8 @@ impl Domain {
     ///
     /// The original values can be found inside the codeword on even
     /// positions. The odd positions are filled with the other correlated data
-    /// (polynomial evaluations that serve as "checksums"):
+    /// (polynomial evaluations that serve as "checksums"). Every share of the
+    /// codeword thus reveals the data, `SecretSharing` hides it:
     ///
     /// ```
     /// use shamir_ss::Domain;
@@ -74,14 +114,14 @@ impl Domain {
         let even = (0..).step_by(2).take(1 << self.k);
         let odd  = (1..).step_by(2).take(1 << self.k);
 
-        let known = even.zip(value.iter()).map(|(i, y_i)| {
-            (self.degrees[i], y_i.clone())
+        let known: Vec<_> = even.zip(value.iter()).map(|(i, y_i)| {
+            (self.degrees[i], *y_i)
         }).collect();
-        let wanted = odd.map(|i| self.degrees[i]).collect();
+        let wanted: Vec<_> = odd.map(|i| self.degrees[i]).collect();
         let extra = Self::interpolate(&known, &wanted);
         let mut res = vec![];
         // intersperse even and odd
-        for (e, o) in value.into_iter().zip(extra.into_iter()) {
+        for (e, o) in value.into_iter().zip(extra) {
             res.push(e);
             res.push(o);
         }
@@ -133,9 +173,9 @@ impl Domain {
     /// ```
     pub fn decode(&self, code: &Vec<Option<Fr>>) -> Option<Vec<Fr>> {
         let known : Vec<_> = self.degrees.iter().zip(code).flat_map(|(x, y)| {
-            y.map(|y| (x.clone(), y))
+            y.map(|y| (*x, y))
         }).collect();
-        let wanted = self.degrees.iter().cloned().step_by(2).take(1 << self.k).collect();
+        let wanted = self.data_points();
         if known.len() >= (1 << self.k) {
             Some(Self::interpolate(&known, &wanted))
         } else {
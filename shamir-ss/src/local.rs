@@ -0,0 +1,83 @@
+use std::ops::Range;
+
+use ark_bn254::Fr;
+
+/// Locally repairable groups on top of a `Domain` codeword.
+///
+/// The codeword is split into shards of `shard_size` values and every
+/// `group_size` consecutive shards get a parity shard, their sum. A single
+/// lost shard is then rebuilt from the rest of its group and the parity, i.e.
+/// from `group_size` shards instead of the half of the codeword `decode`
+/// needs.
+pub struct LocalGroups {
+    pub shard_size: usize,
+    pub group_size: usize,
+}
+
+impl LocalGroups {
+
+    pub fn new(shard_size: usize, group_size: usize) -> Self {
+        LocalGroups { shard_size, group_size }
+    }
+
+    /// The group `shard` belongs to.
+    pub fn group(&self, shard: usize) -> usize {
+        shard / self.group_size
+    }
+
+    /// The shards of the group `group`, of `num_shards` in total.
+    pub fn shards(&self, group: usize, num_shards: usize) -> Range<usize> {
+        let start = group * self.group_size;
+        start.min(num_shards)..(start + self.group_size).min(num_shards)
+    }
+
+    /// Parity shards of every group of `code`:
+    ///
+    /// ```
+    /// use shamir_ss::LocalGroups;
+    /// use ark_bn254::Fr;
+    /// let l = LocalGroups::new(2, 2);
+    /// let c : Vec<Fr> = (1..=8).map(|x| Fr::from(x)).collect();
+    /// let p = l.parities(&c);
+    /// assert_eq!(p, vec![vec![Fr::from(1 + 3), Fr::from(2 + 4)], vec![Fr::from(5 + 7), Fr::from(6 + 8)]]);
+    /// ```
+    pub fn parities(&self, code: &[Fr]) -> Vec<Vec<Fr>> {
+        code.chunks(self.shard_size * self.group_size).map(|group| {
+            let mut parity = vec![Fr::from(0); self.shard_size];
+            for shard in group.chunks(self.shard_size) {
+                for (p, x) in parity.iter_mut().zip(shard) {
+                    *p += x;
+                }
+            }
+            parity
+        }).collect()
+    }
+
+    /// Rebuilds the only missing shard of a group from the others and the
+    /// parity. Returns `None` if more than one shard is missing:
+    ///
+    /// ```
+    /// use shamir_ss::LocalGroups;
+    /// use ark_bn254::Fr;
+    /// let l = LocalGroups::new(2, 2);
+    /// let c : Vec<Fr> = (1..=8).map(|x| Fr::from(x)).collect();
+    /// let p = l.parities(&c);
+    /// let group = vec![None, Some(c[6..8].to_vec())];
+    /// assert_eq!(l.repair(&group, &p[1]), Some(c[4..6].to_vec()));
+    /// assert_eq!(l.repair(&vec![None, None], &p[1]), None);
+    /// ```
+    pub fn repair(&self, group: &[Option<Vec<Fr>>], parity: &[Fr]) -> Option<Vec<Fr>> {
+        if group.iter().filter(|shard| shard.is_none()).count() != 1 {
+            return None;
+        }
+
+        let mut res = parity.to_vec();
+        for shard in group.iter().flatten() {
+            for (r, x) in res.iter_mut().zip(shard) {
+                *r -= x;
+            }
+        }
+        Some(res)
+    }
+
+}
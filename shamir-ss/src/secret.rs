@@ -0,0 +1,103 @@
+use ark_bn254::Fr;
+use ark_ff::UniformRand;
+use rand::Rng;
+
+use crate::Domain;
+
+/// Packed Shamir secret sharing over the points of a `Domain`.
+///
+/// Unlike `Domain::encode`, the values don't appear in the codeword: they are
+/// the evaluations of a random polynomial of degree below 2^k at points
+/// outside of the domain, `5 * x` for the first even points `x`. The
+/// polynomial is pinned down by `t` random values, so any `t` shares reveal
+/// nothing about the values, while any 2^k of the 2^(k+1) shares still
+/// recover them. The price is that a codeword packs only 2^k - t values.
+pub struct SecretSharing {
+    pub domain: Domain,
+    pub t: usize,
+    pub secret_points: Vec<Fr>,
+}
+
+impl SecretSharing {
+
+    /// Returns `None` unless `0 < t < 2^k`.
+    pub fn new(domain: Domain, t: usize) -> Option<Self> {
+        if t == 0 || t >= 1 << domain.k {
+            return None;
+        }
+
+        let secret_points: Vec<Fr> = domain.data_points().into_iter().take((1 << domain.k) - t).map(|x| {
+            x * Fr::from(5)
+        }).collect();
+        if secret_points.iter().any(|x| domain.degrees.contains(x)) {
+            return None;
+        }
+
+        Some(SecretSharing { domain, t, secret_points })
+    }
+
+    /// Number of values a codeword packs.
+    pub fn capacity(&self) -> usize {
+        self.secret_points.len()
+    }
+
+    /// Takes 2^k - t values, produces 2^(k+1) shares of them. The first `t`
+    /// shares are uniformly random, the rest follow. `None` unless there are
+    /// exactly `capacity()` values:
+    ///
+    /// ```
+    /// use shamir_ss::{Domain, SecretSharing};
+    /// use ark_bn254::Fr;
+    /// let s = SecretSharing::new(Domain::from_k(2), 2).unwrap();
+    /// let v : Vec<Fr> = vec![1, 2].iter().map(|&x| Fr::from(x)).collect();
+    /// let c = s.encode(v.clone(), &mut rand::thread_rng()).unwrap();
+    /// assert_eq!(c.len(), 8);
+    /// assert!(v.iter().all(|x| !c.contains(x)));
+    /// // Sharing the same values twice gives unrelated shares.
+    /// assert_ne!(c, s.encode(v.clone(), &mut rand::thread_rng()).unwrap());
+    /// // Missing values would be silently replaced, extra ones dropped.
+    /// assert!(s.encode(v[..1].to_vec(), &mut rand::thread_rng()).is_none());
+    /// assert!(s.encode(vec![Fr::from(1); 3], &mut rand::thread_rng()).is_none());
+    /// ```
+    pub fn encode<R: Rng>(&self, value: Vec<Fr>, rng: &mut R) -> Option<Vec<Fr>> {
+        if value.len() != self.capacity() {
+            return None;
+        }
+        let mut res: Vec<Fr> = (0..self.t).map(|_| Fr::rand(rng)).collect();
+
+        let known: Vec<_> = self.secret_points.iter().cloned().zip(value)
+            .chain(self.domain.degrees.iter().cloned().zip(res.iter().cloned()))
+            .collect();
+        res.extend(Domain::interpolate(&known, &self.domain.degrees[self.t..]));
+        Some(res)
+    }
+
+    /// Takes the 2^(k+1) shares, no more than half of which are unknown/lost,
+    /// and recovers the values, same as `Domain::decode`:
+    ///
+    /// ```
+    /// use shamir_ss::{Domain, SecretSharing};
+    /// use ark_bn254::Fr;
+    /// let s = SecretSharing::new(Domain::from_k(2), 2).unwrap();
+    /// let v : Vec<Fr> = vec![1, 2].iter().map(|&x| Fr::from(x)).collect();
+    /// let mut c : Vec<Option<Fr>> = s.encode(v.clone(), &mut rand::thread_rng()).unwrap().into_iter().map(|x| Some(x)).collect();
+    /// c[0] = None;
+    /// c[3] = None;
+    /// c[4] = None;
+    /// c[7] = None;
+    /// assert_eq!(Some(v), s.decode(&c));
+    /// c[5] = None;
+    /// assert_eq!(None, s.decode(&c));
+    /// ```
+    pub fn decode(&self, code: &Vec<Option<Fr>>) -> Option<Vec<Fr>> {
+        let known : Vec<_> = self.domain.degrees.iter().zip(code).flat_map(|(x, y)| {
+            y.map(|y| (*x, y))
+        }).take(1 << self.domain.k).collect();
+        if known.len() >= (1 << self.domain.k) {
+            Some(Domain::interpolate(&known, &self.secret_points))
+        } else {
+            None
+        }
+    }
+
+}
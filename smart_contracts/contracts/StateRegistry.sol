@@ -5,14 +5,415 @@ pragma solidity ^0.8.9;
 // import "hardhat/console.sol";
 
 contract StateRegistry {
+    // A state is a BN254 G1 commitment (x, y as 32-byte big-endian words, in
+    // the form used by the ecAdd/ecMul/ecPairing precompiles) followed by the
+    // object metadata: uint64 size, uint32 k and uint32 chunk size.
+    uint256 public constant STATE_LENGTH = 80;
+
+    // Stake a storage node must lock to be assigned chunks.
+    uint256 public constant MIN_STAKE = 1 ether;
+    // Time between a node's exit request and the release of its stake.
+    uint256 public constant UNBONDING_PERIOD = 7 days;
+
+    // BN254 scalar and base field moduli.
+    uint256 constant R = 21888242871839275222246405745257275088548364400416034343698204186575808495617;
+    uint256 constant P = 21888242871839275222246405745257275088696311157297823662689037894645226208583;
+    // Primitive 2^28-th root of unity of the scalar field, the one `Domain::bit_reversed` uses.
+    uint256 constant ROOT_OF_UNITY = 19103219067921713944291392827692070036145651957329286315305642004821462161904;
+    uint256 constant TWO_ADICITY = 28;
+    // Generators of G1 and G2 in the precompile encodings.
+    uint256 constant G1_X = 1;
+    uint256 constant G1_Y = 2;
+    uint256 constant G2_X_IM = 11559732032986387107991004021392285783925812861821192530917403151452391805634;
+    uint256 constant G2_X_RE = 10857046999023057135944570762232829481370756359578518086990519993285655852781;
+    uint256 constant G2_Y_IM = 4082367875863433681332203403145435568316851327593401208105741076214120093531;
+    uint256 constant G2_Y_RE = 8495653923123431417604973247489272438418190587263600148770280649306958101930;
+
+    address public immutable owner;
+
+    // KZG setup slashing evidence is checked against, set once by the owner:
+    // [τ^i]_1 as (x, y) and [τ^i]_2 as (x_im, x_re, y_im, y_re), the
+    // encodings of the ecAdd/ecMul/ecPairing precompiles. `initVerifier`
+    // checks that they are powers of the same τ, and nodes check that they
+    // are the ones of their own setup against `verifierHash`.
+    uint256[2][] public g1Powers;
+    uint256[4][] public g2Powers;
+    // keccak256(abi.encode(g1Powers, g2Powers)).
+    bytes32 public verifierHash;
+
+    // Block each commitment to slashing evidence was made in, see `commitEvidence`.
+    mapping(bytes32 => uint256) public evidenceCommitments;
+
     mapping(address => bytes[]) public state;
 
+    // Storage nodes, identified by the address of their key. The endpoint is
+    // the `host:port` the node serves its HTTP API on.
+    struct Node {
+        string endpoint;
+        uint256 stake;
+        // Zero while the node is active, the time the stake can be withdrawn
+        // at once it has asked to exit.
+        uint256 unlockTime;
+    }
+
+    mapping(address => Node) public nodes;
+    address[] public nodeList;
+    // Position of a node in `nodeList`, plus one.
+    mapping(address => uint256) private nodeIndex;
+
+    event StatePushed(address indexed uploader, uint256 indexed height, bytes state);
+    event NodeRegistered(address indexed node, string endpoint, uint256 stake);
+    event NodeExiting(address indexed node, uint256 unlockTime);
+    event NodeWithdrawn(address indexed node, uint256 stake);
+    event NodeSlashed(address indexed node, address indexed reporter, uint256 stake);
+    event VerifierInitialized(bytes32 hash, uint256 g1Length, uint256 g2Length);
+    event EvidenceCommitted(bytes32 indexed commitment, address indexed reporter);
+
+    // A chunk of a state served by a node that doesn't match the commitment.
+    struct Evidence {
+        address node;
+        address uploader;
+        uint64 height;
+        uint32 chunk;
+        uint256[] data;
+        // Multi-point KZG proof of `data`, as served.
+        uint256[2] proof;
+        // Coefficients of the polynomial taking `data` at the points of the chunk.
+        uint256[] interpolation;
+        // Signature of the node over `chunkDigest`, `r || s || v` with v in {0, 1}.
+        bytes signature;
+    }
+
+    constructor() {
+        owner = msg.sender;
+    }
+
     function pushState(bytes memory _state) public {
+        require(_state.length == STATE_LENGTH, "StateRegistry: invalid state length");
         state[msg.sender].push(_state);
+        emit StatePushed(msg.sender, state[msg.sender].length - 1, _state);
     }
 
     function getStateHeight(address _address) public view returns (uint) {
         return state[_address].length;
     }
 
-}
\ No newline at end of file
+    function getCommitment(address _address, uint256 _index) public view returns (uint256 x, uint256 y) {
+        bytes memory s = state[_address][_index];
+        assembly {
+            x := mload(add(s, 32))
+            y := mload(add(s, 64))
+        }
+    }
+
+    // Registers the sender as a storage node or updates its endpoint, adding
+    // the sent value to its stake.
+    function registerNode(string calldata _endpoint) public payable {
+        require(bytes(_endpoint).length > 0, "StateRegistry: empty endpoint");
+        Node storage node = nodes[msg.sender];
+        require(node.unlockTime == 0, "StateRegistry: node is exiting");
+        require(node.stake + msg.value >= MIN_STAKE, "StateRegistry: stake is too low");
+
+        if (nodeIndex[msg.sender] == 0) {
+            nodeList.push(msg.sender);
+            nodeIndex[msg.sender] = nodeList.length;
+        }
+        node.endpoint = _endpoint;
+        node.stake += msg.value;
+
+        emit NodeRegistered(msg.sender, _endpoint, node.stake);
+    }
+
+    // Stops assigning chunks to the sender, its stake is locked for
+    // `UNBONDING_PERIOD` more.
+    function exitNode() public {
+        Node storage node = nodes[msg.sender];
+        require(node.stake > 0, "StateRegistry: unknown node");
+        require(node.unlockTime == 0, "StateRegistry: node is exiting");
+
+        node.unlockTime = block.timestamp + UNBONDING_PERIOD;
+
+        emit NodeExiting(msg.sender, node.unlockTime);
+    }
+
+    function withdrawStake() public {
+        Node storage node = nodes[msg.sender];
+        require(node.unlockTime != 0, "StateRegistry: node is not exiting");
+        require(block.timestamp >= node.unlockTime, "StateRegistry: stake is locked");
+
+        uint256 stake = node.stake;
+        removeNode(msg.sender);
+
+        emit NodeWithdrawn(msg.sender, stake);
+        (bool sent, ) = payable(msg.sender).call{value: stake}("");
+        require(sent, "StateRegistry: transfer failed");
+    }
+
+    function isActiveNode(address _node) public view returns (bool) {
+        Node storage node = nodes[_node];
+        return node.unlockTime == 0 && node.stake >= MIN_STAKE;
+    }
+
+    // Nodes chunks can be placed on, with their endpoints.
+    function getActiveNodes() public view returns (address[] memory addresses, string[] memory endpoints) {
+        uint256 count = 0;
+        for (uint256 i = 0; i < nodeList.length; i++) {
+            if (isActiveNode(nodeList[i])) {
+                count++;
+            }
+        }
+
+        addresses = new address[](count);
+        endpoints = new string[](count);
+        uint256 j = 0;
+        for (uint256 i = 0; i < nodeList.length; i++) {
+            if (isActiveNode(nodeList[i])) {
+                addresses[j] = nodeList[i];
+                endpoints[j] = nodes[nodeList[i]].endpoint;
+                j++;
+            }
+        }
+    }
+
+    // Sets the setup once. The powers must start at the generators and be
+    // powers of the same non-zero τ, which is checked with pairings:
+    // e([τ^i]_1, [1]_2) = e([τ^(i-1)]_1, [τ]_2) and
+    // e([1]_1, [τ^j]_2) = e([τ^(j-1)]_1, [τ]_2).
+    function initVerifier(uint256[2][] calldata _g1Powers, uint256[4][] calldata _g2Powers) public {
+        require(msg.sender == owner, "StateRegistry: caller is not the owner");
+        require(g2Powers.length == 0, "StateRegistry: verifier is already set");
+        require(_g1Powers.length > 0 && _g2Powers.length > 1, "StateRegistry: setup is too small");
+        require(_g1Powers.length + 1 >= _g2Powers.length, "StateRegistry: too few G1 powers");
+        require(
+            _g1Powers[0][0] == G1_X && _g1Powers[0][1] == G1_Y &&
+            _g2Powers[0][0] == G2_X_IM && _g2Powers[0][1] == G2_X_RE &&
+            _g2Powers[0][2] == G2_Y_IM && _g2Powers[0][3] == G2_Y_RE,
+            "StateRegistry: setup doesn't start at the generators"
+        );
+        require(
+            _g2Powers[1][0] != 0 || _g2Powers[1][1] != 0 || _g2Powers[1][2] != 0 || _g2Powers[1][3] != 0,
+            "StateRegistry: setup has tau = 0"
+        );
+
+        for (uint256 i = 1; i < _g1Powers.length; i++) {
+            require(
+                pairing(_g1Powers[i], _g2Powers[0], negate(_g1Powers[i - 1]), _g2Powers[1]),
+                "StateRegistry: inconsistent G1 powers"
+            );
+        }
+        for (uint256 j = 2; j < _g2Powers.length; j++) {
+            require(
+                pairing(_g1Powers[0], _g2Powers[j], negate(_g1Powers[j - 1]), _g2Powers[1]),
+                "StateRegistry: inconsistent G2 powers"
+            );
+        }
+
+        for (uint256 i = 0; i < _g1Powers.length; i++) {
+            g1Powers.push(_g1Powers[i]);
+        }
+        for (uint256 i = 0; i < _g2Powers.length; i++) {
+            g2Powers.push(_g2Powers[i]);
+        }
+        verifierHash = keccak256(abi.encode(_g1Powers, _g2Powers));
+
+        emit VerifierInitialized(verifierHash, _g1Powers.length, _g2Powers.length);
+    }
+
+    function getVerifier() public view returns (bytes32 hash, uint256 g1Length, uint256 g2Length) {
+        return (verifierHash, g1Powers.length, g2Powers.length);
+    }
+
+    // First step of `slash`: the reporter commits to its evidence, so that
+    // whoever copies the evidence from the `slash` transaction is too late to
+    // claim the reward. See `evidenceCommitment`.
+    function commitEvidence(bytes32 _commitment) public {
+        require(evidenceCommitments[_commitment] == 0, "StateRegistry: already committed");
+        evidenceCommitments[_commitment] = block.number;
+
+        emit EvidenceCommitted(_commitment, msg.sender);
+    }
+
+    function evidenceCommitment(address _reporter, Evidence calldata _evidence) public view returns (bytes32) {
+        return keccak256(abi.encode(_reporter, _evidence.node, chunkDigest(_evidence)));
+    }
+
+    // Takes the whole stake of a node that has signed a chunk inconsistent
+    // with the commitment of its state. Half of it goes to the reporter, who
+    // must have committed to the evidence in an earlier block, the rest stays
+    // locked in the contract.
+    function slash(Evidence calldata _evidence) public {
+        Node storage node = nodes[_evidence.node];
+        require(node.stake > 0, "StateRegistry: unknown node");
+        uint256 committed = evidenceCommitments[evidenceCommitment(msg.sender, _evidence)];
+        require(committed != 0 && committed < block.number, "StateRegistry: evidence is not committed");
+        require(chunkSigner(_evidence) == _evidence.node, "StateRegistry: invalid signature");
+        require(!verifyChunk(_evidence), "StateRegistry: chunk matches the commitment");
+
+        uint256 stake = node.stake;
+        removeNode(_evidence.node);
+
+        emit NodeSlashed(_evidence.node, msg.sender, stake);
+        (bool sent, ) = payable(msg.sender).call{value: stake / 2}("");
+        require(sent, "StateRegistry: transfer failed");
+    }
+
+    // What a node signs when serving a chunk. It names the chain and the
+    // registry, so that a chunk served for one deployment isn't evidence in another.
+    function chunkDigest(Evidence calldata _evidence) public view returns (bytes32) {
+        return keccak256(abi.encodePacked(
+            "\x19Sharded Storage chunk:\n",
+            block.chainid,
+            address(this),
+            _evidence.uploader,
+            _evidence.height,
+            _evidence.chunk,
+            _evidence.data,
+            _evidence.proof
+        ));
+    }
+
+    function chunkSigner(Evidence calldata _evidence) internal view returns (address) {
+        require(_evidence.signature.length == 65, "StateRegistry: invalid signature length");
+        bytes32 r = bytes32(_evidence.signature[0:32]);
+        bytes32 s = bytes32(_evidence.signature[32:64]);
+        uint8 v = uint8(_evidence.signature[64]) + 27;
+
+        return ecrecover(chunkDigest(_evidence), v, r, s);
+    }
+
+    // Checks the proof of a chunk like `kzg::verify_multi`. The points of a
+    // chunk of l values form a coset {x : x^l = a}, so the check is
+    // e(C - [I(τ)]_1 + a π, [1]_2) = e(π, [τ^l]_2).
+    function verifyChunk(Evidence calldata _evidence) public view returns (bool) {
+        require(_evidence.height < state[_evidence.uploader].length, "StateRegistry: unknown state");
+        bytes memory s = state[_evidence.uploader][_evidence.height];
+        uint256[2] memory commitment;
+        uint256 meta;
+        assembly {
+            mstore(commitment, mload(add(s, 32)))
+            mstore(add(commitment, 32), mload(add(s, 64)))
+            meta := mload(add(s, 96))
+        }
+        uint256 logN = uint256(uint32(meta >> 160)) + 1;
+        uint256 chunkSize = uint256(uint32(meta >> 128));
+        require(logN <= TWO_ADICITY, "StateRegistry: domain is too large");
+
+        uint256 l = chunkSize < (1 << logN) ? chunkSize : (1 << logN);
+        require(l > 0 && l & (l - 1) == 0, "StateRegistry: chunk size is not a power of two");
+        require(_evidence.chunk < (1 << logN) / l, "StateRegistry: chunk is out of range");
+        require(
+            _evidence.data.length == l && _evidence.interpolation.length == l,
+            "StateRegistry: invalid chunk length"
+        );
+        require(g1Powers.length >= l && g2Powers.length > l, "StateRegistry: setup is too small");
+
+        uint256 a = checkInterpolation(_evidence, logN);
+
+        // C - [I(τ)]_1 + a π
+        uint256[2] memory lhs = ecAdd(commitment, negate(commit(_evidence.interpolation)));
+        lhs = ecAdd(lhs, ecMul(_evidence.proof, a));
+
+        return pairing(lhs, g2Powers[0], negate(_evidence.proof), g2Powers[l]);
+    }
+
+    // Checks that the interpolation takes the data at the points of the chunk,
+    // ω^bitrev(chunk * l + j) for the primitive 2^logN-th root ω, and returns
+    // the constant of the coset, x^l for any of the points.
+    function checkInterpolation(Evidence calldata _evidence, uint256 logN) internal pure returns (uint256) {
+        uint256 l = _evidence.data.length;
+        uint256 omega = expmod(ROOT_OF_UNITY, 1 << (TWO_ADICITY - logN));
+
+        for (uint256 j = 0; j < l; j++) {
+            uint256 x = expmod(omega, bitReverse(_evidence.chunk * l + j, logN));
+            uint256 y = 0;
+            for (uint256 i = l; i > 0; i--) {
+                y = addmod(mulmod(y, x, R), _evidence.interpolation[i - 1], R);
+            }
+            require(
+                _evidence.data[j] < R && y == _evidence.data[j],
+                "StateRegistry: interpolation doesn't match the data"
+            );
+        }
+
+        return expmod(expmod(omega, bitReverse(_evidence.chunk * l, logN)), l);
+    }
+
+    function commit(uint256[] calldata _poly) internal view returns (uint256[2] memory res) {
+        for (uint256 i = 0; i < _poly.length; i++) {
+            res = ecAdd(res, ecMul(g1Powers[i], _poly[i]));
+        }
+    }
+
+    function bitReverse(uint256 _i, uint256 _bits) internal pure returns (uint256 res) {
+        for (uint256 b = 0; b < _bits; b++) {
+            res = (res << 1) | ((_i >> b) & 1);
+        }
+    }
+
+    function expmod(uint256 _base, uint256 _exp) internal pure returns (uint256 res) {
+        res = 1;
+        for (; _exp > 0; _exp >>= 1) {
+            if (_exp & 1 == 1) {
+                res = mulmod(res, _base, R);
+            }
+            _base = mulmod(_base, _base, R);
+        }
+    }
+
+    function negate(uint256[2] memory _p) internal pure returns (uint256[2] memory) {
+        if (_p[0] == 0 && _p[1] == 0) {
+            return _p;
+        }
+        return [_p[0], P - (_p[1] % P)];
+    }
+
+    function ecAdd(uint256[2] memory _a, uint256[2] memory _b) internal view returns (uint256[2] memory res) {
+        uint256[4] memory input = [_a[0], _a[1], _b[0], _b[1]];
+        bool ok;
+        assembly {
+            ok := staticcall(gas(), 6, input, 128, res, 64)
+        }
+        require(ok, "StateRegistry: ecAdd failed");
+    }
+
+    function ecMul(uint256[2] memory _p, uint256 _s) internal view returns (uint256[2] memory res) {
+        uint256[3] memory input = [_p[0], _p[1], _s];
+        bool ok;
+        assembly {
+            ok := staticcall(gas(), 7, input, 96, res, 64)
+        }
+        require(ok, "StateRegistry: ecMul failed");
+    }
+
+    // e(a1, b1) * e(a2, b2) == 1
+    function pairing(
+        uint256[2] memory _a1,
+        uint256[4] memory _b1,
+        uint256[2] memory _a2,
+        uint256[4] memory _b2
+    ) internal view returns (bool) {
+        uint256[12] memory input = [
+            _a1[0], _a1[1], _b1[0], _b1[1], _b1[2], _b1[3],
+            _a2[0], _a2[1], _b2[0], _b2[1], _b2[2], _b2[3]
+        ];
+        uint256[1] memory res;
+        bool ok;
+        assembly {
+            ok := staticcall(gas(), 8, input, 384, res, 32)
+        }
+        require(ok, "StateRegistry: ecPairing failed");
+        return res[0] == 1;
+    }
+
+    function removeNode(address _node) internal {
+        uint256 index = nodeIndex[_node] - 1;
+        address last = nodeList[nodeList.length - 1];
+        nodeList[index] = last;
+        nodeIndex[last] = index + 1;
+        nodeList.pop();
+
+        delete nodeIndex[_node];
+        delete nodes[_node];
+    }
+
+}
@@ -4,8 +4,12 @@
 // You can also run a script with `npx hardhat run <script>`. If you do that, Hardhat
 // will compile your contracts, add the Hardhat Runtime Environment's members to the
 // global scope, and execute the script.
+const fs = require("fs");
 const hre = require("hardhat");
 
+// KZG setup slashing evidence is checked against, see `kzg10 verifier`.
+const VERIFIER = process.env.VERIFIER || "../res/verifier.json";
+
 async function main() {
   const StateRegistry = await hre.ethers.getContractFactory("StateRegistry");
   const stateRegistry = await StateRegistry.deploy();
@@ -15,6 +19,14 @@ async function main() {
   console.log(
     `StateRegistry deployed to ${stateRegistry.address}`
   );
+
+  if (fs.existsSync(VERIFIER)) {
+    const { g1Powers, g2Powers } = JSON.parse(fs.readFileSync(VERIFIER));
+    await (await stateRegistry.initVerifier(g1Powers, g2Powers)).wait();
+    console.log(`Verifier initialized from ${VERIFIER}`);
+  } else {
+    console.log(`No ${VERIFIER}, slashing is disabled until initVerifier is called`);
+  }
 }
 
 // We recommend this pattern to be able to use async/await everywhere
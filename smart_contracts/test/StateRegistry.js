@@ -5,24 +5,421 @@ const {
   const { anyValue } = require("@nomicfoundation/hardhat-chai-matchers/withArgs");
   const { expect } = require("chai");
 
+  // Generator of BN254 G1 followed by size = 4, k = 2, chunk size = 2.
+  const G1 = ethers.utils.hexConcat([
+    ethers.utils.hexZeroPad("0x01", 32),
+    ethers.utils.hexZeroPad("0x02", 32),
+  ]);
+  const META = ethers.utils.solidityPack(["uint64", "uint32", "uint32"], [4, 2, 2]);
+
+  function record(commitment) {
+    return ethers.utils.hexConcat([commitment, META]);
+  }
+
   describe("StateRegistry", function () {
     it("Should push a new state to the registry", async function () {
         const [owner, a1, a2, a3] = await ethers.getSigners();
         const StateRegistry = await ethers.getContractFactory("StateRegistry");
         const stateRegistry = await StateRegistry.deploy();
 
-        await stateRegistry.connect(a1).pushState("0x1234");
-        await stateRegistry.connect(a2).pushState("0x1234");
-        await stateRegistry.connect(a3).pushState("0x1234");
-        await stateRegistry.connect(a1).pushState("0x1235");
+        const s1 = record(ethers.utils.hexZeroPad("0x", 64));
+        const s2 = record(G1);
+
+        await stateRegistry.connect(a1).pushState(s1);
+        await stateRegistry.connect(a2).pushState(s1);
+        await stateRegistry.connect(a3).pushState(s1);
+        await stateRegistry.connect(a1).pushState(s2);
         
 
         let height = await stateRegistry.getStateHeight(a1.address);
         expect(height).to.equal(2);
 
         let state = await stateRegistry.state(a1.address, 1);
-        expect(state).to.equal("0x1235");
+        expect(state).to.equal(s2);
+
+    });
+
+    it("Should keep a commitment pushed by a node", async function () {
+        const [owner, node] = await ethers.getSigners();
+        const StateRegistry = await ethers.getContractFactory("StateRegistry");
+        const stateRegistry = await StateRegistry.deploy();
+
+        await stateRegistry.connect(node).pushState(record(G1));
+
+        expect(await stateRegistry.getStateHeight(node.address)).to.equal(1);
+        expect(await stateRegistry.getStateHeight(owner.address)).to.equal(0);
+        expect(await stateRegistry.state(node.address, 0)).to.equal(record(G1));
+
+        const [x, y] = await stateRegistry.getCommitment(node.address, 0);
+        expect(x).to.equal(1);
+        expect(y).to.equal(2);
+    });
+
+    it("Should emit an event for every pushed state", async function () {
+        const [owner, node] = await ethers.getSigners();
+        const StateRegistry = await ethers.getContractFactory("StateRegistry");
+        const stateRegistry = await StateRegistry.deploy();
+
+        await expect(stateRegistry.connect(node).pushState(record(G1)))
+            .to.emit(stateRegistry, "StatePushed")
+            .withArgs(node.address, 0, record(G1));
+        await expect(stateRegistry.connect(node).pushState(record(G1)))
+            .to.emit(stateRegistry, "StatePushed")
+            .withArgs(node.address, 1, record(G1));
+    });
+
+    it("Should reject malformed states", async function () {
+        const StateRegistry = await ethers.getContractFactory("StateRegistry");
+        const stateRegistry = await StateRegistry.deploy();
+
+        await expect(stateRegistry.pushState("0x1234")).to.be.revertedWith(
+            "StateRegistry: invalid state length"
+        );
+    });
+
+    describe("Node registry", function () {
+      const STAKE = ethers.utils.parseEther("1");
+
+      async function deploy() {
+        const StateRegistry = await ethers.getContractFactory("StateRegistry");
+        return await StateRegistry.deploy();
+      }
+
+      it("Should register staked nodes", async function () {
+        const [owner, n1, n2] = await ethers.getSigners();
+        const stateRegistry = await loadFixture(deploy);
+
+        await expect(stateRegistry.connect(n1).registerNode("127.0.0.1:3001", { value: STAKE }))
+          .to.emit(stateRegistry, "NodeRegistered")
+          .withArgs(n1.address, "127.0.0.1:3001", STAKE);
+        await stateRegistry.connect(n2).registerNode("127.0.0.1:3002", { value: STAKE.mul(2) });
+
+        expect(await stateRegistry.isActiveNode(n1.address)).to.equal(true);
+        expect(await stateRegistry.isActiveNode(owner.address)).to.equal(false);
+
+        const [addresses, endpoints] = await stateRegistry.getActiveNodes();
+        expect(addresses).to.deep.equal([n1.address, n2.address]);
+        expect(endpoints).to.deep.equal(["127.0.0.1:3001", "127.0.0.1:3002"]);
+
+        const node = await stateRegistry.nodes(n2.address);
+        expect(node.stake).to.equal(STAKE.mul(2));
+      });
+
+      it("Should update the endpoint and add to the stake", async function () {
+        const [owner, node] = await ethers.getSigners();
+        const stateRegistry = await loadFixture(deploy);
+
+        await stateRegistry.connect(node).registerNode("127.0.0.1:3001", { value: STAKE });
+        await stateRegistry.connect(node).registerNode("10.0.0.1:3001");
+
+        const [addresses, endpoints] = await stateRegistry.getActiveNodes();
+        expect(addresses).to.deep.equal([node.address]);
+        expect(endpoints).to.deep.equal(["10.0.0.1:3001"]);
+        expect((await stateRegistry.nodes(node.address)).stake).to.equal(STAKE);
+      });
+
+      it("Should reject nodes without enough stake", async function () {
+        const [owner, node] = await ethers.getSigners();
+        const stateRegistry = await loadFixture(deploy);
+
+        await expect(
+          stateRegistry.connect(node).registerNode("127.0.0.1:3001", { value: STAKE.sub(1) })
+        ).to.be.revertedWith("StateRegistry: stake is too low");
+        await expect(
+          stateRegistry.connect(node).registerNode("", { value: STAKE })
+        ).to.be.revertedWith("StateRegistry: empty endpoint");
+      });
+
+      it("Should release the stake after the unbonding period", async function () {
+        const [owner, n1, n2] = await ethers.getSigners();
+        const stateRegistry = await loadFixture(deploy);
+
+        await stateRegistry.connect(n1).registerNode("127.0.0.1:3001", { value: STAKE });
+        await stateRegistry.connect(n2).registerNode("127.0.0.1:3002", { value: STAKE });
+        await expect(stateRegistry.connect(n1).withdrawStake()).to.be.revertedWith(
+          "StateRegistry: node is not exiting"
+        );
+
+        await expect(stateRegistry.connect(n1).exitNode())
+          .to.emit(stateRegistry, "NodeExiting")
+          .withArgs(n1.address, anyValue);
+        expect(await stateRegistry.isActiveNode(n1.address)).to.equal(false);
+        const [addresses] = await stateRegistry.getActiveNodes();
+        expect(addresses).to.deep.equal([n2.address]);
+
+        await expect(stateRegistry.connect(n1).withdrawStake()).to.be.revertedWith(
+          "StateRegistry: stake is locked"
+        );
+
+        await time.increase(await stateRegistry.UNBONDING_PERIOD());
+        await expect(stateRegistry.connect(n1).withdrawStake()).to.changeEtherBalances(
+          [n1, stateRegistry],
+          [STAKE, STAKE.mul(-1)]
+        );
+        expect(await stateRegistry.nodeList(0)).to.equal(n2.address);
+        expect((await stateRegistry.nodes(n1.address)).stake).to.equal(0);
+      });
+    });
+
+    describe("Slashing", function () {
+      const STAKE = ethers.utils.parseEther("1");
+
+      // Setup with τ = 17: [τ^i]_1 and [τ^i]_2, see `kzg10 verifier`.
+      const G1_POWERS = [
+        ["0x01", "0x02"],
+        [
+          "0x1c6a451060210f3baad93fe1631753751da9857edae0468e8e4bee7dd33cfb2c",
+          "0x2331a64aa86c50d2d1e0237893ef7744a77228881ce73fcc2ad555a37d4ab405",
+        ],
+        [
+          "0x1d6b5d692b48e1ea70f19e754118457c83c8a3f24d4e573d5b079d56dedcab4b",
+          "0x1f666bcf1c68d52c7576ff64d70ff0d2802ccb33a892b019bf7b23b801ae7a8f",
+        ],
+        [
+          "0x17dc0b76c3f92fa9d85a29ce65d0be39890bbb071b9cc8fac59a234ad3163659",
+          "0x0b76b51cbef4a9efba36e7dcd37745045eeb849a8bbd33f5baecbe641fe7642a",
+        ],
+      ];
+      const G2_POWERS = [
+        [
+          "0x198e9393920d483a7260bfb731fb5d25f1aa493335a9e71297e485b7aef312c2",
+          "0x1800deef121f1e76426a00665e5c4479674322d4f75edadd46debd5cd992f6ed",
+          "0x090689d0585ff075ec9e99ad690c3395bc4b313370b38ef355acdadcd122975b",
+          "0x12c85ea5db8c6deb4aab71808dcb408fe3d1e7690c43d37b4ce6cc0166fa7daa",
+        ],
+        [
+          "0x227071bba5ff3b47ed8b504bb5b215bc701d7a3259b933bff1a4164eae499c2c",
+          "0x0c51a367b61d3119677b29739ddccbb78002b5558d8f49ff16e299c1b41f8098",
+          "0x08bb188b2a6187bb1e87834c85a6a917763d65b98febf2c45ea339dd77fac415",
+          "0x18fd2fd13be8494c39e8a91325d1ef3ba7d1a205d10788e38bc9e09d9be87769",
+        ],
+        [
+          "0x26941e18299649808ae17deba5ef7d83e0c6bb496cea18f1399ad4687e9f83f6",
+          "0x0ce45d041b725d42aaa8ad0a3556951eccc78f85de7c6aa9c74e2c858eb9bfd5",
+          "0x2c5ffef8d553d6657246c7057d6e09001456213ecc8bb414c5ea38efa8297c54",
+          "0x23d7d81feb7177904511d7cc75dfb2b866dc3ddde3ea4e3fa37e2679bec064c3",
+        ],
+      ];
+
+      // Commitment to the encoding of [1, 2, 3, 4] over `Domain::bit_reversed(2)`,
+      // chunk 1 of it with its proof and a tampered copy.
+      const COMMITMENT =
+        "0x075036752feb7522b138267853bdc9a2c614bfe362afec188f79bd4a85debd3c" +
+        "005a2f4f85f84295dd3688b7b883db31c641ca2009413c1d9e5c53c1862fb556";
+      const PROOF = [
+        "0x12d12138463f676f651e1b8d84f6a1767ed2a223d8c48572b2f7217387d012e0",
+        "0x292036539cd9c2567d3d7fddd8f3e0db80551821939e8ab4f6126320d0b5cfe1",
+      ];
+      const VALID = {
+        data: ["0x02", "0x0dda46807f07f557970fd2a1c978e378e133206eb850c21a1d5d73a89a964cb4"],
+        interpolation: [
+          "0x06ed23403f83faabcb87e950e4bc71bc709990375c28610d0eaeb9d44d4b265b",
+          "0x24465aa1893d54772113b4deb1f37d79e1c8e32f9beea8fd85def37674723829",
+        ],
+      };
+      const TAMPERED = {
+        data: ["0x03", "0x0dda46807f07f557970fd2a1c978e378e133206eb850c21a1d5d73a89a964cb4"],
+        interpolation: [
+          "0x1f1f4a79b01ccac0a7b00c2c257d1deb04b3845b99051955b09fb49e454b265c",
+          "0x0c14336818a484629ecdfdd212075cf7b353d0b41d34f3077455f13e2cba1d0e",
+        ],
+      };
+
+      async function deploy() {
+        const [owner, uploader] = await ethers.getSigners();
+        const StateRegistry = await ethers.getContractFactory("StateRegistry");
+        const stateRegistry = await StateRegistry.deploy();
+        await stateRegistry.initVerifier(G1_POWERS, G2_POWERS);
+        await stateRegistry.connect(uploader).pushState(record(COMMITMENT));
+
+        // A node with a key we can sign digests with.
+        const node = new ethers.Wallet(ethers.utils.hexZeroPad("0x5eed", 32), ethers.provider);
+        await owner.sendTransaction({ to: node.address, value: STAKE.mul(2) });
+        await stateRegistry.connect(node).registerNode("127.0.0.1:3001", { value: STAKE });
+
+        return { stateRegistry, node, uploader };
+      }
+
+      // Evidence of `chunk` signed by `signer` the way nodes sign served chunks.
+      async function evidence(stateRegistry, signer, node, uploader, chunk) {
+        const e = {
+          node: node.address,
+          uploader: uploader.address,
+          height: 0,
+          chunk: 1,
+          data: chunk.data,
+          proof: PROOF,
+          interpolation: chunk.interpolation,
+          signature: "0x",
+        };
+        const key = new ethers.utils.SigningKey(signer.privateKey);
+        const sig = key.signDigest(await stateRegistry.chunkDigest(e));
+        e.signature = ethers.utils.hexConcat([sig.r, sig.s, sig.recoveryParam === 0 ? "0x00" : "0x01"]);
+        return e;
+      }
+
+      // The first step of `slash`, in a block of its own.
+      async function commit(stateRegistry, reporter, e) {
+        await stateRegistry
+          .connect(reporter)
+          .commitEvidence(await stateRegistry.evidenceCommitment(reporter.address, e));
+      }
+
+      it("Should set the verifier once, by the owner only", async function () {
+        const [owner, other] = await ethers.getSigners();
+        const StateRegistry = await ethers.getContractFactory("StateRegistry");
+        const stateRegistry = await StateRegistry.deploy();
+
+        await expect(
+          stateRegistry.connect(other).initVerifier(G1_POWERS, G2_POWERS)
+        ).to.be.revertedWith("StateRegistry: caller is not the owner");
+        await stateRegistry.initVerifier(G1_POWERS, G2_POWERS);
+        expect(await stateRegistry.g2Powers(2, 0)).to.equal(G2_POWERS[2][0]);
+        await expect(stateRegistry.initVerifier(G1_POWERS, G2_POWERS)).to.be.revertedWith(
+          "StateRegistry: verifier is already set"
+        );
+      });
+
+      it("Should publish the hash of the verifier", async function () {
+        const StateRegistry = await ethers.getContractFactory("StateRegistry");
+        const stateRegistry = await StateRegistry.deploy();
+
+        const hash = ethers.utils.keccak256(
+          ethers.utils.defaultAbiCoder.encode(["uint256[2][]", "uint256[4][]"], [G1_POWERS, G2_POWERS])
+        );
+        await expect(stateRegistry.initVerifier(G1_POWERS, G2_POWERS))
+          .to.emit(stateRegistry, "VerifierInitialized")
+          .withArgs(hash, G1_POWERS.length, G2_POWERS.length);
+        expect(await stateRegistry.verifierHash()).to.equal(hash);
+        const verifier = await stateRegistry.getVerifier();
+        expect(verifier.g1Length).to.equal(G1_POWERS.length);
+        expect(verifier.g2Length).to.equal(G2_POWERS.length);
+      });
+
+      it("Should reject a setup that isn't made of powers of one tau", async function () {
+        const StateRegistry = await ethers.getContractFactory("StateRegistry");
+        const stateRegistry = await StateRegistry.deploy();
+
+        await expect(
+          stateRegistry.initVerifier([G1_POWERS[1], ...G1_POWERS.slice(1)], G2_POWERS)
+        ).to.be.revertedWith("StateRegistry: setup doesn't start at the generators");
+        await expect(
+          stateRegistry.initVerifier(G1_POWERS, [G2_POWERS[0], G2_POWERS[0], G2_POWERS[2]])
+        ).to.be.revertedWith("StateRegistry: inconsistent G1 powers");
+        await expect(
+          stateRegistry.initVerifier(
+            [G1_POWERS[0], G1_POWERS[1], G1_POWERS[3], G1_POWERS[2]],
+            G2_POWERS
+          )
+        ).to.be.revertedWith("StateRegistry: inconsistent G1 powers");
+        // A bogus [τ^2]_2 would fail honest chunks of 2 values.
+        await expect(
+          stateRegistry.initVerifier(G1_POWERS, [G2_POWERS[0], G2_POWERS[1], G2_POWERS[1]])
+        ).to.be.revertedWith("StateRegistry: inconsistent G2 powers");
+        await expect(
+          stateRegistry.initVerifier(G1_POWERS.slice(0, 1), G2_POWERS)
+        ).to.be.revertedWith("StateRegistry: too few G1 powers");
+      });
+
+      it("Should sign chunks for one deployment only", async function () {
+        const { stateRegistry, node, uploader } = await loadFixture(deploy);
+        const StateRegistry = await ethers.getContractFactory("StateRegistry");
+        const other = await StateRegistry.deploy();
+
+        const e = await evidence(stateRegistry, node, node, uploader, TAMPERED);
+        expect(await other.chunkDigest(e)).to.not.equal(await stateRegistry.chunkDigest(e));
+      });
+
+      it("Should verify chunks against the commitment", async function () {
+        const { stateRegistry, node, uploader } = await loadFixture(deploy);
+
+        const valid = await evidence(stateRegistry, node, node, uploader, VALID);
+        expect(await stateRegistry.verifyChunk(valid)).to.equal(true);
+        const tampered = await evidence(stateRegistry, node, node, uploader, TAMPERED);
+        expect(await stateRegistry.verifyChunk(tampered)).to.equal(false);
+
+        // The interpolation must take the data at the points of the chunk.
+        await expect(
+          stateRegistry.verifyChunk({ ...tampered, interpolation: VALID.interpolation })
+        ).to.be.revertedWith("StateRegistry: interpolation doesn't match the data");
+      });
+
+      it("Should slash a node that signed an invalid chunk", async function () {
+        const [owner, uploader, reporter] = await ethers.getSigners();
+        const { stateRegistry, node } = await loadFixture(deploy);
+
+        const e = await evidence(stateRegistry, node, node, uploader, TAMPERED);
+        await commit(stateRegistry, reporter, e);
+        await expect(stateRegistry.connect(reporter).slash(e))
+          .to.emit(stateRegistry, "NodeSlashed")
+          .withArgs(node.address, reporter.address, STAKE);
+
+        expect(await stateRegistry.isActiveNode(node.address)).to.equal(false);
+        expect((await stateRegistry.nodes(node.address)).stake).to.equal(0);
+        await expect(stateRegistry.connect(reporter).slash(e)).to.be.revertedWith(
+          "StateRegistry: unknown node"
+        );
+      });
+
+      it("Should pay the reporter half of the stake", async function () {
+        const [owner, uploader, reporter] = await ethers.getSigners();
+        const { stateRegistry, node } = await loadFixture(deploy);
+
+        const e = await evidence(stateRegistry, node, node, uploader, TAMPERED);
+        await commit(stateRegistry, reporter, e);
+        await expect(stateRegistry.connect(reporter).slash(e)).to.changeEtherBalances(
+          [reporter, stateRegistry],
+          [STAKE.div(2), STAKE.div(2).mul(-1)]
+        );
+      });
+
+      it("Should reject evidence against valid chunks or not signed by the node", async function () {
+        const [owner, uploader, reporter] = await ethers.getSigners();
+        const { stateRegistry, node } = await loadFixture(deploy);
+
+        const valid = await evidence(stateRegistry, node, node, uploader, VALID);
+        await commit(stateRegistry, reporter, valid);
+        await expect(stateRegistry.connect(reporter).slash(valid)).to.be.revertedWith(
+          "StateRegistry: chunk matches the commitment"
+        );
+
+        const other = new ethers.Wallet(ethers.utils.hexZeroPad("0xbad", 32));
+        const forged = await evidence(stateRegistry, other, node, uploader, TAMPERED);
+        await commit(stateRegistry, reporter, forged);
+        await expect(stateRegistry.connect(reporter).slash(forged)).to.be.revertedWith(
+          "StateRegistry: invalid signature"
+        );
+
+        const unknown = await evidence(stateRegistry, other, other, uploader, TAMPERED);
+        await expect(stateRegistry.connect(reporter).slash(unknown)).to.be.revertedWith(
+          "StateRegistry: unknown node"
+        );
+      });
+
+      it("Should only pay reporters that committed to the evidence first", async function () {
+        const [owner, uploader, reporter, copycat] = await ethers.getSigners();
+        const { stateRegistry, node } = await loadFixture(deploy);
+
+        const e = await evidence(stateRegistry, node, node, uploader, TAMPERED);
+        await expect(stateRegistry.connect(reporter).slash(e)).to.be.revertedWith(
+          "StateRegistry: evidence is not committed"
+        );
+
+        await commit(stateRegistry, reporter, e);
+        // Copying the evidence of someone else doesn't help.
+        await expect(stateRegistry.connect(copycat).slash(e)).to.be.revertedWith(
+          "StateRegistry: evidence is not committed"
+        );
+        await expect(
+          stateRegistry.connect(reporter).commitEvidence(
+            await stateRegistry.evidenceCommitment(reporter.address, e)
+          )
+        ).to.be.revertedWith("StateRegistry: already committed");
 
+        await expect(stateRegistry.connect(reporter).slash(e))
+          .to.emit(stateRegistry, "NodeSlashed")
+          .withArgs(node.address, reporter.address, STAKE);
+      });
     });
 
-  });
\ No newline at end of file
+  });
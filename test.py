@@ -8,9 +8,10 @@ headers = {'Content-Type': 'application/json'}
 
 response = requests.post('http://localhost:3000/data', data=json_data, headers=headers)
 print('POST /data result:', response)
+state = response.json()
 
 for i in range(5):
-    response = requests.get(f'http://localhost:300{i}/data/partial')
+    response = requests.get(f'http://localhost:300{i}/state/{state["uploader"]}/{state["height"]}/partial')
     print(f'Partial data for peer {i}:', response.json())
 
 response = requests.get('http://localhost:3000/data')